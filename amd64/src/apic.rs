@@ -0,0 +1,518 @@
+//! Local and I/O Advanced Programmable Interrupt Controller (APIC) interfaces.
+//! 
+//! Register docs are taken from https://www.amd.com/system/files/TechDocs/24593.pdf
+//! and the Intel 82093AA I/O APIC datasheet.
+
+use crate::registers::{rdmsr, wrmsr, APIC_BASE_MSR};
+
+
+// APIC BASE MSR
+
+bitflags::bitflags! {
+    /// Flags of the APIC Base MSR.
+    pub struct ApicBase: u64 {
+        /// BSC: Set if this is the boot strap processor.
+        const BSP = 1 << 8;
+        /// EXTD: Set if the x2APIC mode is enabled.
+        const EXTD = 1 << 10;
+        /// AE: Set if the local APIC is enabled.
+        const AE = 1 << 11;
+        /// ABA: The physical base address of the APIC registers' page.
+        const BASE_MASK = 0x000F_FFFF_FFFF_F000;
+    }
+}
+
+impl ApicBase {
+    /// Reads the APIC Base MSR.
+    pub fn read() -> Self {
+        Self::from_bits_truncate(rdmsr(APIC_BASE_MSR))
+    }
+    /// Writes the APIC Base MSR.
+    /// ### Safety:
+    /// Caller must ensure the change doesn't violate memory safety, e.g. by moving the
+    /// registers' page, and that the transition between APIC modes is valid.
+    pub unsafe fn write(self) {
+        wrmsr(APIC_BASE_MSR, self.bits);
+    }
+
+    /// Returns the physical address of the APIC registers' page.
+    #[inline]
+    pub fn get_paddr(self) -> usize {
+        (self & Self::BASE_MASK).bits as usize
+    }
+}
+
+
+// REGISTERS
+
+/// Offsets of the local APIC registers from the base of the registers' page.
+#[repr(usize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ApicReg {
+    Id                  = 0x020,
+    Version             = 0x030,
+    TaskPriority        = 0x080,
+    ArbitrationPriority = 0x090,
+    ProcessorPriority   = 0x0A0,
+    EndOfInterrupt      = 0x0B0,
+    RemoteRead          = 0x0C0,
+    LogicalDestination  = 0x0D0,
+    DestinationFormat   = 0x0E0,
+    SpuriousInterrupt   = 0x0F0,
+    ErrorStatus         = 0x280,
+    InterruptCmdLo      = 0x300,
+    InterruptCmdHi      = 0x310,
+    TimerLvt            = 0x320,
+    ThermalLvt          = 0x330,
+    PerfCounterLvt      = 0x340,
+    Lint0Lvt            = 0x350,
+    Lint1Lvt            = 0x360,
+    ErrorLvt            = 0x370,
+    TimerInitialCount   = 0x380,
+    TimerCurrentCount   = 0x390,
+    TimerDivideConfig   = 0x3E0,
+}
+
+/// The Local Vector Table entries, each of which routes a local interrupt source.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Lvt {
+    Timer,
+    Thermal,
+    PerfCounter,
+    Lint0,
+    Lint1,
+    Error,
+}
+
+impl Lvt {
+    pub const ALL: [Lvt; 6] = [Lvt::Timer, Lvt::Thermal, Lvt::PerfCounter, Lvt::Lint0, Lvt::Lint1, Lvt::Error];
+
+    #[inline]
+    pub const fn reg(self) -> ApicReg {
+        match self {
+            Lvt::Timer => ApicReg::TimerLvt,
+            Lvt::Thermal => ApicReg::ThermalLvt,
+            Lvt::PerfCounter => ApicReg::PerfCounterLvt,
+            Lvt::Lint0 => ApicReg::Lint0Lvt,
+            Lvt::Lint1 => ApicReg::Lint1Lvt,
+            Lvt::Error => ApicReg::ErrorLvt,
+        }
+    }
+}
+
+/// Delivery modes of Local Vector Table entries and interprocessor interrupts.
+#[repr(u32)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeliveryMode {
+    Fixed          = 0b000,
+    LowestPriority = 0b001,
+    Smi            = 0b010,
+    Nmi            = 0b100,
+    Init           = 0b101,
+    Startup        = 0b110,
+    ExtInt         = 0b111,
+}
+
+bitflags::bitflags! {
+    /// A Local Vector Table entry.
+    pub struct LvtEntry: u32 {
+        /// VEC: The vector of the interrupt.
+        const VECTOR_MASK = 0xFF;
+        /// MT: The delivery mode, see `DeliveryMode`.
+        const DELIVERY_MODE_MASK = 0b111 << 8;
+        /// DS: Set while the interrupt is pending delivery.
+        const DELIVERY_PENDING = 1 << 12;
+        /// PP: Set if the pin is active low, LINT0 and LINT1 only.
+        const ACTIVE_LOW = 1 << 13;
+        /// RIR: Set while a level-triggered interrupt is being serviced, LINT0 and LINT1 only.
+        const REMOTE_IRR = 1 << 14;
+        /// TGM: Set if the pin is level-triggered, LINT0 and LINT1 only.
+        const LEVEL_TRIGGERED = 1 << 15;
+        /// M: Set if the interrupt is masked.
+        const MASKED = 1 << 16;
+        /// TMM: Set if the timer is periodic, timer only.
+        const TIMER_PERIODIC = 1 << 17;
+    }
+}
+
+impl LvtEntry {
+    pub const fn new(vector: u8, mode: DeliveryMode) -> Self {
+        Self::from_bits_truncate(vector as u32 | (mode as u32) << 8)
+    }
+}
+
+bitflags::bitflags! {
+    /// Flags of the Error Status Register.
+    pub struct ApicError: u32 {
+        const SEND_CHECKSUM = 1 << 0;
+        const RECV_CHECKSUM = 1 << 1;
+        const SEND_ACCEPT = 1 << 2;
+        const RECV_ACCEPT = 1 << 3;
+        const REDIRECTABLE_IPI = 1 << 4;
+        const SEND_ILLEGAL_VECTOR = 1 << 5;
+        const RECV_ILLEGAL_VECTOR = 1 << 6;
+        const ILLEGAL_REGISTER = 1 << 7;
+    }
+}
+
+/// Software enable flag of the spurious interrupt register.
+const SPURIOUS_APIC_ENABLE: u32 = 1 << 8;
+/// Delivery status flag of the interrupt command register, set while an IPI is being sent.
+const ICR_DELIVERY_PENDING: u32 = 1 << 12;
+/// Level flag of the interrupt command register, which must be set but for INIT deassertion.
+const ICR_LEVEL_ASSERT: u32 = 1 << 14;
+/// Shift of the destination shorthand of the interrupt command register, see `IpiDest`.
+const ICR_SHORTHAND_SHIFT: u32 = 18;
+/// The MSR of the first register in x2APIC mode, from which registers are
+/// laid out by their xAPIC offset divided by 16.
+const X2APIC_MSR_BASE: u64 = 0x800;
+
+
+/// The base of the addresses of message signaled interrupts, see `msi_message`.
+const MSI_ADDRESS_BASE: u32 = 0xFEE0_0000;
+/// Shift of the destination APIC ID in the address of message signaled interrupts.
+const MSI_DESTINATION_SHIFT: u32 = 12;
+
+
+/// Returns the address and data of a message signaled interrupt, delivering `vector`
+/// with the fixed delivery mode and edge-triggered to the APIC ID `destination`.
+pub const fn msi_message(vector: u8, destination: u8) -> (u32, u32) {
+    (MSI_ADDRESS_BASE | (destination as u32) << MSI_DESTINATION_SHIFT, (DeliveryMode::Fixed as u32) << 8 | vector as u32)
+}
+
+/// The means of accessing the local APIC registers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ApicMode {
+    /// Through the memory-mapped registers' page, at the given linear address.
+    XApic(*mut u8),
+    /// Through MSRs, see `X2APIC_MSR_BASE`.
+    X2Apic,
+}
+
+/// The destination of an interprocessor interrupt.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IpiDest {
+    /// The processor of the APIC ID.
+    Apic(u32),
+    /// The executing processor.
+    Current,
+    /// All processors, including the executing processor.
+    All,
+    /// All processors but the executing processor.
+    AllButCurrent,
+}
+
+/// The local APIC of the executing processor.
+/// 
+/// Every processor's local APIC is accessed at the same physical address or MSRs,
+/// hence the same `LocalApic` may be used on each, provided they're in the same mode.
+#[derive(Debug, Clone, Copy)]
+pub struct LocalApic {
+    mode: ApicMode,
+}
+
+unsafe impl Send for LocalApic {}
+unsafe impl Sync for LocalApic {}
+
+impl LocalApic {
+    /// Creates an xAPIC mode `LocalApic`.
+    /// ### Safety:
+    /// `base` must be the linear address of the APIC registers' page, mapped uncacheable.
+    pub const unsafe fn new(base: *mut u8) -> Self {
+        Self { mode: ApicMode::XApic(base) }
+    }
+    /// Creates an x2APIC mode `LocalApic`.
+    /// ### Safety:
+    /// x2APIC mode must be enabled, see `ApicBase::EXTD`.
+    pub const unsafe fn new_x2apic() -> Self {
+        Self { mode: ApicMode::X2Apic }
+    }
+
+    #[inline]
+    pub fn mode(&self) -> ApicMode {
+        self.mode
+    }
+
+    #[inline]
+    pub fn read(&self, reg: ApicReg) -> u32 {
+        match self.mode {
+            // SAFETY: guaranteed by `new`
+            ApicMode::XApic(base) => unsafe { base.add(reg as usize).cast::<u32>().read_volatile() },
+            ApicMode::X2Apic => rdmsr(X2APIC_MSR_BASE + (reg as u64 >> 4)) as u32,
+        }
+    }
+    /// ### Safety:
+    /// Caller must ensure the write doesn't violate memory safety, e.g. by delivering
+    /// interrupts to vectors that aren't handled.
+    #[inline]
+    pub unsafe fn write(&self, reg: ApicReg, value: u32) {
+        match self.mode {
+            ApicMode::XApic(base) => base.add(reg as usize).cast::<u32>().write_volatile(value),
+            ApicMode::X2Apic => wrmsr(X2APIC_MSR_BASE + (reg as u64 >> 4), value as u64),
+        }
+    }
+
+    /// Returns the APIC ID of the executing processor.
+    /// 
+    /// IDs are 8-bit in xAPIC mode, and 32-bit in x2APIC mode.
+    pub fn id(&self) -> u32 {
+        match self.mode {
+            ApicMode::XApic(_) => self.read(ApicReg::Id) >> 24,
+            ApicMode::X2Apic => self.read(ApicReg::Id),
+        }
+    }
+    /// Returns the version of the local APIC.
+    pub fn version(&self) -> u8 {
+        self.read(ApicReg::Version) as u8
+    }
+    /// Returns the number of Local Vector Table entries.
+    pub fn lvt_count(&self) -> usize {
+        (self.read(ApicReg::Version) >> 16 & 0xFF) as usize + 1
+    }
+
+    /// Software-enables the local APIC, delivering spurious interrupts to `spurious_vector`.
+    /// ### Safety:
+    /// Caller must ensure the interrupts delivered are handled.
+    pub unsafe fn enable(&self, spurious_vector: u8) {
+        self.write(ApicReg::SpuriousInterrupt, SPURIOUS_APIC_ENABLE | spurious_vector as u32);
+    }
+    /// Software-disables the local APIC, masking all Local Vector Table entries.
+    pub fn disable(&self) {
+        // SAFETY: no interrupts are delivered while disabled
+        unsafe {
+            let svr = self.read(ApicReg::SpuriousInterrupt);
+            self.write(ApicReg::SpuriousInterrupt, svr & !SPURIOUS_APIC_ENABLE);
+        }
+    }
+    /// Returns whether the local APIC is software-enabled.
+    pub fn is_enabled(&self) -> bool {
+        self.read(ApicReg::SpuriousInterrupt) & SPURIOUS_APIC_ENABLE != 0
+    }
+
+    /// Returns the task priority, below which interrupts are not delivered.
+    pub fn tpr(&self) -> u8 {
+        self.read(ApicReg::TaskPriority) as u8
+    }
+    /// Sets the task priority, inhibiting the delivery of interrupts of vectors of
+    /// a lower priority class, being the high nibble of the vector.
+    pub fn set_tpr(&self, priority: u8) {
+        // SAFETY: only affects which interrupts are delivered
+        unsafe { self.write(ApicReg::TaskPriority, priority as u32) }
+    }
+
+    pub fn get_lvt(&self, lvt: Lvt) -> LvtEntry {
+        LvtEntry::from_bits_truncate(self.read(lvt.reg()))
+    }
+    /// ### Safety:
+    /// Caller must ensure the interrupts delivered are handled.
+    pub unsafe fn set_lvt(&self, lvt: Lvt, entry: LvtEntry) {
+        self.write(lvt.reg(), entry.bits());
+    }
+
+    /// Returns the errors detected since the last call, clearing them.
+    pub fn error_status(&self) -> ApicError {
+        // SAFETY: writing the error status register latches the errors for reading
+        unsafe { self.write(ApicReg::ErrorStatus, 0); }
+        ApicError::from_bits_truncate(self.read(ApicReg::ErrorStatus))
+    }
+
+    /// Signals the end of the interrupt being serviced.
+    #[inline]
+    pub fn eoi(&self) {
+        // SAFETY: only affects interrupt delivery
+        unsafe { self.write(ApicReg::EndOfInterrupt, 0) }
+    }
+
+    /// Writes the interrupt command register, sending an interprocessor interrupt as per
+    /// `command`, the low doubleword of the register, to the APIC ID `destination`.
+    /// 
+    /// In xAPIC mode, only the low 8 bits of `destination` are used.
+    /// ### Safety:
+    /// Caller must ensure the interrupt doesn't violate memory safety on the destination,
+    /// e.g. by delivering a vector that isn't handled.
+    pub unsafe fn write_icr(&self, destination: u32, command: u32) {
+        match self.mode {
+            ApicMode::XApic(_) => {
+                // the command is sent upon writing the low doubleword
+                self.write(ApicReg::InterruptCmdHi, destination << 24);
+                self.write(ApicReg::InterruptCmdLo, command);
+            },
+            ApicMode::X2Apic => {
+                let icr_msr = X2APIC_MSR_BASE + (ApicReg::InterruptCmdLo as u64 >> 4);
+                wrmsr(icr_msr, (destination as u64) << 32 | command as u64);
+            },
+        }
+    }
+    /// Returns whether the last interprocessor interrupt has yet to be sent.
+    /// 
+    /// Always false in x2APIC mode, where sending doesn't pend.
+    pub fn is_icr_pending(&self) -> bool {
+        match self.mode {
+            ApicMode::XApic(_) => self.read(ApicReg::InterruptCmdLo) & ICR_DELIVERY_PENDING != 0,
+            ApicMode::X2Apic => false,
+        }
+    }
+
+    /// Sends an interprocessor interrupt of `vector` with the delivery `mode` to `dest`.
+    /// 
+    /// `vector` is ignored by the NMI, INIT and SMI delivery modes.
+    /// ### Safety:
+    /// Caller must ensure the interrupt doesn't violate memory safety on the destination,
+    /// e.g. by delivering a vector that isn't handled.
+    pub unsafe fn send_ipi(&self, dest: IpiDest, mode: DeliveryMode, vector: u8) {
+        let (destination, shorthand) = match dest {
+            IpiDest::Apic(id) => (id, 0b00),
+            IpiDest::Current => (0, 0b01),
+            IpiDest::All => (0, 0b10),
+            IpiDest::AllButCurrent => (0, 0b11),
+        };
+        let command = shorthand << ICR_SHORTHAND_SHIFT | ICR_LEVEL_ASSERT | (mode as u32) << 8 | vector as u32;
+        self.write_icr(destination, command);
+    }
+    /// Sends an INIT interprocessor interrupt to the APIC ID `destination`, 
+    /// resetting the processor into the wait-for-SIPI state.
+    /// ### Safety:
+    /// The destination's execution is abandoned.
+    pub unsafe fn send_init(&self, destination: u32) {
+        self.write_icr(destination, (DeliveryMode::Init as u32) << 8 | ICR_LEVEL_ASSERT);
+    }
+    /// Sends a startup interprocessor interrupt to the APIC ID `destination` which, if in 
+    /// the wait-for-SIPI state, begins executing in real mode at the physical address 
+    /// `page << 12`, with CS set to `page << 8` and IP to zero.
+    /// ### Safety:
+    /// The page must contain valid real-mode code that maintains memory safety.
+    pub unsafe fn send_startup(&self, destination: u32, page: u8) {
+        self.write_icr(destination, (DeliveryMode::Startup as u32) << 8 | ICR_LEVEL_ASSERT | page as u32);
+    }
+}
+
+
+// I/O APIC
+
+/// Offset of the register select register from the base of the I/O APIC's registers.
+const IOREGSEL: usize = 0x00;
+/// Offset of the register window from the base of the I/O APIC's registers.
+const IOWIN: usize = 0x10;
+
+/// Indices of the I/O APIC registers, selected through `IOREGSEL`.
+#[repr(u32)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IoApicReg {
+    Id                = 0x00,
+    Version           = 0x01,
+    Arbitration       = 0x02,
+    /// The low doubleword of the first redirection entry, each taking two registers.
+    RedirectionTable  = 0x10,
+}
+
+bitflags::bitflags! {
+    /// An I/O APIC redirection table entry, routing an interrupt input.
+    pub struct RedirectionEntry: u64 {
+        /// INTVEC: The vector of the interrupt.
+        const VECTOR_MASK = 0xFF;
+        /// DELMOD: The delivery mode, see `DeliveryMode`.
+        const DELIVERY_MODE_MASK = 0b111 << 8;
+        /// DESTMOD: Set if the destination is a logical destination, else an APIC ID.
+        const LOGICAL_DESTINATION = 1 << 11;
+        /// DELIVS: Set while the interrupt is pending delivery.
+        const DELIVERY_PENDING = 1 << 12;
+        /// INTPOL: Set if the input is active low.
+        const ACTIVE_LOW = 1 << 13;
+        /// Remote IRR: Set while a level-triggered interrupt is being serviced.
+        const REMOTE_IRR = 1 << 14;
+        /// TRIGGER: Set if the input is level-triggered.
+        const LEVEL_TRIGGERED = 1 << 15;
+        /// MASK: Set if the interrupt is masked.
+        const MASKED = 1 << 16;
+        /// DEST: The destination APIC ID or logical destination.
+        const DESTINATION_MASK = 0xFF << 56;
+    }
+}
+
+impl RedirectionEntry {
+    pub const fn new(vector: u8, mode: DeliveryMode, destination: u8) -> Self {
+        Self::from_bits_truncate(vector as u64 | (mode as u64) << 8 | (destination as u64) << 56)
+    }
+
+    #[inline]
+    pub const fn vector(self) -> u8 {
+        self.bits as u8
+    }
+    #[inline]
+    pub const fn destination(self) -> u8 {
+        (self.bits >> 56) as u8
+    }
+    #[inline]
+    pub const fn with_destination(self, destination: u8) -> Self {
+        Self::from_bits_truncate(self.bits & !Self::DESTINATION_MASK.bits | (destination as u64) << 56)
+    }
+}
+
+/// An I/O APIC, which routes its interrupt inputs to local APICs.
+/// 
+/// Registers are accessed indirectly by selecting them, hence access
+/// requires a unique reference.
+#[derive(Debug)]
+pub struct IoApic {
+    base: *mut u8,
+}
+
+unsafe impl Send for IoApic {}
+
+impl IoApic {
+    /// ### Safety:
+    /// `base` must be the linear address of the I/O APIC's registers, mapped 
+    /// uncacheable, and no other `IoApic` may access the same registers.
+    pub const unsafe fn new(base: *mut u8) -> Self {
+        Self { base }
+    }
+
+    #[inline]
+    pub fn read(&mut self, reg: u32) -> u32 {
+        // SAFETY: guaranteed by `new`
+        unsafe {
+            self.base.add(IOREGSEL).cast::<u32>().write_volatile(reg);
+            self.base.add(IOWIN).cast::<u32>().read_volatile()
+        }
+    }
+    /// ### Safety:
+    /// Caller must ensure the write doesn't violate memory safety, e.g. by delivering
+    /// interrupts to vectors that aren't handled.
+    #[inline]
+    pub unsafe fn write(&mut self, reg: u32, value: u32) {
+        self.base.add(IOREGSEL).cast::<u32>().write_volatile(reg);
+        self.base.add(IOWIN).cast::<u32>().write_volatile(value);
+    }
+
+    /// Returns the APIC ID of the I/O APIC.
+    pub fn id(&mut self) -> u8 {
+        (self.read(IoApicReg::Id as u32) >> 24 & 0xF) as u8
+    }
+    /// Returns the version of the I/O APIC.
+    pub fn version(&mut self) -> u8 {
+        self.read(IoApicReg::Version as u32) as u8
+    }
+    /// Returns the number of redirection entries, being the number of interrupt inputs.
+    pub fn redirection_count(&mut self) -> u32 {
+        (self.read(IoApicReg::Version as u32) >> 16 & 0xFF) + 1
+    }
+
+    pub fn get_redirection(&mut self, index: u32) -> RedirectionEntry {
+        let reg = IoApicReg::RedirectionTable as u32 + index * 2;
+        let lo = self.read(reg) as u64;
+        let hi = self.read(reg + 1) as u64;
+        RedirectionEntry::from_bits_truncate(hi << 32 | lo)
+    }
+    /// Writes the redirection entry of the interrupt input `index`.
+    /// 
+    /// The entry is masked while being written, such that no interrupt
+    /// is delivered as per a partially written entry.
+    /// ### Safety:
+    /// Caller must ensure the interrupts delivered are handled.
+    pub unsafe fn set_redirection(&mut self, index: u32, entry: RedirectionEntry) {
+        let reg = IoApicReg::RedirectionTable as u32 + index * 2;
+        self.write(reg, RedirectionEntry::MASKED.bits as u32);
+        self.write(reg + 1, (entry.bits >> 32) as u32);
+        self.write(reg, entry.bits as u32);
+    }
+}
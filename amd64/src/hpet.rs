@@ -0,0 +1,216 @@
+//! High Precision Event Timer (HPET) interface.
+//! 
+//! Register docs are taken from the IA-PC HPET Specification 1.0a.
+//! 
+//! The HPET has a main counter, counting up at a fixed frequency, and a number of
+//! timers, each of which interrupts when the counter matches its comparator.
+
+
+// REGISTERS
+
+/// Offsets of the general registers from the base of the HPET's registers.
+#[repr(usize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HpetReg {
+    Capabilities    = 0x000,
+    Configuration   = 0x010,
+    InterruptStatus = 0x020,
+    MainCounter     = 0x0F0,
+}
+
+/// Offset of the first timer's configuration register from the base of the HPET's registers.
+const TIMER_CONFIG_BASE: usize = 0x100;
+/// Offset of the first timer's comparator register from the base of the HPET's registers.
+const TIMER_COMPARATOR_BASE: usize = 0x108;
+/// Offset of the first timer's FSB interrupt route register from the base of the HPET's registers.
+const TIMER_FSB_ROUTE_BASE: usize = 0x110;
+/// The stride of each timer's registers.
+const TIMER_STRIDE: usize = 0x20;
+
+/// The size of the HPET's registers.
+pub const HPET_REGS_SIZE: usize = 0x400;
+/// The maximum number of timers of an HPET.
+pub const MAX_TIMERS: usize = 32;
+
+/// Capabilities: The index of the last timer.
+const CAP_LAST_TIMER_SHIFT: u64 = 8;
+/// Capabilities: Set if the main counter is 64 bits wide, else 32.
+const CAP_COUNTER_64BIT: u64 = 1 << 13;
+/// Capabilities: Set if the legacy replacement route is supported.
+const CAP_LEGACY_ROUTE: u64 = 1 << 15;
+/// Capabilities: The period of the main counter in femtoseconds.
+const CAP_PERIOD_SHIFT: u64 = 32;
+
+bitflags::bitflags! {
+    /// Flags of the General Configuration register.
+    pub struct HpetConfig: u64 {
+        /// ENABLE_CNF: Set if the main counter runs and timers may interrupt.
+        const ENABLE = 1 << 0;
+        /// LEG_RT_CNF: Set if timers 0 and 1 replace the PIT's and RTC's interrupts.
+        const LEGACY_ROUTE = 1 << 1;
+    }
+}
+
+bitflags::bitflags! {
+    /// Flags of a timer's Configuration and Capabilities register.
+    pub struct TimerConfig: u64 {
+        /// Tn_INT_TYPE_CNF: Set if the interrupt is level-triggered, else edge-triggered.
+        const LEVEL_TRIGGERED = 1 << 1;
+        /// Tn_INT_ENB_CNF: Set if the timer interrupts.
+        const INT_ENABLE = 1 << 2;
+        /// Tn_TYPE_CNF: Set if the timer is periodic, else one-shot.
+        const PERIODIC = 1 << 3;
+        /// Tn_PER_INT_CAP: Set if the timer may be periodic.
+        const PERIODIC_CAPABLE = 1 << 4;
+        /// Tn_SIZE_CAP: Set if the comparator is 64 bits wide, else 32.
+        const COMPARATOR_64BIT = 1 << 5;
+        /// Tn_VAL_SET_CNF: Set for the next write to a periodic timer's comparator
+        /// to set its accumulator, rather than the period.
+        const VALUE_SET = 1 << 6;
+        /// Tn_32MODE_CNF: Set if a 64-bit timer compares only the low 32 bits.
+        const MODE_32BIT = 1 << 8;
+        /// Tn_INT_ROUTE_CNF: The I/O APIC input the interrupt is routed to.
+        const INT_ROUTE_MASK = 0x1F << 9;
+        /// Tn_FSB_EN_CNF: Set if the interrupt is delivered as a message, see `Hpet::set_fsb_route`.
+        const FSB_ENABLE = 1 << 14;
+        /// Tn_FSB_INT_DEL_CAP: Set if the interrupt may be delivered as a message.
+        const FSB_CAPABLE = 1 << 15;
+        /// Tn_INT_ROUTE_CAP: The I/O APIC inputs the interrupt may be routed to, indexed by bit.
+        const INT_ROUTE_CAP_MASK = 0xFFFF_FFFF << 32;
+    }
+}
+
+/// Shift of the I/O APIC input the interrupt is routed to, see `TimerConfig::INT_ROUTE_MASK`.
+const TIMER_INT_ROUTE_SHIFT: u64 = 9;
+/// Shift of the I/O APIC inputs the interrupt may be routed to, see `TimerConfig::INT_ROUTE_CAP_MASK`.
+const TIMER_INT_ROUTE_CAP_SHIFT: u64 = 32;
+
+impl TimerConfig {
+    /// Returns the I/O APIC inputs the timer may be routed to, indexed by bit.
+    #[inline]
+    pub const fn route_capabilities(self) -> u32 {
+        (self.bits >> TIMER_INT_ROUTE_CAP_SHIFT) as u32
+    }
+    /// Returns `self` routed to the I/O APIC input `input`.
+    #[inline]
+    pub const fn with_route(self, input: u8) -> Self {
+        Self::from_bits_truncate(
+            self.bits & !Self::INT_ROUTE_MASK.bits
+            | (input as u64) << TIMER_INT_ROUTE_SHIFT & Self::INT_ROUTE_MASK.bits
+        )
+    }
+}
+
+
+/// An HPET's registers.
+#[derive(Debug, Clone, Copy)]
+pub struct Hpet {
+    base: *mut u8,
+}
+
+unsafe impl Send for Hpet {}
+unsafe impl Sync for Hpet {}
+
+impl Hpet {
+    /// ### Safety:
+    /// `base` must be the linear address of the HPET's registers, mapped
+    /// uncacheable over `HPET_REGS_SIZE`.
+    pub const unsafe fn new(base: *mut u8) -> Self {
+        Self { base }
+    }
+
+    #[inline]
+    pub fn read(&self, offset: usize) -> u64 {
+        // SAFETY: guaranteed by `new`
+        unsafe { self.base.add(offset).cast::<u64>().read_volatile() }
+    }
+    /// ### Safety:
+    /// Caller must ensure the write doesn't violate memory safety, e.g. by delivering
+    /// interrupts to vectors that aren't handled.
+    #[inline]
+    pub unsafe fn write(&self, offset: usize, value: u64) {
+        self.base.add(offset).cast::<u64>().write_volatile(value);
+    }
+
+    /// Returns the number of timers.
+    pub fn timer_count(&self) -> usize {
+        (self.read(HpetReg::Capabilities as usize) >> CAP_LAST_TIMER_SHIFT & 0x1F) as usize + 1
+    }
+    /// Returns whether the main counter is 64 bits wide, else 32.
+    pub fn is_counter_64bit(&self) -> bool {
+        self.read(HpetReg::Capabilities as usize) & CAP_COUNTER_64BIT != 0
+    }
+    /// Returns whether the legacy replacement route is supported, see `HpetConfig::LEGACY_ROUTE`.
+    pub fn is_legacy_route_capable(&self) -> bool {
+        self.read(HpetReg::Capabilities as usize) & CAP_LEGACY_ROUTE != 0
+    }
+    /// Returns the period of the main counter in femtoseconds.
+    pub fn period_fs(&self) -> u64 {
+        self.read(HpetReg::Capabilities as usize) >> CAP_PERIOD_SHIFT
+    }
+
+    pub fn config(&self) -> HpetConfig {
+        HpetConfig::from_bits_truncate(self.read(HpetReg::Configuration as usize))
+    }
+    /// ### Safety:
+    /// Caller must ensure the interrupts delivered are handled.
+    pub unsafe fn set_config(&self, config: HpetConfig) {
+        // reserved bits must be preserved
+        let reserved = self.read(HpetReg::Configuration as usize) & !HpetConfig::all().bits;
+        self.write(HpetReg::Configuration as usize, reserved | config.bits);
+    }
+
+    /// Returns the main counter.
+    #[inline]
+    pub fn counter(&self) -> u64 {
+        self.read(HpetReg::MainCounter as usize)
+    }
+    /// Sets the main counter, which should only be done while it's halted.
+    /// ### Safety:
+    /// Caller must ensure users of the counter and timers expect the change.
+    pub unsafe fn set_counter(&self, value: u64) {
+        self.write(HpetReg::MainCounter as usize, value);
+    }
+
+    /// Returns whether the level-triggered interrupt of `timer` is active.
+    pub fn is_interrupt_active(&self, timer: usize) -> bool {
+        self.read(HpetReg::InterruptStatus as usize) & 1 << timer != 0
+    }
+    /// Clears the level-triggered interrupt of `timer`.
+    pub fn clear_interrupt(&self, timer: usize) {
+        // SAFETY: the bits are cleared by writing ones, and other bits are unaffected
+        unsafe { self.write(HpetReg::InterruptStatus as usize, 1 << timer) }
+    }
+
+    pub fn timer_config(&self, timer: usize) -> TimerConfig {
+        TimerConfig::from_bits_truncate(self.read(TIMER_CONFIG_BASE + timer * TIMER_STRIDE))
+    }
+    /// ### Safety:
+    /// Caller must ensure the interrupts delivered are handled.
+    pub unsafe fn set_timer_config(&self, timer: usize, config: TimerConfig) {
+        // reserved bits must be preserved
+        let offset = TIMER_CONFIG_BASE + timer * TIMER_STRIDE;
+        let reserved = self.read(offset) & !TimerConfig::all().bits;
+        self.write(offset, reserved | config.bits);
+    }
+
+    pub fn comparator(&self, timer: usize) -> u64 {
+        self.read(TIMER_COMPARATOR_BASE + timer * TIMER_STRIDE)
+    }
+    /// Sets the comparator of `timer`, or the period of a periodic timer unless
+    /// `TimerConfig::VALUE_SET` is set, see the specification.
+    /// ### Safety:
+    /// Caller must ensure the interrupts delivered are handled.
+    pub unsafe fn set_comparator(&self, timer: usize, value: u64) {
+        self.write(TIMER_COMPARATOR_BASE + timer * TIMER_STRIDE, value);
+    }
+
+    /// Sets the message delivered by `timer` when FSB delivery is enabled, being
+    /// the write of `data` to `address`, e.g. a local APIC's message signaled
+    /// interrupt address.
+    /// ### Safety:
+    /// Caller must ensure the interrupts delivered are handled.
+    pub unsafe fn set_fsb_route(&self, timer: usize, address: u32, data: u32) {
+        self.write(TIMER_FSB_ROUTE_BASE + timer * TIMER_STRIDE, (address as u64) << 32 | data as u64);
+    }
+}
@@ -527,6 +527,9 @@ pub struct IDT {
 }
 
 impl IDT {
+    /// The number of vectors reserved for exceptions, which precede the available interrupts.
+    pub const EXCEPTION_COUNT: usize = 32;
+
     pub const fn empty() -> Self {
         Self {
             div_by_zero_fault:          IntTrapGate::<ISR>::missing(Ssdt::InterruptGate),
@@ -561,6 +564,19 @@ impl IDT {
     }
 }
 
+impl IDT {
+    /// Returns the gate of `vector`, regardless of the signature of its handler.
+    /// 
+    /// Gates of reserved vectors are included.
+    /// # Safety
+    /// Caller must ensure that any handler set through the gate suits the vector, 
+    /// e.g. handles the error code pushed by the processor, if any.
+    pub unsafe fn gate_mut(&mut self, vector: u8) -> &mut IntTrapGate<ISR> {
+        // SAFETY: the IDT is a repr(C) table of 256 gate-sized entries
+        &mut *(self as *mut Self).cast::<IntTrapGate<ISR>>().add(vector as usize)
+    }
+}
+
 /* impl Index for IDT {
     type Output = IntTrapGate<>;
 
@@ -592,6 +608,21 @@ pub fn sti_hlt() {
     }
 }
 
+/// Disables interrupts for the duration of `f`, restoring the interrupt flag afterwards.
+/// 
+/// Useful for taking locks that are also taken by interrupt handlers.
+pub fn without_interrupts<R>(f: impl FnOnce() -> R) -> R {
+    let were_enabled = crate::registers::RFLAGS::read().contains(crate::registers::RFLAGS::IF);
+    if were_enabled {
+        cli();
+    }
+    let ret = f();
+    if were_enabled {
+        sti();
+    }
+    ret
+}
+
 /// Load Interrupt Descriptor Table into IDTR
 /// # Safety:
 /// Caller must ensure loading this IDT is safe.
@@ -16,6 +16,10 @@ pub mod interrupts;
 pub mod segmentation;
 pub mod paging;
 pub mod ports;
+pub mod apic;
+pub mod pic;
+pub mod pit;
+pub mod hpet;
 
 
 
@@ -70,3 +74,12 @@ pub fn hlt_loop() -> ! {
         }
     }
 }
+
+/// Reads the time-stamp counter, once all preceding instructions have executed.
+pub fn rdtsc() -> u64 {
+    let (lo, hi): (u32, u32);
+    unsafe {
+        core::arch::asm!("lfence", "rdtsc", out("eax") lo, out("edx") hi, options(nostack, preserves_flags));
+    }
+    (hi as u64) << 32 | lo as u64
+}
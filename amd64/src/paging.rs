@@ -164,6 +164,13 @@ impl PTE {
     pub const fn get_paddr(&self) -> usize {
         self.bits & PTE::BASE_MASK.bits
     }
+
+    /// Returns the physical address of the page mapped by a leaf entry of
+    /// the given `page_size`. Unlike `get_paddr`, this masks out `PTE::PAT_PS`.
+    #[inline]
+    pub const fn get_leaf_paddr(&self, page_size: usize) -> usize {
+        self.bits & PTE::BASE_MASK.bits & !(page_size - 1)
+    }
 }
 
 
@@ -285,6 +292,62 @@ impl Pat {
 
 
 
+// TLB
+
+/// Invalidate the TLB entry for the page containing `laddr`, as well as
+/// any cached paging-structure entries.
+#[inline]
+pub fn invlpg<T>(laddr: *mut T) {
+    unsafe {
+        core::arch::asm!("invlpg [{}]", in(reg) laddr, options(nostack, preserves_flags));
+    }
+}
+
+/// INVPCID invalidation types.
+#[repr(u64)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InvpcidType {
+    /// Invalidate the entry for the linear address tagged with the PCID.
+    Address = 0,
+    /// Invalidate all non-global entries tagged with the PCID.
+    SingleContext = 1,
+    /// Invalidate all entries, including global entries, for all PCIDs.
+    AllContextsGlobal = 2,
+    /// Invalidate all non-global entries for all PCIDs.
+    AllContexts = 3,
+}
+
+/// Invalidate TLB and paging-structure cache entries by PCID.
+/// 
+/// `pcid` and `laddr` are ignored where the `InvpcidType` doesn't make use of them.
+/// ### Safety:
+/// The CPU must support the INVPCID instruction, else #UD occurs.
+#[inline]
+pub unsafe fn invpcid<T>(kind: InvpcidType, pcid: usize, laddr: *mut T) {
+    let descriptor: [u64; 2] = [pcid as u64 & 0o7777, laddr as u64];
+    core::arch::asm!(
+        "invpcid {}, [{}]",
+        in(reg) kind as u64,
+        in(reg) &descriptor,
+        options(readonly, nostack, preserves_flags)
+    );
+}
+
+/// Invalidate all TLB entries, including global entries, for all PCIDs.
+/// 
+/// This is done by toggling `CR4::PGE`, and is thus usable without INVPCID.
+pub fn flush_global() {
+    use crate::registers::CR4;
+
+    let cr4 = CR4::read();
+    // SAFETY: toggling PGE does not change the active translations
+    unsafe {
+        CR4::write(cr4 ^ CR4::PGE);
+        CR4::write(cr4);
+    }
+}
+
+
 // GENERAL PAGE TABLE UTILS
 
 
@@ -0,0 +1,200 @@
+//! Legacy 8259 Programmable Interrupt Controller (PIC) pair interface.
+//! 
+//! The master PIC handles IRQs 0-7 and the slave PIC IRQs 8-15, cascaded through
+//! the master's IRQ 2. Upon reset, the master delivers vectors 8-15, colliding 
+//! with the exception vectors, hence the pair must be remapped before being
+//! used or masked.
+
+use crate::ports::{self, Port};
+
+
+pub const MASTER_COMMAND_PORT: u16 = 0x20;
+pub const MASTER_DATA_PORT: u16 = 0x21;
+pub const SLAVE_COMMAND_PORT: u16 = 0xA0;
+pub const SLAVE_DATA_PORT: u16 = 0xA1;
+
+/// The number of IRQs of the pair.
+pub const PIC_IRQ_COUNT: u8 = 16;
+/// The master's IRQ to which the slave is cascaded.
+pub const CASCADE_IRQ: u8 = 2;
+
+/// ICW1: Begin initialization, with ICW4 to follow.
+const ICW1_INIT_ICW4: u8 = 0x11;
+/// ICW4: 8086/88 mode.
+const ICW4_8086: u8 = 0x01;
+/// OCW2: Non-specific end of interrupt.
+const OCW2_EOI: u8 = 0x20;
+/// OCW3: Read the in-service register upon the next command port read.
+const OCW3_READ_ISR: u8 = 0x0B;
+/// OCW3: Read the interrupt request register upon the next command port read.
+const OCW3_READ_IRR: u8 = 0x0A;
+
+/// Unused port written to in order to wait an I/O cycle between PIC commands.
+const IO_WAIT_PORT: u16 = 0x80;
+
+fn io_wait() {
+    // SAFETY: port 0x80 is the POST code port, which is unused after boot
+    unsafe { ports::out8(IO_WAIT_PORT, 0); }
+}
+
+
+/// A single 8259 PIC.
+#[derive(Debug)]
+struct Pic {
+    command: Port<u8>,
+    data: Port<u8>,
+}
+
+impl Pic {
+    /// Reads the register selected by the OCW3 `ocw3` through the command port.
+    fn read_reg(&mut self, ocw3: u8) -> u8 {
+        // SAFETY: OCW3 only selects the register read
+        unsafe { self.command.write(ocw3); }
+        self.command.read().0
+    }
+}
+
+/// The cascaded pair of 8259 PICs.
+/// 
+/// IRQs are numbered 0-15 across the pair, and masks are 16-bit, the low
+/// byte being the master's and the high byte the slave's.
+#[derive(Debug)]
+pub struct ChainedPics {
+    master: Pic,
+    slave: Pic,
+    vector_base: u8,
+}
+
+impl ChainedPics {
+    /// Creates a `ChainedPics` that delivers IRQs 0-15 to vectors from `vector_base` once `remap`ped.
+    /// ### Safety:
+    /// No other `ChainedPics` may access the PICs. `vector_base` must be a multiple of 8.
+    pub const unsafe fn new(vector_base: u8) -> Self {
+        Self {
+            master: Pic {
+                command: Port::new(MASTER_COMMAND_PORT, u8::MAX),
+                data: Port::new(MASTER_DATA_PORT, u8::MAX),
+            },
+            slave: Pic {
+                command: Port::new(SLAVE_COMMAND_PORT, u8::MAX),
+                data: Port::new(SLAVE_DATA_PORT, u8::MAX),
+            },
+            vector_base,
+        }
+    }
+
+    /// Returns the vector of `irq` once remapped.
+    #[inline]
+    pub const fn vector(&self, irq: u8) -> u8 {
+        self.vector_base + irq
+    }
+    /// Returns the IRQ delivered to `vector`, if any.
+    #[inline]
+    pub const fn irq(&self, vector: u8) -> Option<u8> {
+        match vector.wrapping_sub(self.vector_base) {
+            irq if irq < PIC_IRQ_COUNT => Some(irq),
+            _ => None,
+        }
+    }
+
+    /// Reinitializes the pair, delivering IRQs to vectors from the vector base,
+    /// and masking all IRQs.
+    /// ### Safety:
+    /// Interrupts must be disabled. Any IRQ pending delivery may be delivered 
+    /// to its remapped vector once interrupts are enabled.
+    pub unsafe fn remap(&mut self) {
+        self.master.command.write(ICW1_INIT_ICW4);
+        io_wait();
+        self.slave.command.write(ICW1_INIT_ICW4);
+        io_wait();
+
+        // ICW2: vector base
+        self.master.data.write(self.vector_base);
+        io_wait();
+        self.slave.data.write(self.vector_base + 8);
+        io_wait();
+
+        // ICW3: the master's IRQ line of the slave, and the slave's identity
+        self.master.data.write(1 << CASCADE_IRQ);
+        io_wait();
+        self.slave.data.write(CASCADE_IRQ);
+        io_wait();
+
+        self.master.data.write(ICW4_8086);
+        io_wait();
+        self.slave.data.write(ICW4_8086);
+        io_wait();
+
+        self.set_masks(u16::MAX);
+    }
+
+    /// Returns the IRQ masks, a set bit masking the IRQ of its index.
+    pub fn masks(&mut self) -> u16 {
+        self.master.data.read().0 as u16 | (self.slave.data.read().0 as u16) << 8
+    }
+    /// Sets the IRQ masks, a set bit masking the IRQ of its index.
+    /// ### Safety:
+    /// Caller must ensure the interrupts delivered are handled.
+    pub unsafe fn set_masks(&mut self, masks: u16) {
+        self.master.data.write(masks as u8);
+        self.slave.data.write((masks >> 8) as u8);
+    }
+
+    /// Masks all IRQs, effectively disabling the pair.
+    pub fn disable(&mut self) {
+        // SAFETY: no interrupts are delivered while masked
+        unsafe { self.set_masks(u16::MAX); }
+    }
+
+    /// Masks `irq`.
+    pub fn mask(&mut self, irq: u8) {
+        let masks = self.masks() | 1 << irq;
+        // SAFETY: only masks an IRQ
+        unsafe { self.set_masks(masks); }
+    }
+    /// Unmasks `irq`, as well as the cascade if `irq` is the slave's.
+    /// ### Safety:
+    /// Caller must ensure the interrupts delivered are handled.
+    pub unsafe fn unmask(&mut self, irq: u8) {
+        let mut masks = self.masks() & !(1 << irq);
+        if irq >= 8 {
+            masks &= !(1 << CASCADE_IRQ);
+        }
+        self.set_masks(masks);
+    }
+
+    /// Returns the in-service register of the pair, a set bit
+    /// indicating the IRQ of its index is being serviced.
+    pub fn isr(&mut self) -> u16 {
+        self.master.read_reg(OCW3_READ_ISR) as u16 | (self.slave.read_reg(OCW3_READ_ISR) as u16) << 8
+    }
+    /// Returns the interrupt request register of the pair, a set bit
+    /// indicating the IRQ of its index is pending.
+    pub fn irr(&mut self) -> u16 {
+        self.master.read_reg(OCW3_READ_IRR) as u16 | (self.slave.read_reg(OCW3_READ_IRR) as u16) << 8
+    }
+
+    /// Returns whether `irq` is a spurious interrupt, being the lowest priority IRQ
+    /// of either PIC without being in service, which mustn't be acknowledged by its PIC.
+    pub fn is_spurious(&mut self, irq: u8) -> bool {
+        (irq == 7 || irq == 15) && self.isr() & 1 << irq == 0
+    }
+
+    /// Signals the end of `irq`, to the slave too if `irq` is the slave's.
+    /// 
+    /// For spurious interrupts, the slave's are signalled to the master only,
+    /// as the cascade was in service, and the master's aren't signalled.
+    pub fn eoi(&mut self, irq: u8) {
+        let is_spurious = self.is_spurious(irq);
+
+        // SAFETY: only affects interrupt delivery
+        unsafe {
+            if irq >= 8 && !is_spurious {
+                self.slave.command.write(OCW2_EOI);
+            }
+            if irq >= 8 || !is_spurious {
+                self.master.command.write(OCW2_EOI);
+            }
+        }
+    }
+}
@@ -0,0 +1,50 @@
+//! Legacy 8253/8254 Programmable Interval Timer (PIT) interface.
+//! 
+//! Each of the PIT's three channels counts down at `PIT_FREQUENCY`. Channel 0 is
+//! wired to IRQ 0, while channel 2's gate and output are accessed through the
+//! NMI status and control port, making it usable for polled delays.
+
+use crate::ports;
+
+
+pub const CHANNEL0_PORT: u16 = 0x40;
+pub const CHANNEL2_PORT: u16 = 0x42;
+pub const COMMAND_PORT: u16 = 0x43;
+/// The NMI status and control port, holding channel 2's gate and output.
+pub const NMI_SC_PORT: u16 = 0x61;
+
+/// The frequency at which the channels count down, in hertz.
+pub const PIT_FREQUENCY: u64 = 1_193_182;
+
+/// NMI_SC: Channel 2's gate, which enables counting.
+const NMI_SC_GATE2: u8 = 1 << 0;
+/// NMI_SC: Connects channel 2's output to the PC speaker.
+const NMI_SC_SPEAKER: u8 = 1 << 1;
+/// NMI_SC: Channel 2's output.
+const NMI_SC_OUT2: u8 = 1 << 5;
+
+/// Command: Channel 2, low then high byte access, mode 0 (interrupt on terminal count), binary.
+const CMD_CHANNEL2_ONESHOT: u8 = 0b10_11_000_0;
+
+
+/// Starts channel 2 counting down from `count`, disconnected from the PC speaker.
+/// Its output is set once it reaches zero, see `is_oneshot2_done`.
+/// ### Safety:
+/// Channel 2 must not be otherwise in use, e.g. by the PC speaker.
+pub unsafe fn start_oneshot2(count: u16) {
+    // the count is loaded while the gate is low, and begins upon its rising edge
+    let nmi_sc = ports::in8(NMI_SC_PORT) & !(NMI_SC_GATE2 | NMI_SC_SPEAKER);
+    ports::out8(NMI_SC_PORT, nmi_sc);
+
+    ports::out8(COMMAND_PORT, CMD_CHANNEL2_ONESHOT);
+    ports::out8(CHANNEL2_PORT, count as u8);
+    ports::out8(CHANNEL2_PORT, (count >> 8) as u8);
+
+    ports::out8(NMI_SC_PORT, nmi_sc | NMI_SC_GATE2);
+}
+
+/// Returns whether channel 2 has counted down to zero since `start_oneshot2`.
+pub fn is_oneshot2_done() -> bool {
+    // SAFETY: reading the NMI status and control port has no side effects
+    unsafe { ports::in8(NMI_SC_PORT) & NMI_SC_OUT2 != 0 }
+}
@@ -4,8 +4,8 @@ use core::arch::asm;
 
 pub const APIC_BASE_MSR: u64 = 0x0000001B;
 pub const EFER_MSR: u64 =      0xC0000080;
-pub const MPERF_MSR: u64 =     0xC00000E7;
-pub const APERF_MSR: u64 =     0xC00000E8;
+pub const MPERF_MSR: u64 =     0x000000E7;
+pub const APERF_MSR: u64 =     0x000000E8;
 
 
 bitflags::bitflags! {
@@ -254,6 +254,19 @@ impl CR4 {
         asm!("mov cr4, {}", in(reg) cr4.bits, options(nomem, nostack, preserves_flags));
     }
 }
+impl EFER {
+    pub fn read() -> Self {
+        // SAFETY: reserved bits are retained
+        unsafe { Self::from_bits_unchecked(rdmsr(EFER_MSR)) }
+    }
+
+    /// # Safety:
+    /// Caller must gurantee that the new system behaviour as a consequence of setting 
+    /// EFER will not violate memory safety, or otherwise cause erroneous behaviour.
+    pub unsafe fn write(self) {
+        wrmsr(EFER_MSR, self.bits);
+    }
+}
 
 
 
@@ -291,7 +304,10 @@ pub struct CR3 {
 }
 
 impl CR3 {
-    const PCID_MASK: usize = 0o777;
+    const PCID_MASK: usize = 0o7777;
+    /// When set upon writing CR3 while `CR4::PCIDE` is set, the TLB entries 
+    /// of the new PCID are not invalidated.
+    const NO_FLUSH: usize = 1 << 63;
 
     /// Sets CR3 given the given physical address, no PCID, and no flags set.
     /// ### Safety:
@@ -300,6 +316,29 @@ impl CR3 {
         CR3 { data: CR3Data::Flags(CR3Flags::empty()), paddr: pml4_paddr }.write()
     }
 
+    /// Sets CR3 given the given physical address and PCID, enabling `CR4::PCIDE`
+    /// if necessary. If `no_flush` is set, the TLB entries tagged with `pcid`
+    /// are retained, else they are invalidated.
+    /// ### Safety:
+    /// See `CR3::write`. The CPU must support PCIDs.
+    pub unsafe fn set_pcid(pml4_paddr: usize, pcid: usize, no_flush: bool) {
+        if !CR4::read().contains(CR4::PCIDE) {
+            // enabling PCIDE implies a full flush anyway
+            return CR3 { data: CR3Data::PCID(pcid), paddr: pml4_paddr }.write();
+        }
+
+        let mut cr3 = pml4_paddr | pcid & Self::PCID_MASK;
+        if no_flush {
+            cr3 |= Self::NO_FLUSH;
+        }
+
+        asm!(
+            "mov cr3, {}",
+            in(reg) cr3,
+            options(nostack, preserves_flags)
+        );
+    }
+
     /// Reads the CR3.
     pub fn read() -> Self {
         let paddr_mask = crate::paging::PTE::BASE_MASK.bits();
@@ -6,6 +6,8 @@ use super::{PrivLvl, interrupts::Ssdt};
 
 
 
+/// IA32_GS_BASE MSR number
+pub const GS_BASE: u32 = 0xC0000101;
 /// IA32_KERNEL_GS_BASE MSR number
 pub const KERNEL_GS_BASE: u32 = 0xC0000102;
 
@@ -0,0 +1,312 @@
+//! Module for locating and parsing the ACPI tables.
+//! 
+//! The tables are located once from the RSDP provided by the bootloader, and
+//! the commonly used information is parsed upfront, such that subsystems 
+//! needn't scan the tables themselves.
+
+use core::ptr::{self, NonNull};
+
+use acpi::{
+    AcpiError, AcpiHandler, AcpiTable, AcpiTables, AmlTable, PhysicalMapping,
+    PlatformInfo, HpetInfo, PciConfigRegions,
+    fadt::Fadt,
+    hpet::HpetTable,
+    madt::Madt,
+    mcfg::Mcfg,
+    platform::address::{AccessSize, AddressSpace, GenericAddress},
+    sdt::Signature,
+};
+use amd64::{paging::{self, PTE, PatType}, ports};
+use spin::Once;
+
+use crate::memm::{self, MAPPER};
+
+
+/// Maps ACPI tables through the offset mapping.
+/// 
+/// Tables outside of the available physical memory, which the offset mapping
+/// may not cover, are mapped read-only upon being accessed, and remain mapped.
+#[derive(Debug, Clone, Copy)]
+pub struct OffsetAcpiHandler;
+
+impl AcpiHandler for OffsetAcpiHandler {
+    unsafe fn map_physical_region<T>(&self, paddr: usize, size: usize) -> PhysicalMapping<Self, T> {
+        let mut mapper = MAPPER.lock();
+        let pml4 = ptr::slice_from_raw_parts_mut(crate::from_phys_addr!(mapper.krnl_pml4, PTE), 512);
+
+        let base_paddr = paddr & !(paging::PTE_SIZE-1);
+        for page_paddr in (base_paddr..paddr + size).step_by(paging::PTE_SIZE) {
+            if memm::translate(crate::from_phys_addr!(page_paddr, u8), pml4).is_none() {
+                mapper.map_mmio(page_paddr, paging::PTE_SIZE, PTE::empty());
+            }
+        }
+
+        PhysicalMapping::new(
+            paddr,
+            NonNull::new_unchecked(crate::from_phys_addr!(paddr, T)),
+            size,
+            size,
+            *self
+        )
+    }
+
+    fn unmap_physical_region<T>(_: &PhysicalMapping<Self, T>) {
+        // the offset mapping is retained
+    }
+}
+
+
+/// The ACPI tables, and the information parsed from them upon `init`.
+pub struct Acpi {
+    pub tables: AcpiTables<OffsetAcpiHandler>,
+    /// The interrupt model, processors, power profile and PM timer, as per 
+    /// the MADT and FADT, or `None` if there's no valid FADT.
+    pub platform: Option<PlatformInfo>,
+    /// The HPET's registers and capabilities, or `None` if there's no HPET table.
+    pub hpet: Option<HpetInfo>,
+    /// The PCIe enhanced configuration space regions, or `None` if there's no MCFG.
+    pub pci_config: Option<PciConfigRegions>,
+}
+
+static ACPI: Once<Acpi> = Once::new();
+
+/// The number of the FADT's registers that are mapped upon `init`, see `map_fadt_regs`.
+const FADT_REGS: usize = 9;
+
+/// The physical and linear addresses of the FADT's registers in system memory,
+/// mapped once upon `init` rather than upon each access, see `gas_reg`.
+static FADT_REG_MAPPINGS: Once<[(u64, usize); FADT_REGS]> = Once::new();
+
+/// Locates the ACPI tables from the RSDP at `rsdp_paddr`, 
+/// parses the platform, HPET and MCFG information, and maps the FADT's registers.
+/// ### Safety:
+/// `rsdp_paddr` must be the physical address of a valid RSDP,
+/// and must be called after `Mapper::setup`.
+pub unsafe fn init(rsdp_paddr: usize) -> Result<(), AcpiError> {
+    // the tables and information are held in `alloc`'s types, which are retained
+    let (tables, platform, pci_config) = memm::PageAllocator::lend(|| {
+        let tables = AcpiTables::from_rsdp(OffsetAcpiHandler, rsdp_paddr)?;
+        let platform = PlatformInfo::new(&tables).ok();
+        let pci_config = PciConfigRegions::new(&tables).ok();
+        Ok((tables, platform, pci_config))
+    })?;
+    let hpet = HpetInfo::new(&tables).ok();
+
+    if let Ok(Some(fadt)) = tables.get_sdt::<Fadt>(Signature::FADT) {
+        FADT_REG_MAPPINGS.call_once(|| map_fadt_regs(&fadt));
+    }
+
+    ACPI.call_once(|| Acpi { tables, platform, hpet, pci_config });
+    Ok(())
+}
+
+/// Returns the ACPI tables and information, or `None` if `init` hasn't succeeded.
+pub fn get() -> Option<&'static Acpi> {
+    ACPI.get()
+}
+
+/// Returns the ACPI tables, or `None` if `init` hasn't succeeded.
+pub fn tables() -> Option<&'static AcpiTables<OffsetAcpiHandler>> {
+    ACPI.get().map(|acpi| &acpi.tables)
+}
+
+/// Returns the platform information, or `None` if it's unavailable, see `Acpi::platform`.
+pub fn platform() -> Option<&'static PlatformInfo> {
+    ACPI.get()?.platform.as_ref()
+}
+
+/// Returns the HPET information, or `None` if it's unavailable, see `Acpi::hpet`.
+pub fn hpet() -> Option<&'static HpetInfo> {
+    ACPI.get()?.hpet.as_ref()
+}
+
+/// Returns the PCIe configuration space regions, or `None` if they're unavailable, see `Acpi::pci_config`.
+pub fn pci_config() -> Option<&'static PciConfigRegions> {
+    ACPI.get()?.pci_config.as_ref()
+}
+
+/// Returns the mapped table of signature `signature`, or `None`
+/// if it's absent, invalid, or `init` hasn't succeeded.
+/// ### Safety:
+/// `T` must be the table of signature `signature`.
+unsafe fn sdt<T: AcpiTable>(signature: Signature) -> Option<PhysicalMapping<OffsetAcpiHandler, T>> {
+    tables()?.get_sdt::<T>(signature).ok().flatten()
+}
+
+/// Returns the mapped MADT, see `sdt`.
+pub fn madt() -> Option<PhysicalMapping<OffsetAcpiHandler, Madt>> {
+    // SAFETY: the signature is that of the MADT
+    unsafe { sdt(Signature::MADT) }
+}
+
+/// Returns the mapped FADT, see `sdt`.
+pub fn fadt() -> Option<PhysicalMapping<OffsetAcpiHandler, Fadt>> {
+    // SAFETY: the signature is that of the FADT
+    unsafe { sdt(Signature::FADT) }
+}
+
+/// Returns the mapped HPET table, see `sdt`.
+pub fn hpet_table() -> Option<PhysicalMapping<OffsetAcpiHandler, HpetTable>> {
+    // SAFETY: the signature is that of the HPET table
+    unsafe { sdt(Signature::HPET) }
+}
+
+/// Returns the mapped MCFG, see `sdt`.
+pub fn mcfg() -> Option<PhysicalMapping<OffsetAcpiHandler, Mcfg>> {
+    // SAFETY: the signature is that of the MCFG
+    unsafe { sdt(Signature::MCFG) }
+}
+
+/// Returns the location of the DSDT's AML, or `None` if there's none.
+pub fn dsdt() -> Option<&'static AmlTable> {
+    tables()?.dsdt.as_ref()
+}
+
+/// Returns the locations of the SSDTs' AML.
+pub fn ssdts() -> &'static [AmlTable] {
+    tables().map_or(&[], |tables| &tables.ssdts)
+}
+
+/// Returns the AML of `table`, as mapped by `OffsetAcpiHandler`.
+pub fn aml_bytes(table: &AmlTable) -> &'static [u8] {
+    // SAFETY: the AML stream is mapped, and remains mapped
+    unsafe {
+        let mapping = OffsetAcpiHandler.map_physical_region::<u8>(table.address, table.length as usize);
+        core::slice::from_raw_parts(mapping.virtual_start().as_ptr(), table.length as usize)
+    }
+}
+
+
+/// The PCI configuration space address port.
+const PCI_CONFIG_ADDRESS_PORT: u16 = 0xCF8;
+/// The PCI configuration space data port.
+const PCI_CONFIG_DATA_PORT: u16 = 0xCFC;
+
+/// Returns the width in bits of accesses to the register at `gas`.
+fn gas_width(gas: &GenericAddress) -> u8 {
+    match gas.access_size {
+        AccessSize::ByteAccess => 8,
+        AccessSize::WordAccess => 16,
+        AccessSize::DWordAccess => 32,
+        AccessSize::QWordAccess => 64,
+        // legacy tables specify the register's width only
+        AccessSize::Undefined => (gas.bit_width + gas.bit_offset).next_power_of_two().clamp(8, 64),
+    }
+}
+
+/// The flags of the mappings of registers in system memory.
+fn gas_leaves() -> PTE {
+    PTE::RW | memm::pat_type_to_pte(PatType::Uncacheable, false)
+}
+
+/// Maps the FADT's registers that are in system memory, see `FADT_REG_MAPPINGS`.
+unsafe fn map_fadt_regs(fadt: &Fadt) -> [(u64, usize); FADT_REGS] {
+    let regs = [
+        fadt.reset_register().ok(),
+        fadt.pm1a_event_block().ok(),
+        fadt.pm1b_event_block().ok().flatten(),
+        fadt.pm1a_control_block().ok(),
+        fadt.pm1b_control_block().ok().flatten(),
+        fadt.pm2_control_block().ok().flatten(),
+        fadt.pm_timer_block().ok().flatten(),
+        fadt.sleep_control_register().ok().flatten(),
+        fadt.sleep_status_register().ok().flatten(),
+    ];
+
+    let mut mappings = [(0, 0); FADT_REGS];
+    let mut mapper = MAPPER.lock();
+    for (mapping, gas) in mappings.iter_mut().zip(regs) {
+        if let Some(gas) = gas.filter(|gas| gas.address_space == AddressSpace::SystemMemory && gas.address != 0) {
+            let reg = mapper.map_mmio(gas.address as usize, gas_width(&gas) as usize / 8, gas_leaves());
+            *mapping = (gas.address, reg as usize);
+        }
+    }
+    mappings
+}
+
+/// Returns the mapped register at `gas`, which must be in system memory.
+/// 
+/// The FADT's registers are mapped upon `init`, others are mapped upon each access.
+unsafe fn gas_reg(gas: &GenericAddress) -> *mut u8 {
+    let mapped = FADT_REG_MAPPINGS.get()
+        .and_then(|mappings| mappings.iter().find(|&&(paddr, _)| paddr == gas.address));
+    match mapped {
+        Some(&(_, reg)) => reg as *mut u8,
+        None => MAPPER.lock().map_mmio(gas.address as usize, gas_width(gas) as usize / 8, gas_leaves()),
+    }
+}
+
+/// Selects the register at `gas` in the configuration space of a PCI device 
+/// on segment 0, bus 0, and returns the data port to access it through.
+unsafe fn select_pci_config(gas: &GenericAddress) -> u16 {
+    let device = (gas.address >> 32 & 0x1F) as u32;
+    let function = (gas.address >> 16 & 0x7) as u32;
+    let offset = (gas.address & 0xFF) as u32;
+    ports::out32(PCI_CONFIG_ADDRESS_PORT, 1 << 31 | device << 11 | function << 8 | offset & 0xFC);
+    PCI_CONFIG_DATA_PORT + (offset & 0x3) as u16
+}
+
+/// Reads the register at `gas`, or returns `Err(())` if its address space isn't supported,
+/// being other than system memory, system I/O, or PCI configuration space.
+/// ### Safety:
+/// `gas` must describe a register that's valid to read, e.g. from the FADT.
+pub unsafe fn read_gas(gas: &GenericAddress) -> Result<u64, ()> {
+    let width = gas_width(gas);
+    let value = match gas.address_space {
+        AddressSpace::SystemIo | AddressSpace::PciConfigSpace => {
+            let port = match gas.address_space {
+                AddressSpace::PciConfigSpace => select_pci_config(gas),
+                _ => gas.address as u16,
+            };
+            match width {
+                8 => ports::in8(port) as u64,
+                16 => ports::in16(port) as u64,
+                _ => ports::in32(port) as u64,
+            }
+        },
+        AddressSpace::SystemMemory => {
+            let reg = gas_reg(gas);
+            match width {
+                8 => reg.read_volatile() as u64,
+                16 => reg.cast::<u16>().read_volatile() as u64,
+                32 => reg.cast::<u32>().read_volatile() as u64,
+                _ => reg.cast::<u64>().read_volatile(),
+            }
+        },
+        _ => return Err(()),
+    };
+    Ok(value)
+}
+
+/// Writes the register at `gas`, or returns `Err(())` if its address space isn't supported,
+/// being other than system memory, system I/O, or PCI configuration space.
+/// ### Safety:
+/// `gas` must describe a register that's valid to write, e.g. from the FADT, 
+/// and the write mustn't violate memory safety.
+pub unsafe fn write_gas(gas: &GenericAddress, value: u64) -> Result<(), ()> {
+    let width = gas_width(gas);
+    match gas.address_space {
+        AddressSpace::SystemIo | AddressSpace::PciConfigSpace => {
+            let port = match gas.address_space {
+                AddressSpace::PciConfigSpace => select_pci_config(gas),
+                _ => gas.address as u16,
+            };
+            match width {
+                8 => ports::out8(port, value as u8),
+                16 => ports::out16(port, value as u16),
+                _ => ports::out32(port, value as u32),
+            }
+        },
+        AddressSpace::SystemMemory => {
+            let reg = gas_reg(gas);
+            match width {
+                8 => reg.write_volatile(value as u8),
+                16 => reg.cast::<u16>().write_volatile(value as u16),
+                32 => reg.cast::<u32>().write_volatile(value as u32),
+                _ => reg.cast::<u64>().write_volatile(value),
+            }
+        },
+        _ => return Err(()),
+    }
+    Ok(())
+}
@@ -46,6 +46,8 @@ macro_rules! krnl_boot_cfg {
 
 krnl_boot_cfg!(
     stack_size: usize = 0x800000 - 0x1000;
+    stack_guard_size: usize = 0x1000;
     heap_init_size: usize = 0x1000000;
+    heap_max_size: usize = 0x10000000;
     heap_smlst_block: usize = 0x20
 );
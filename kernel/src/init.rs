@@ -20,19 +20,26 @@ extern crate alloc;
 #[allow(dead_code)]
 mod bootboot;
 
-use core::{panic::PanicInfo, sync::atomic::{AtomicUsize, Ordering}, alloc::{Layout, GlobalAlloc, AllocError}, ptr};
+use core::{panic::PanicInfo, alloc::{Layout, GlobalAlloc, AllocError}, ptr};
  
 use alloc::boxed::Box;
-use amd64::{self, paging, registers::CR3};
+use amd64::{self, apic::ApicBase, paging, registers::CR3};
 use sys::{println, memm::{self, talloc::{Tallock, Talloc}}, from_phys_addr, cfg, out::framebuffer};
 
 
 // NO GLOBAL ALLOCATOR
 // Each CPU gets their own allocator, use that one.
+// Dependencies that require one, e.g. acpi, are lent memm::PageAllocator explicitly.
 struct Panicator;
 unsafe impl GlobalAlloc for Panicator {
-    unsafe fn alloc(&self, _: Layout) -> *mut u8 { panic!("No global allocator!"); }
-    unsafe fn dealloc(&self, _: *mut u8, _: Layout) { panic!("No global allocator!"); }
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        if !memm::PageAllocator::is_lent() { panic!("No global allocator!"); }
+        memm::PageAllocator.alloc(layout)
+    }
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        // only memory allocated while lent is freed
+        memm::PageAllocator.dealloc(ptr, layout)
+    }
 }
 #[global_allocator]
 static PANICATOR: Panicator = Panicator;
@@ -48,100 +55,128 @@ pub extern "C" fn _start() -> ! {
     
     unsafe { memm::KRNL_DEFAULT_PAT.write(); }
 
-    static THREAD_TICKET: AtomicUsize = AtomicUsize::new(0);
-    let thread_ticket = THREAD_TICKET.fetch_add(1, Ordering::SeqCst);
-    
-    static IS_MAPPER_INITD_PML4: AtomicUsize = AtomicUsize::new(usize::MAX);
-    
-    if thread_ticket == 0 {
-        unsafe {
-            sys::out::terminal::TERM1.lock().fb = sys::out::framebuffer::FrameBuffer::new(
-                bootboot::FRAMEBUFFER, 
-                (*bootboot::BOOTBOOT).fb_width as usize,
-                (*bootboot::BOOTBOOT).fb_height as usize,
-                (*bootboot::BOOTBOOT).fb_scanline as usize,
-                match (*bootboot::BOOTBOOT).fb_type {
-                    bootboot::FB_ABGR => framebuffer::PixelFormat::ABGR,
-                    bootboot::FB_ARGB => framebuffer::PixelFormat::ARGB,
-                    bootboot::FB_BGRA => framebuffer::PixelFormat::BGRA,
-                    bootboot::FB_RGBA => framebuffer::PixelFormat::RGBA,
-                    _ => panic!()
-                }
-            );
-        }
+    // application processors started by the bootloader wait to be restarted by sys::smp
+    if !ApicBase::read().contains(ApicBase::BSP) {
+        interrupts::cli();
+        amd64::hlt_loop();
+    }
 
-        println!("[BSP] KERNEL _START! ");
-        sys::print!("{}", char::from_u32(31).unwrap());
+    // SAFETY: this is the BSP, which has just started
+    unsafe { sys::percpu::init_boot(); }
 
-        if true { // BOOTBOOT
-            // Store configuration file as static data.
-            // SAFETY: this is only called once,
-            // mapping is unchanged, bootloader is BOOTBOOT
-            cfg::init_boot_cfg(unsafe { bootboot::env_cfg_as_str() });
-        }
+    unsafe {
+        sys::out::terminal::TERM1.lock().fb = sys::out::framebuffer::FrameBuffer::new(
+            bootboot::FRAMEBUFFER, 
+            (*bootboot::BOOTBOOT).fb_width as usize,
+            (*bootboot::BOOTBOOT).fb_height as usize,
+            (*bootboot::BOOTBOOT).fb_scanline as usize,
+            match (*bootboot::BOOTBOOT).fb_type {
+                bootboot::FB_ABGR => framebuffer::PixelFormat::ABGR,
+                bootboot::FB_ARGB => framebuffer::PixelFormat::ARGB,
+                bootboot::FB_BGRA => framebuffer::PixelFormat::BGRA,
+                bootboot::FB_RGBA => framebuffer::PixelFormat::RGBA,
+                _ => panic!()
+            }
+        );
+    }
 
-        // set up mapper & physical memory management
-        let pml4_paddr = unsafe {
-            let iter = bootboot::mmap_available_iter();
-            memm::Mapper::setup(&iter)
-        };
+    println!("[BSP] KERNEL _START! ");
+    sys::print!("{}", char::from_u32(31).unwrap());
 
-        IS_MAPPER_INITD_PML4.store(pml4_paddr, Ordering::SeqCst);
-    } else {
-        while IS_MAPPER_INITD_PML4.load(Ordering::SeqCst) == usize::MAX {
-            unsafe {
-                core::arch::asm!("pause", options(nomem, nostack, preserves_flags));
-            }
-        }
+    if true { // BOOTBOOT
+        // Store configuration file as static data.
+        // SAFETY: this is only called once,
+        // mapping is unchanged, bootloader is BOOTBOOT
+        cfg::init_boot_cfg(unsafe { bootboot::env_cfg_as_str() });
     }
+
+    // set up mapper & physical memory management
+    let pml4_paddr = unsafe {
+        let iter = bootboot::mmap_available_iter();
+        memm::Mapper::setup(&iter)
+    };
     
     unsafe {
-        CR3::set_nflags(IS_MAPPER_INITD_PML4.load(Ordering::SeqCst));
+        CR3::set_nflags(pml4_paddr);
     }
 
 
-    // map thread stack by thread_ticket index
-    let stack_acme = memm::KRNL_STACK_ACME - (memm::KRNL_STACK_SIZE + paging::PTE_SIZE) * thread_ticket;
+    // map the BSP's stacks, surrounded by guard pages; those of APs are mapped by sys::smp
+    let stack_acme = memm::stack::krnl_stack_acme(0);
     unsafe {
-        // todo: map stacks with 2mib gap?
-        let _mapping = memm::MAPPER.lock().map(
-            (stack_acme - memm::KRNL_STACK_SIZE) as *mut u8,
-            memm::KRNL_STACK_SIZE, 
-            paging::PTE::RW, 
-            paging::PTE::RW,
+        memm::stack::map_stacks(
+            0,
             CR3::read().get_laddr_offset(memm::PHYS_LADDR_OFFSET)
         );
     }
 
     unsafe {
-        // switch the stacks onto loader pre-allocated stack area
-        // mem::KERNEL_STACK_BOTTOM itself is unmapped, thus reduce by sixteen (preserves alignment)
+        // switch the stacks onto the mapped stack area and jump to init
+        // the stack acme itself is unmapped, thus reduce by sixteen (preserves alignment)
         core::arch::asm!(
             // set the stack pointer
             "mov rsp, {}",
+            "call {}",
             in(reg) stack_acme - 0x10,
-            options(preserves_flags)
+            in(reg) init as usize,
+            in("rdi") 0,
+            options(noreturn)
         );
     };
-    
-    // jump to init immediately; don't touch the stack
-    init();
 }
 
-fn init() -> ! {
-    static THREAD_TICKET: AtomicUsize = AtomicUsize::new(0);
-    let thread_ticket = THREAD_TICKET.fetch_add(1, Ordering::SeqCst);
+extern "sysv64" fn init(cpu: usize) -> ! {
+    println!("CPU{}: KERNEL INIT", cpu);
 
-    println!("T{}: KERNEL INIT", thread_ticket);
+    let talloc: &'static Tallock = Box::leak(unsafe { allocator_setup(cpu) });
+    let (gdt, idt, tss) = unsafe { setup_sys_tables(talloc, cpu) };
 
-    let mut talloc = unsafe { allocator_setup(thread_ticket) };
-    let (gdt, idt, tss) = unsafe { setup_sys_tables(talloc.as_ref()) };
+    if cpu == 0 {
+        // SAFETY: BOOTBOOT provides the RSDP's physical address, this is only called once, before the APs start
+        unsafe {
+            sys::acpi::init((*bootboot::BOOTBOOT).platform.acpi_paddr as usize)
+                .expect("ACPI table parsing failed.");
+
+            // the legacy PICs deliver onto the exception vectors until remapped
+            if sys::interrupts::lapic::is_supported() {
+                sys::interrupts::pic::disable();
+                sys::interrupts::ioapic::init().expect("I/O APIC discovery failed.");
+            } else {
+                sys::interrupts::pic::init_fallback();
+            }
 
-    if thread_ticket == 0 {
+            if sys::time::hpet::init().is_err() {
+                println!("HPET: unavailable.");
+            }
+            sys::time::init();
+        }
+    }
+
+    if sys::interrupts::lapic::is_supported() {
+        unsafe { sys::interrupts::lapic::init(); }
+    }
+
+    // SAFETY: the tables are loaded, and the local APIC is initialized if supported
+    unsafe { sys::percpu::init(cpu, talloc, gdt, idt, tss); }
+
+    if cpu == 0 && sys::interrupts::lapic::is_supported() {
+        // SAFETY: this is the BSP, and init sets up each AP as it does the BSP
+        unsafe {
+            sys::smp::init();
+            sys::smp::start_aps(init);
+        }
+    }
+
+    // SAFETY: interrupts are enabled hereafter, such that shootdowns are acknowledged
+    unsafe { memm::tlb::join(); }
+    interrupts::sti();
+
+    sys::time::init_cpu();
+
+    if cpu == 0 {
         /* println!("sizeof inttrapgate: {}", core::mem::size_of::<IntTrapGate<interrupts::ISR>>());
         println!("sizeof idt: {}", core::mem::size_of::<[IntTrapGate<interrupts::ISR>; 256]>());
         println!("sizeof idt: {}", core::mem::size_of::<IDT>()); */
-        // ISRs with error codes are breaking!
         unsafe { core::arch::asm!("int3"); }
         //unsafe { core::arch::asm!("mov rcx, 0", "div rcx"); }
         unsafe { core::arch::asm!("nop"); }
@@ -158,23 +193,18 @@ fn init() -> ! {
     amd64::hlt_loop();
 
 
-    // extract data from bb structs
-
-    // acpi rsdp
-    // acpihandler
-    // acpi
-    // madtd
-
-    // apic
-    // idt & interrupt handling
-
-
     //amd64::hlt_loop()
 }
 
 
 #[panic_handler]
 fn panic_handler(info: &PanicInfo) -> ! {
+    interrupts::cli();
+    // only the first CPU to panic reports it, the others are halted
+    sys::xcall::freeze_others();
+    // SAFETY: the other CPUs are halted, yet one may have been printing
+    unsafe { sys::out::terminal::TERM1.force_unlock(); }
+
     println!("{}", info);
 
     amd64::hlt_loop()
@@ -183,20 +213,31 @@ fn panic_handler(info: &PanicInfo) -> ! {
 
 
 /// Sets up an allocator for this CPU and returns itself allocated on it's own heap.
-unsafe fn allocator_setup(thread_ticket: usize) -> Box<Tallock, &'static Tallock> {
+unsafe fn allocator_setup(cpu: usize) -> Box<Tallock, &'static Tallock> {
     use core::alloc::Allocator;
 
-    let heap_base = -(paging::PDPTE_SIZE as isize) * (2 + thread_ticket as isize);
+    let heap_base = memm::stack::slot_base(cpu) as isize;
     let heap_size = cfg::heap_init_size();
     let heap_smlst_block = cfg::heap_smlst_block();
 
+    // the initial heap is mapped eagerly, as it's used to set up the IDT
     memm::MAPPER.lock().map(
         heap_base as *mut u8,
         heap_size,
         paging::PTE::RW,
         paging::PTE::RW,
+        memm::frames::FrameOwner::Heap,
         core::ptr::slice_from_raw_parts_mut(from_phys_addr!(CR3::read().paddr, paging::PTE), 512)
     );
+    // reserve the rest of the heap's span to be backed upon page fault
+    memm::region::REGIONS.lock().reserve(memm::region::Region::anonymous(
+        heap_base as *mut u8,
+        (heap_base + heap_size as isize) as *mut u8,
+        (heap_base + heap_max_size() as isize) as *mut u8,
+        paging::PTE::RW,
+        paging::PTE::RW,
+        memm::frames::FrameOwner::Heap,
+    )).expect("Heap region reservation failed.");
 
     let tallock = memm::talloc::Tallock(spin::Mutex::new(
         memm::talloc::Talloc::new(
@@ -219,24 +260,27 @@ unsafe fn allocator_setup(thread_ticket: usize) -> Box<Tallock, &'static Tallock
     tallock_box
 }
 
+/// Returns the size the heap may grow to, being the lesser of the
+/// configured maximum and the space below the stacks.
+fn heap_max_size() -> usize {
+    (paging::PDPTE_SIZE - memm::stack::stacks_size()).min(cfg::heap_max_size())
+}
+
 fn oom_handler(talloc: &mut Talloc, layout: Layout) -> Result<(), AllocError> {
     let (arena_base, arena_size) = talloc.get_arena();
 
-    let max_arena_size = paging::PDPTE_SIZE - cfg::stack_size() - paging::PTE_SIZE;
-    let lgr_size = (arena_size * 2).min(max_arena_size);
+    let lgr_size = (arena_size * 2).min(heap_max_size());
     let free_mem_size = talloc.req_free_mem(arena_base, lgr_size);
 
     // ensure there is 1) sufficient room to expand, 2) enough space for status data
     if arena_size < lgr_size - free_mem_size {
+        // the extension is backed lazily upon page fault
+        memm::region::REGIONS.lock().commit(
+            arena_base as *mut u8,
+            (arena_base + lgr_size as isize) as *mut u8,
+        )?;
+
         unsafe {
-            memm::MAPPER.lock().map(
-                (arena_base + arena_size as isize) as *mut _, 
-                lgr_size - arena_size, 
-                paging::PTE::RW, 
-                paging::PTE::RW, 
-                CR3::read().get_laddr_offset(memm::PHYS_LADDR_OFFSET),
-            );
-    
             talloc.extend(
                 arena_base,
                 lgr_size,
@@ -255,7 +299,7 @@ fn oom_handler(talloc: &mut Talloc, layout: Layout) -> Result<(), AllocError> {
 use amd64::{
     PrivLvl,
     segmentation::{self, SegSel, SysSegDesc, TaskStateSeg, CodeSegDesc, DataSegDesc},
-    interrupts::{self, IDT, Ssdt, IntTrapGate, InterruptStackFrame},
+    interrupts::{self, IDT, Ssdt, IntTrapGate},
 };
 
 
@@ -268,10 +312,15 @@ pub const DATA_SEG_SEL: SegSel = SegSel::new_gdt(PrivLvl::Ring3, DATA_SEG_IDX);
 pub const TSS_SEG_IDX: u16 = 4;
 pub const TSS_SEG_SEL: SegSel = SegSel::new_gdt(PrivLvl::Ring0, TSS_SEG_IDX);
 
-pub unsafe fn setup_sys_tables(talloc: &crate::memm::talloc::Tallock)
--> (Box<[u64], &Tallock>, Box<IDT, &Tallock>, Box<TaskStateSeg, &Tallock>, ) {
+pub unsafe fn setup_sys_tables(talloc: &'static Tallock, cpu: usize)
+-> (Box<[u64], &'static Tallock>, Box<IDT, &'static Tallock>, Box<TaskStateSeg, &'static Tallock>, ) {
 
-    let tss = TaskStateSeg::new([ptr::null_mut(); 3], [ptr::null_mut(); 7]);
+    // the guard-paged interrupt stacks were mapped along with the kernel stack
+    let mut ist_table = [ptr::null_mut(); 7];
+    for ist in 1..=memm::stack::IST_COUNT as u8 {
+        ist_table[ist as usize - 1] = memm::stack::ist_acme(cpu, ist) as *mut u8;
+    }
+    let tss = TaskStateSeg::new([ptr::null_mut(); 3], ist_table);
     let mut tss = Box::new_in(tss, talloc);
 
     let tss_desc = SysSegDesc::new(
@@ -295,12 +344,11 @@ pub unsafe fn setup_sys_tables(talloc: &crate::memm::talloc::Tallock)
     segmentation::lgdt(gdt.as_mut() as *mut _);
     // switch to new code segment
     segmentation::cs_write(KRNL_CODE_SEG_SEL);
-    // switch data segments
+    // switch data segments; loading GS would clear its base, see sys::percpu
     core::arch::asm!(
         "mov ds, {0:x}",
         "mov es, {0:x}",
         "mov fs, {0:x}",
-        "mov gs, {0:x}",
         "mov ss, {0:x}",
         in(reg) DATA_SEG_IDX,
     );
@@ -310,334 +358,20 @@ pub unsafe fn setup_sys_tables(talloc: &crate::memm::talloc::Tallock)
 
     let mut idt = Box::new_in(IDT::empty(), talloc);
 
-    // todo: create some ISTs and have abort exceptions use them
-    // todo: create more ISRs
+    // every vector enters through its stub, see sys::interrupts
+    for vector in 0..=u8::MAX {
+        *idt.gate_mut(vector) = IntTrapGate::new(sys::interrupts::stub_laddr(vector), KRNL_CODE_SEG_SEL, 0, Ssdt::InterruptGate, PrivLvl::Ring0);
+    }
 
-    idt.div_by_zero_fault = IntTrapGate::new(div_by_zero_fault as u64, KRNL_CODE_SEG_SEL, 0, Ssdt::InterruptGate, PrivLvl::Ring0);
-    idt.debug = IntTrapGate::new(debug_exception as u64, KRNL_CODE_SEG_SEL, 0, Ssdt::InterruptGate, PrivLvl::Ring0);
-    idt.break_point_trap = IntTrapGate::new(naked_breakpoint_trap_wrapper as u64, KRNL_CODE_SEG_SEL, 0, Ssdt::InterruptGate, PrivLvl::Ring0);
-    idt.double_fault_abort = IntTrapGate::new(double_fault_abort as u64,KRNL_CODE_SEG_SEL,0,Ssdt::InterruptGate,PrivLvl::Ring0);
-    idt.page_fault = IntTrapGate::new(naked_page_fault_wrapper as u64,KRNL_CODE_SEG_SEL,0,Ssdt::InterruptGate,PrivLvl::Ring0);
-    idt.general_protection_fault = IntTrapGate::new(naked_general_protection_fault_wrapper as u64,KRNL_CODE_SEG_SEL,0,Ssdt::InterruptGate,PrivLvl::Ring0);
-    idt.segment_not_present_fault = IntTrapGate::new(segment_not_present_fault as u64,KRNL_CODE_SEG_SEL,0,Ssdt::InterruptGate,PrivLvl::Ring0);
-    idt.alignment_check_fault = IntTrapGate::new(alignment_check_fault as u64,KRNL_CODE_SEG_SEL,0,Ssdt::InterruptGate,PrivLvl::Ring0);
+    // these may occur while the current stack is unusable, e.g. overflowed, hence have their own
+    idt.double_fault_abort.set_ist(memm::stack::DOUBLE_FAULT_IST);
+    idt.non_maskable_interrupt.set_ist(memm::stack::NMI_IST);
+    idt.machine_check_abort.set_ist(memm::stack::MACHINE_CHECK_IST);
+    idt.debug.set_ist(memm::stack::DEBUG_IST);
+    // page faults may be upon the current stack's unbacked pages, see memm::stack
+    idt.page_fault.set_ist(memm::stack::PAGE_FAULT_IST);
 
     interrupts::lidt(idt.as_ref() as *const _);
 
     (gdt, idt, tss)
 }
-
-
-extern "x86-interrupt" fn div_by_zero_fault(stack_frame: InterruptStackFrame) {
-    crate::println!("DIV BY ZERO FAULT!\nStack Frame: {:#?}", stack_frame);
-
-    amd64::hlt_loop();
-}
-
-extern "x86-interrupt" fn debug_exception(stack_frame: InterruptStackFrame) {
-    crate::println!("DEBUG EXCEPTION!\nStack Frame: {:#?}", stack_frame);
-}
-
-#[no_mangle]
-extern "x86-interrupt" fn break_point_trap(stack_frame: InterruptStackFrame) {
-    /* let rsp: *const u64;
-    unsafe { core::arch::asm!("lea {}, [rsp+0]", out(reg) rsp, options(nomem, nostack, preserves_flags)); }
-    let slice = core::ptr::slice_from_raw_parts(rsp.wrapping_sub(0x100), 0x200);
-    println!();
-    for i in (0..slice.len()).rev() {
-        sys::print!("{:x} ", unsafe { *slice.get_unchecked(i) });
-    }
-    let ptr = core::ptr::addr_of!(stack_frame);
-    println!("{:p} {:p}", rsp, ptr); */
-    crate::println!("BREAK POINT TRAP!\nStack Frame: {:#?}", &stack_frame);
-}
-
-/* #[naked]
-extern "C" fn naked_page_fault_wrapper() -> ! {
-    unsafe {
-        asm!("mov rdi, rsp; call $0"
-             :: "i"(divide_by_zero_handler as extern "C" fn(_) -> !)
-             : "rdi" : "intel");
-        ::core::intrinsics::unreachable();
-    }
-} */
-/* extern "C" fn naked_breakpoint_trap_wrapper() -> ! {
-    unsafe {
-        core::arch::asm!(
-            "add rsp, 8",
-            "mov rdi, rsp",
-            "call naked_breakpoint_trap",
-            options(noreturn),
-        );
-    }
-} */
-#[no_mangle]
-extern "sysv64" fn naked_breakpoint_trap(stack_frame: &InterruptStackFrame) {
-    /* let rsp: *const u64;
-    unsafe { core::arch::asm!("mov {}, rsp", out(reg) rsp, options(nostack, nomem, preserves_flags)); }
-    println!("rsp: {:p}", rsp);
-    /* let rsp = rsp.wrapping_add(rsp as usize % 16);
-    for i in -0x100..0x100 {
-        sys::print!("{:p}:{:x} ", unsafe { rsp.offset(i) }, unsafe { *rsp.offset(i) });
-    } */ */
-
-    crate::println!(
-        "NAKED BREAKPOINT TRAP!\nStack Frame: {:#?}",
-        unsafe { /* &* */stack_frame },
-    );
-    /* amd64::hlt_loop(); */
-}
-/* extern "C" fn naked_page_fault_wrapper() -> ! {
-    unsafe {
-        core::arch::asm!(
-            "add rsp, 8",
-            "pop rsi",
-            "mov rdi, rsp",
-            "call naked_page_fault",
-            options(noreturn),
-        );
-    }
-} */
-#[no_mangle]
-extern "sysv64" fn naked_page_fault(stack_frame: &InterruptStackFrame, err_code: u64) -> ! {
-    let rsp: *const u64;
-    unsafe { core::arch::asm!("mov {}, rsp", out(reg) rsp, options(nostack, nomem, preserves_flags)); }
-    println!("rsp: {:p}", rsp);
-    /* let rsp = rsp.wrapping_add(rsp as usize % 16);
-    for i in -0x100..0x100 {
-        sys::print!("{:p}:{:x} ", unsafe { rsp.offset(i) }, unsafe { *rsp.offset(i) });
-    } */
-
-    let cr2 = amd64::registers::cr2_read();
-    crate::println!(
-        "PAGE FAULT!\nStack Frame: {:#?}\nError code: {:?}\nCR2: {:p}",
-        stack_frame,
-        unsafe { interrupts::PfErrCode::from_bits_unchecked(err_code) },
-        cr2
-    );
-    amd64::hlt_loop();
-}
-
-/* extern { fn naked_general_protection_fault_wrapper() -> !; }
-core::arch::global_asm!(
-    "naked_general_protection_fault_wrapper:",
-    "and rsp, 0xfffffffffffffff0",
-    "pop rsi",
-    "mov rdi, rsp",
-    "call naked_general_protection_fault"
-); */
-
-/* extern { fn naked_page_fault_wrapper() -> !; }
-core::arch::global_asm!(
-    "naked_page_fault_wrapper:",
-    "and rsp, 0xfffffffffffffff0",
-    "pop rsi",
-    "mov rdi, rsp",
-    "call naked_page_fault"
-); */
-
-/* extern { fn naked_breakpoint_trap_wrapper() -> !; }
-core::arch::global_asm!(
-    "naked_breakpoint_trap_wrapper:",
-    "jmp break_point_trap",
-    "iretq",
-); */
-
-
-extern { fn naked_breakpoint_trap_wrapper(); }
-core::arch::global_asm!("
-naked_breakpoint_trap_wrapper:
-    push rax
-    push rcx
-    push rdx
-    push rsi
-    push rdi
-    push r8
-    push r9
-    push r10
-    push r11
-
-    mov rdi, rsp
-    add rdi, 0x48
-
-    //sub rsp, 8
-
-    call naked_breakpoint_trap
-
-    //add rsp, 8
-
-    pop r11
-    pop r10
-    pop r9
-    pop r8
-    pop rdi
-    pop rsi
-    pop rdx
-    pop rcx
-    pop rax
-
-    iretq"
-);
-extern { fn naked_page_fault_wrapper() -> !; }
-core::arch::global_asm!("
-naked_page_fault_wrapper:
-    push rax
-    push rcx
-    push rdx
-    push rsi
-    push rdi
-    push r8
-    push r9
-    push r10
-    push r11
-
-    mov rsi, [rsp+0x48]
-    mov rdi, rsp
-    add rdi, 0x50
-
-    sub rsp, 8
-
-    call naked_page_fault
-
-    add rsp, 8
-
-    pop r11
-    pop r10
-    pop r9
-    pop r8
-    pop rdi
-    pop rsi
-    pop rdx
-    pop rcx
-    pop rax
-
-    add rsp, 8
-
-    iretq"
-);
-extern { fn naked_general_protection_fault_wrapper() -> !; }
-core::arch::global_asm!("
-naked_general_protection_fault_wrapper:
-    push rax
-    push rcx
-    push rdx
-    push rsi
-    push rdi
-    push r8
-    push r9
-    push r10
-    push r11
-
-    mov rsi, [rsp+0x40]
-    mov rdi, rsp
-    add rdi, 0x48
-
-    sub rsp, 8
-
-    call naked_general_protection_fault
-
-    add rsp, 8
-
-    pop r11
-    pop r10
-    pop r9
-    pop r8
-    pop rdi
-    pop rsi
-    pop rdx
-    pop rcx
-    pop rax
-
-    add rsp, 8
-
-    iretq"
-);
-
-#[no_mangle]
-extern "x86-interrupt" fn page_fault(stack_frame: InterruptStackFrame/* , err_code: u64 */) {
-    /* unsafe { core::arch::asm!("add rsp, 8", options(nomem, preserves_flags)); } */
-    /* let rsp: *const u64;
-    unsafe { core::arch::asm!("lea {}, [rsp+0]", out(reg) rsp, options(nomem, nostack, preserves_flags)); }
-    let slice = core::ptr::slice_from_raw_parts(rsp.wrapping_sub(0x100), 0x200);
-    println!();
-    for i in (0..slice.len()).rev() {
-        sys::println!("{:p} {:x}", unsafe {  slice.get_unchecked(i) }, unsafe { *slice.get_unchecked(i) });
-    }
-    //unsafe { core::arch::asm!("lea rsp, [rsp-16]", options(nomem, nostack, preserves_flags)); }
-    let ptr = core::ptr::addr_of!(stack_frame);
-    println!("rsp {:p}, isf ptr {:p}", rsp, ptr);
-    // let ptr = ptr.cast::<u8>().wrapping_sub(128).cast::<InterruptStackFrame>(); */
-
-    let stack_frame = unsafe { *core::ptr::addr_of!(stack_frame).cast::<u8>().wrapping_add(8).cast::<InterruptStackFrame>() };
-    let err_code = unsafe { *core::ptr::addr_of!(stack_frame).cast::<u64>()/* .wrapping_sub(1) */ };
-
-    let cr2 = amd64::registers::cr2_read();
-    crate::println!(
-        "PAGE FAULT!\nStack Frame: {:#?}\nError code: {:?}\nCR2: {:p}",
-        &stack_frame,
-        unsafe { interrupts::PfErrCode::from_bits_unchecked(err_code) },
-        cr2
-    );
-
-    amd64::hlt_loop();
-}
-
-extern "x86-interrupt" fn double_fault_abort(stack_frame: InterruptStackFrame/* , err_code: u64 */) -> ! {
-    let stack_frame = unsafe { *core::ptr::addr_of!(stack_frame).cast::<u8>().wrapping_add(8).cast::<InterruptStackFrame>() };
-    let err_code = unsafe { *core::ptr::addr_of!(stack_frame).cast::<u64>()/* .wrapping_sub(1) */ };
-
-    crate::println!("DOUBLE FAULT!\nStack Frame: {:#?}\nError Code: {:#?}", stack_frame, err_code);
-
-    amd64::hlt_loop();
-}
-
-/* extern "C" fn naked_general_protection_fault_wrapper() -> ! {
-    unsafe {
-        core::arch::asm!(
-            //"add rsp, 8",
-            "pop rsi",
-            "mov rdi, rsp",
-            "call naked_general_protection_fault",
-            options(noreturn),
-        );
-    }
-} */
-#[no_mangle]
-extern "sysv64" fn naked_general_protection_fault(stack_frame: &InterruptStackFrame, err_code: u64) -> ! {
-    crate::println!(
-        "NAKED GENERAL PROTECTION FAULT!\nStack Frame: {:#?}\nError code: {:#x}",
-        stack_frame,
-        err_code
-    );
-    amd64::hlt_loop();
-}
-extern "x86-interrupt" fn general_protection_fault(stack_frame: InterruptStackFrame/* , err_code: u64 */) {
-    let stack_frame = unsafe { *core::ptr::addr_of!(stack_frame).cast::<u8>()/* .wrapping_add(8) */.cast::<InterruptStackFrame>() };
-    let err_code = unsafe { *core::ptr::addr_of!(stack_frame).cast::<u64>().wrapping_sub(1) };
-
-    crate::println!("GENERAL PROTECTION FAULT!\nStack Frame: {:#?}", stack_frame);
-    if err_code != 0 {
-        crate::println!("Error Code: {:#x}", err_code);
-    }
-
-    amd64::hlt_loop();
-}
-
-extern "x86-interrupt" fn segment_not_present_fault(stack_frame: InterruptStackFrame/* , err_code: u64 */) {
-    let stack_frame = unsafe { *core::ptr::addr_of!(stack_frame).cast::<u8>().wrapping_add(8).cast::<InterruptStackFrame>() };
-    let err_code = unsafe { *core::ptr::addr_of!(stack_frame).cast::<u64>()/* .wrapping_sub(1) */ };
-
-    crate::println!("SEGMENT NOT PRESENT FAULT!\nStack Frame: {:#?}\nError Code: {:#x}", stack_frame, err_code);
-
-    amd64::hlt_loop();
-}
-
-extern "x86-interrupt" fn alignment_check_fault(stack_frame: InterruptStackFrame, err_code: u64) {
-    crate::println!("ALIGNMENT CHECK FAULT!\nStack Frame: {:#?}\nError Code: {:#x}", stack_frame, err_code);
-
-    amd64::hlt_loop();
-}
-
-
-
-
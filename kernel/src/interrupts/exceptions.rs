@@ -0,0 +1,101 @@
+//! Module for the handlers of the exception vectors.
+
+use amd64::{interrupts::{IDT, PfErrCode}, registers::{self, CR3}};
+
+use crate::memm::{self, fault::{PageFault, PageWalk}};
+use super::TrapFrame;
+
+
+pub const DIV_BY_ZERO_VECTOR: u8 = 0;
+pub const DEBUG_VECTOR: u8 = 1;
+pub const NMI_VECTOR: u8 = 2;
+pub const BREAK_POINT_VECTOR: u8 = 3;
+pub const DOUBLE_FAULT_VECTOR: u8 = 8;
+pub const GENERAL_PROTECTION_VECTOR: u8 = 13;
+pub const PAGE_FAULT_VECTOR: u8 = 14;
+pub const MACHINE_CHECK_VECTOR: u8 = 18;
+
+/// The names of the exceptions, indexed by vector.
+pub const EXCEPTION_NAMES: [&str; IDT::EXCEPTION_COUNT] = [
+    "DIVIDE-BY-ZERO-ERROR (#DE)",
+    "DEBUG (#DB)",
+    "NON-MASKABLE INTERRUPT (#NMI)",
+    "BREAKPOINT (#BP)",
+    "OVERFLOW (#OF)",
+    "BOUND-RANGE (#BR)",
+    "INVALID-OPCODE (#UD)",
+    "DEVICE-NOT-AVAILABLE (#NM)",
+    "DOUBLE-FAULT (#DF)",
+    "COPROCESSOR-SEGMENT-OVERRUN",
+    "INVALID-TSS (#TS)",
+    "SEGMENT-NOT-PRESENT (#NP)",
+    "STACK (#SS)",
+    "GENERAL-PROTECTION (#GP)",
+    "PAGE-FAULT (#PF)",
+    "RESERVED (15)",
+    "X87 FLOATING-POINT EXCEPTION PENDING (#MF)",
+    "ALIGNMENT-CHECK (#AC)",
+    "MACHINE-CHECK (#MC)",
+    "SIMD FLOATING-POINT (#XF)",
+    "RESERVED (20)",
+    "CONTROL-PROTECTION (#CP)",
+    "RESERVED (22)",
+    "RESERVED (23)",
+    "RESERVED (24)",
+    "RESERVED (25)",
+    "RESERVED (26)",
+    "RESERVED (27)",
+    "HYPERVISOR INJECTION (#HV)",
+    "VMM COMMUNICATION (#VC)",
+    "SECURITY (#SX)",
+    "RESERVED (31)",
+];
+
+
+/// Handles the exception of the frame, returning if the interrupted context may resume.
+pub fn dispatch(frame: &mut TrapFrame) {
+    match frame.vector as u8 {
+        // the CPU is being frozen by a panicking CPU
+        NMI_VECTOR if crate::xcall::is_freezing() => crate::xcall::freeze(),
+        DEBUG_VECTOR | NMI_VECTOR | BREAK_POINT_VECTOR => report(frame),
+        DOUBLE_FAULT_VECTOR => double_fault(frame),
+        PAGE_FAULT_VECTOR => page_fault(frame),
+        _ => fatal(frame),
+    }
+}
+
+/// Reports an exception that doesn't affect the interrupted context.
+/// 
+/// The report is dropped if the output is locked, as the interrupted context
+/// may hold the lock, e.g. upon an NMI.
+fn report(frame: &TrapFrame) {
+    crate::try_println!("{}!\nStack Frame: {:#?}", EXCEPTION_NAMES[frame.vector as usize], frame.stack_frame);
+}
+
+/// Panics with the state of an exception the interrupted context can't recover from.
+fn fatal(frame: &TrapFrame) -> ! {
+    panic!("{}!\n{:#?}", EXCEPTION_NAMES[frame.vector as usize], frame);
+}
+
+fn page_fault(frame: &mut TrapFrame) {
+    let cr2 = registers::cr2_read();
+    let fault = PageFault::new(cr2 as *mut u8, PfErrCode::from_bits_truncate(frame.err_code));
+
+    // return to retry the access if the fault was resolved
+    // SAFETY: this is the page fault handler, handling this fault
+    if let Err(reason) = unsafe { memm::fault::resolve(&fault) } {
+        // SAFETY: page tables are offset-identity mapped
+        let walk = unsafe { PageWalk::new(fault.laddr, CR3::read().get_laddr_offset(memm::PHYS_LADDR_OFFSET)) };
+        panic!("PAGE FAULT! {:?}: {}\n{:#?}\n{}", reason, fault, frame, walk);
+    }
+}
+
+fn double_fault(frame: &TrapFrame) -> ! {
+    // a page fault in a stack guard can't be handled on the overflowed stack
+    let cr2 = registers::cr2_read();
+    if let Some(cpu) = memm::stack::guard_owner(cr2) {
+        panic!("stack overflow on CPU {}\nCR2: {:p}\n{:#?}", cpu, cr2, frame);
+    }
+
+    fatal(frame)
+}
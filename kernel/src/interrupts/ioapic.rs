@@ -0,0 +1,218 @@
+//! Module for routing global system interrupts (GSIs) through the I/O APICs.
+//! 
+//! The I/O APICs and the interrupt source overrides of the ISA IRQs are discovered
+//! from the MADT. Each I/O APIC handles a contiguous range of GSIs from its base.
+
+use acpi::platform::interrupt::{self as acpi_int, InterruptModel};
+use amd64::{
+    apic::{IoApic, RedirectionEntry, DeliveryMode},
+    interrupts,
+    paging::{self, PTE, PatType},
+};
+use spin::Mutex;
+
+use crate::memm::{self, MAPPER};
+
+
+/// The maximum number of I/O APICs supported.
+pub const MAX_IOAPICS: usize = 8;
+/// The number of legacy ISA IRQs.
+pub const ISA_IRQ_COUNT: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Polarity {
+    ActiveHigh,
+    ActiveLow,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TriggerMode {
+    Edge,
+    Level,
+}
+
+/// An interrupt line, being a GSI and how it's signalled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IrqLine {
+    pub gsi: u32,
+    pub polarity: Polarity,
+    pub trigger: TriggerMode,
+}
+
+impl IrqLine {
+    /// Returns the line of ISA IRQ `irq` where it isn't overridden,
+    /// being the GSI of the same number, active high and edge-triggered.
+    pub const fn isa_default(irq: u8) -> Self {
+        Self { gsi: irq as u32, polarity: Polarity::ActiveHigh, trigger: TriggerMode::Edge }
+    }
+}
+
+struct Controller {
+    ioapic: IoApic,
+    gsi_base: u32,
+    gsi_count: u32,
+}
+
+struct IoApicTable {
+    controllers: [Option<Controller>; MAX_IOAPICS],
+    isa_lines: [IrqLine; ISA_IRQ_COUNT],
+}
+
+static IOAPICS: Mutex<IoApicTable> = Mutex::new(IoApicTable {
+    controllers: [None, None, None, None, None, None, None, None],
+    isa_lines: {
+        let mut isa_lines = [IrqLine::isa_default(0); ISA_IRQ_COUNT];
+        let mut irq = 0;
+        while irq < ISA_IRQ_COUNT {
+            isa_lines[irq] = IrqLine::isa_default(irq as u8);
+            irq += 1;
+        }
+        isa_lines
+    },
+});
+
+impl IoApicTable {
+    /// Returns the controller handling `gsi` and the index of its input.
+    fn controller_mut(&mut self, gsi: u32) -> Option<(&mut IoApic, u32)> {
+        self.controllers.iter_mut()
+            .flatten()
+            .find(|c| c.gsi_base <= gsi && gsi - c.gsi_base < c.gsi_count)
+            .map(|c| (&mut c.ioapic, gsi - c.gsi_base))
+    }
+}
+
+
+/// Discovers the I/O APICs and ISA interrupt source overrides from the MADT,
+/// mapping each I/O APIC's registers uncacheable and masking all its inputs.
+/// I/O APICs beyond `MAX_IOAPICS` are reported and left unused.
+/// 
+/// Returns `Err(())` if the platform information wasn't parsed from the
+/// ACPI tables, or the platform doesn't use the APIC interrupt model.
+/// ### Safety:
+/// Must be called once, after `sys::acpi::init`.
+pub unsafe fn init() -> Result<(), ()> {
+    let apic = match crate::acpi::platform().map(|platform| &platform.interrupt_model) {
+        Some(InterruptModel::Apic(apic)) => apic,
+        _ => return Err(()),
+    };
+    // the GSIs of those beyond can't be routed
+    if apic.io_apics.len() > MAX_IOAPICS {
+        crate::println!("Too many I/O APICs, only {} are used.", MAX_IOAPICS);
+    }
+
+    interrupts::without_interrupts(|| {
+        let mut table = IOAPICS.lock();
+
+        let leaves = PTE::RW | memm::pat_type_to_pte(PatType::Uncacheable, false);
+        for (slot, entry) in table.controllers.iter_mut().zip(apic.io_apics.iter()) {
+            let base = MAPPER.lock().map_mmio(entry.address as usize, paging::PTE_SIZE, leaves);
+            let mut ioapic = IoApic::new(base);
+
+            let gsi_count = ioapic.redirection_count();
+            for index in 0..gsi_count {
+                ioapic.set_redirection(index, RedirectionEntry::MASKED);
+            }
+
+            *slot = Some(Controller { ioapic, gsi_base: entry.global_system_interrupt_base, gsi_count });
+        }
+
+        // overrides specify deviations from the ISA bus' signalling, else conform to it
+        for iso in apic.interrupt_source_overrides.iter() {
+            if let Some(line) = table.isa_lines.get_mut(iso.isa_source as usize) {
+                line.gsi = iso.global_system_interrupt;
+                line.polarity = match iso.polarity {
+                    acpi_int::Polarity::ActiveLow => Polarity::ActiveLow,
+                    _ => Polarity::ActiveHigh,
+                };
+                line.trigger = match iso.trigger_mode {
+                    acpi_int::TriggerMode::Level => TriggerMode::Level,
+                    _ => TriggerMode::Edge,
+                };
+            }
+        }
+    });
+
+    Ok(())
+}
+
+/// Returns the line of ISA IRQ `irq`, as per the MADT's interrupt source overrides.
+/// ### Panics:
+/// Panics if `irq` isn't below `ISA_IRQ_COUNT`.
+pub fn isa_line(irq: u8) -> IrqLine {
+    interrupts::without_interrupts(|| IOAPICS.lock().isa_lines[irq as usize])
+}
+
+/// Routes `line` to `vector` of the local APIC of ID `apic_id`, leaving it masked.
+/// 
+/// Returns `Err(())` if no I/O APIC handles the GSI, or the APIC ID exceeds 8 bits.
+/// ### Safety:
+/// Caller must ensure the interrupts delivered are handled once unmasked.
+pub unsafe fn route(line: IrqLine, vector: u8, apic_id: u32) -> Result<(), ()> {
+    let apic_id = u8::try_from(apic_id).map_err(|_| ())?;
+
+    let mut entry = RedirectionEntry::new(vector, DeliveryMode::Fixed, apic_id) | RedirectionEntry::MASKED;
+    if line.polarity == Polarity::ActiveLow {
+        entry |= RedirectionEntry::ACTIVE_LOW;
+    }
+    if line.trigger == TriggerMode::Level {
+        entry |= RedirectionEntry::LEVEL_TRIGGERED;
+    }
+
+    interrupts::without_interrupts(|| {
+        let mut table = IOAPICS.lock();
+        let (ioapic, index) = table.controller_mut(line.gsi).ok_or(())?;
+        ioapic.set_redirection(index, entry);
+        Ok(())
+    })
+}
+
+/// Routes ISA IRQ `irq` to `vector` of the local APIC of ID `apic_id`, leaving it masked.
+/// 
+/// Returns `Err(())` as per `route`, or if `irq` isn't below `ISA_IRQ_COUNT`.
+/// ### Safety:
+/// Caller must ensure the interrupts delivered are handled once unmasked.
+pub unsafe fn route_isa(irq: u8, vector: u8, apic_id: u32) -> Result<(), ()> {
+    if irq as usize >= ISA_IRQ_COUNT {
+        return Err(());
+    }
+    route(isa_line(irq), vector, apic_id)
+}
+
+/// Masks `gsi`, such that its interrupts aren't delivered.
+/// 
+/// Returns `Err(())` if no I/O APIC handles the GSI.
+pub fn mask(gsi: u32) -> Result<(), ()> {
+    // SAFETY: masking doesn't deliver interrupts
+    unsafe { update(gsi, |entry| entry | RedirectionEntry::MASKED) }
+}
+
+/// Unmasks `gsi`, delivering its interrupts as routed.
+/// 
+/// Returns `Err(())` if no I/O APIC handles the GSI.
+/// ### Safety:
+/// Caller must ensure the interrupts delivered are handled, see `route`.
+pub unsafe fn unmask(gsi: u32) -> Result<(), ()> {
+    update(gsi, |entry| entry - RedirectionEntry::MASKED)
+}
+
+/// Delivers the interrupts of `gsi` to the local APIC of ID `apic_id`,
+/// retaining its vector, signalling and mask.
+/// 
+/// Returns `Err(())` if no I/O APIC handles the GSI, or the APIC ID exceeds 8 bits.
+/// ### Safety:
+/// Caller must ensure the interrupts delivered are handled on the destination.
+pub unsafe fn retarget(gsi: u32, apic_id: u32) -> Result<(), ()> {
+    let apic_id = u8::try_from(apic_id).map_err(|_| ())?;
+    update(gsi, |entry| entry.with_destination(apic_id))
+}
+
+/// Modifies the redirection entry of `gsi` with `f`.
+unsafe fn update(gsi: u32, f: impl FnOnce(RedirectionEntry) -> RedirectionEntry) -> Result<(), ()> {
+    interrupts::without_interrupts(|| {
+        let mut table = IOAPICS.lock();
+        let (ioapic, index) = table.controller_mut(gsi).ok_or(())?;
+        let entry = ioapic.get_redirection(index);
+        ioapic.set_redirection(index, f(entry));
+        Ok(())
+    })
+}
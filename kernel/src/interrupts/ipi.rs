@@ -0,0 +1,65 @@
+//! Module for sending interprocessor interrupts (IPIs) through the local APIC.
+//! 
+//! CPUs are addressed by their index, see `sys::smp`.
+
+use amd64::{apic::{DeliveryMode, IpiDest}, interrupts};
+
+use super::lapic;
+use crate::smp;
+
+
+/// Sends an IPI of `vector` to `cpu`.
+/// 
+/// Returns `Err(())` if there's no such CPU, or no local APIC.
+/// ### Safety:
+/// `vector` must be handled by `cpu`.
+pub unsafe fn send(cpu: usize, vector: u8) -> Result<(), ()> {
+    let apic_id = smp::apic_id(cpu).ok_or(())?;
+    send_raw(IpiDest::Apic(apic_id), DeliveryMode::Fixed, vector)
+}
+
+/// Sends an IPI of `vector` to every CPU but the executing CPU.
+/// 
+/// Returns `Err(())` if there's no local APIC.
+/// ### Safety:
+/// `vector` must be handled by every CPU.
+pub unsafe fn broadcast(vector: u8) -> Result<(), ()> {
+    send_raw(IpiDest::AllButCurrent, DeliveryMode::Fixed, vector)
+}
+
+/// Sends an IPI of `vector` to the executing CPU, which is
+/// delivered once interrupts are enabled.
+/// 
+/// Returns `Err(())` if there's no local APIC.
+/// ### Safety:
+/// `vector` must be handled by the executing CPU.
+pub unsafe fn send_self(vector: u8) -> Result<(), ()> {
+    send_raw(IpiDest::Current, DeliveryMode::Fixed, vector)
+}
+
+/// Sends a non-maskable interrupt to `cpu`.
+/// 
+/// Returns `Err(())` if there's no such CPU, or no local APIC.
+/// ### Safety:
+/// The NMI handler of `cpu` must expect the NMI.
+pub unsafe fn send_nmi(cpu: usize) -> Result<(), ()> {
+    let apic_id = smp::apic_id(cpu).ok_or(())?;
+    send_raw(IpiDest::Apic(apic_id), DeliveryMode::Nmi, 0)
+}
+
+/// Sends an IPI to `dest` once the executing CPU's previous IPI has been sent.
+unsafe fn send_raw(dest: IpiDest, mode: DeliveryMode, vector: u8) -> Result<(), ()> {
+    if !lapic::is_supported() {
+        return Err(());
+    }
+
+    let lapic = lapic::local_apic();
+    interrupts::without_interrupts(|| {
+        // the command register must not be written while an IPI is pending
+        while lapic.is_icr_pending() {
+            core::hint::spin_loop();
+        }
+        lapic.send_ipi(dest, mode, vector);
+    });
+    Ok(())
+}
@@ -0,0 +1,167 @@
+//! Module for the registration and dispatch of interrupt request handlers.
+//! 
+//! Vectors from `IRQ_VECTOR_BASE` are claimed by drivers through `register_irq`.
+//! Multiple handlers may be chained upon a vector to share an interrupt line,
+//! each being called in turn until one has handled the interrupt.
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use amd64::interrupts::{self, IDT};
+use spin::RwLock;
+
+use super::TrapFrame;
+
+
+/// The first vector available to interrupt requests.
+pub const IRQ_VECTOR_BASE: u8 = IDT::EXCEPTION_COUNT as u8;
+/// The first vector allocated when any vector is requested. Vectors below
+/// are left to be claimed explicitly, e.g. by the remapped legacy PIC.
+pub const DYNAMIC_VECTOR_BASE: u8 = 0x30;
+/// The vector of spurious interrupts, which are counted but neither handled nor acknowledged.
+pub const SPURIOUS_VECTOR: u8 = 0xff;
+/// The maximum number of handlers chained upon a vector.
+pub const IRQ_CHAIN_CAPACITY: usize = 4;
+
+const IRQ_COUNT: usize = 256 - IRQ_VECTOR_BASE as usize;
+
+/// Whether a handler has serviced the interrupt.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IrqStatus {
+    Handled,
+    /// The interrupt wasn't raised by the handler's device, e.g. on a shared line.
+    Unhandled,
+}
+
+/// An interrupt request handler, called with the interrupted context and the
+/// `ctx` pointer it was registered with.
+pub type IrqHandler = fn(frame: &mut TrapFrame, ctx: *mut ()) -> IrqStatus;
+
+#[derive(Debug, Clone, Copy)]
+struct IrqAction {
+    handler: IrqHandler,
+    ctx: *mut (),
+}
+
+/// The handlers chained upon each vector, indexed from `IRQ_VECTOR_BASE`.
+/// A vector is allocated while any handler is chained upon it.
+struct IrqTable {
+    chains: [[Option<IrqAction>; IRQ_CHAIN_CAPACITY]; IRQ_COUNT],
+}
+
+unsafe impl Send for IrqTable {}
+unsafe impl Sync for IrqTable {}
+
+static IRQS: RwLock<IrqTable> = RwLock::new(IrqTable { chains: [[None; IRQ_CHAIN_CAPACITY]; IRQ_COUNT] });
+/// Signals the end of an interrupt to the interrupt controller that delivered it.
+static EOI: RwLock<Option<fn(u8)>> = RwLock::new(None);
+
+const ZERO: AtomicU64 = AtomicU64::new(0);
+/// The number of interrupts of each vector that no handler serviced.
+static UNHANDLED: [AtomicU64; IRQ_COUNT] = [ZERO; IRQ_COUNT];
+static SPURIOUS: AtomicU64 = AtomicU64::new(0);
+
+impl IrqTable {
+    fn chain_mut(&mut self, vector: u8) -> &mut [Option<IrqAction>; IRQ_CHAIN_CAPACITY] {
+        &mut self.chains[(vector - IRQ_VECTOR_BASE) as usize]
+    }
+
+    fn is_allocated(&self, vector: u8) -> bool {
+        self.chains[(vector - IRQ_VECTOR_BASE) as usize].iter().any(Option::is_some)
+    }
+}
+
+
+/// Chains `handler` upon `vector`, or upon an unallocated vector if `None`.
+/// 
+/// Returns the vector, or `Err(())` if no vector is available, the vector
+/// is reserved, or its chain is full.
+/// 
+/// Must not be called from within an interrupt handler.
+pub fn register_irq(vector: Option<u8>, handler: IrqHandler, ctx: *mut ()) -> Result<u8, ()> {
+    interrupts::without_interrupts(|| {
+        let mut irqs = IRQS.write();
+
+        let vector = match vector {
+            Some(vector) if vector < IRQ_VECTOR_BASE || vector == SPURIOUS_VECTOR => return Err(()),
+            Some(vector) => vector,
+            None => (DYNAMIC_VECTOR_BASE..SPURIOUS_VECTOR)
+                .find(|&vector| !irqs.is_allocated(vector))
+                .ok_or(())?,
+        };
+
+        let slot = irqs.chain_mut(vector).iter_mut().find(|action| action.is_none()).ok_or(())?;
+        *slot = Some(IrqAction { handler, ctx });
+        Ok(vector)
+    })
+}
+
+/// Removes `handler` registered with `ctx` from the chain of `vector`,
+/// freeing the vector once no handlers remain.
+/// 
+/// Returns `Err(())` if no such handler is registered. Once this returns,
+/// the handler is neither running nor will be called.
+/// 
+/// Must not be called from within an interrupt handler.
+pub fn unregister_irq(vector: u8, handler: IrqHandler, ctx: *mut ()) -> Result<(), ()> {
+    if vector < IRQ_VECTOR_BASE {
+        return Err(());
+    }
+
+    interrupts::without_interrupts(|| {
+        let mut irqs = IRQS.write();
+
+        let chain = irqs.chain_mut(vector);
+        let i = chain.iter()
+            .position(|action| action.map_or(false, |action|
+                action.handler as usize == handler as usize && action.ctx == ctx))
+            .ok_or(())?;
+
+        // keep the chain contiguous, preserving the order of the remaining handlers
+        chain.copy_within(i + 1.., i);
+        chain[IRQ_CHAIN_CAPACITY - 1] = None;
+        Ok(())
+    })
+}
+
+/// Sets the routine that signals the end of each interrupt to the interrupt controller.
+pub fn set_eoi_handler(eoi: fn(u8)) {
+    interrupts::without_interrupts(|| *EOI.write() = Some(eoi));
+}
+
+/// Returns the number of interrupts upon `vector` that no handler serviced.
+pub fn unhandled_count(vector: u8) -> u64 {
+    match vector {
+        vector if vector < IRQ_VECTOR_BASE => 0,
+        vector => UNHANDLED[(vector - IRQ_VECTOR_BASE) as usize].load(Ordering::Relaxed),
+    }
+}
+/// Returns the number of interrupts upon `SPURIOUS_VECTOR`.
+pub fn spurious_count() -> u64 {
+    SPURIOUS.load(Ordering::Relaxed)
+}
+
+
+/// Calls the handlers chained upon the vector of the frame until one services
+/// the interrupt, then signals the end of the interrupt.
+pub fn dispatch(frame: &mut TrapFrame) {
+    let vector = frame.vector as u8;
+    if vector == SPURIOUS_VECTOR {
+        SPURIOUS.fetch_add(1, Ordering::Relaxed);
+        return;
+    }
+
+    // handlers are called under the read lock, such that unregistering waits on them
+    let irqs = IRQS.read();
+    let is_handled = irqs.chains[(vector - IRQ_VECTOR_BASE) as usize].iter()
+        .map_while(|action| *action)
+        .any(|action| (action.handler)(frame, action.ctx) == IrqStatus::Handled);
+    drop(irqs);
+
+    if !is_handled {
+        UNHANDLED[(vector - IRQ_VECTOR_BASE) as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    if let Some(eoi) = *EOI.read() {
+        eoi(vector);
+    }
+}
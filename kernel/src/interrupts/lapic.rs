@@ -0,0 +1,102 @@
+//! Module for the local APIC of each CPU.
+
+use core::ptr;
+
+use amd64::{
+    apic::{ApicBase, LocalApic, Lvt, LvtEntry, DeliveryMode},
+    paging::{self, PTE, PatType},
+};
+use spin::{Lazy, Once};
+
+use crate::memm::{self, MAPPER};
+use super::{TrapFrame, irq::{self, IrqStatus, SPURIOUS_VECTOR}};
+
+
+/// The vector of local APIC error interrupts.
+pub const ERROR_VECTOR: u8 = 0xfe;
+
+static LAPIC: Once<LocalApic> = Once::new();
+
+/// Whether a local APIC exists, else the legacy PIC pair is the interrupt controller.
+static APIC_SUPPORT: Lazy<bool> = Lazy::new(|| {
+    raw_cpuid::CpuId::new().get_feature_info().map_or(false, |info| info.has_apic())
+});
+/// Whether the x2APIC mode is supported, in which case it's used by every CPU.
+static X2APIC_SUPPORT: Lazy<bool> = Lazy::new(|| {
+    raw_cpuid::CpuId::new().get_feature_info().map_or(false, |info| info.has_x2apic())
+});
+
+/// Returns whether a local APIC exists.
+pub fn is_supported() -> bool {
+    *APIC_SUPPORT
+}
+
+/// Returns the local APIC of the executing CPU.
+/// ### Panics:
+/// Panics if `init` hasn't been called.
+pub fn local_apic() -> &'static LocalApic {
+    LAPIC.get().expect("Local APIC is not initialized.")
+}
+
+/// Enables and configures the local APIC of the executing CPU:
+/// * The x2APIC mode is used if supported, else the registers' page 
+/// is mapped uncacheable, once for all CPUs.
+/// * Spurious interrupts are delivered to `irq::SPURIOUS_VECTOR`.
+/// * Interrupts of all priorities are accepted.
+/// * Local interrupt sources are masked, except LINT1, delivered as an NMI,
+/// and errors, delivered to `ERROR_VECTOR`.
+/// * The end of each interrupt is signalled to it by `irq::dispatch`.
+/// ### Safety:
+/// Must be called once per CPU, after `Mapper::setup` and loading the IDT.
+pub unsafe fn init() {
+    let base = ApicBase::read();
+    if !base.contains(ApicBase::AE) {
+        (base | ApicBase::AE).write();
+    }
+    // x2APIC mode can only be entered from xAPIC mode
+    if *X2APIC_SUPPORT && !base.contains(ApicBase::EXTD) {
+        (base | ApicBase::AE | ApicBase::EXTD).write();
+    }
+
+    let lapic = LAPIC.call_once(|| {
+        let lapic = if *X2APIC_SUPPORT {
+            LocalApic::new_x2apic()
+        } else {
+            let leaves = PTE::RW | memm::pat_type_to_pte(PatType::Uncacheable, false);
+            LocalApic::new(MAPPER.lock().map_mmio(base.get_paddr(), paging::PTE_SIZE, leaves))
+        };
+
+        irq::register_irq(Some(ERROR_VECTOR), error_interrupt, ptr::null_mut())
+            .expect("APIC error vector registration failed.");
+        irq::set_eoi_handler(eoi);
+
+        lapic
+    });
+
+    lapic.enable(SPURIOUS_VECTOR);
+    lapic.set_tpr(0);
+
+    // the performance counter and thermal entries are optional, as per the version register
+    let lvt_count = lapic.lvt_count();
+    for lvt in Lvt::ALL {
+        match lvt {
+            Lvt::PerfCounter if lvt_count < 5 => (),
+            Lvt::Thermal if lvt_count < 6 => (),
+            _ => lapic.set_lvt(lvt, LvtEntry::MASKED),
+        }
+    }
+    lapic.set_lvt(Lvt::Lint1, LvtEntry::new(0, DeliveryMode::Nmi));
+
+    // discard errors latched before the error entry is unmasked
+    lapic.error_status();
+    lapic.set_lvt(Lvt::Error, LvtEntry::new(ERROR_VECTOR, DeliveryMode::Fixed));
+}
+
+fn eoi(_: u8) {
+    local_apic().eoi();
+}
+
+fn error_interrupt(_: &mut TrapFrame, _: *mut ()) -> IrqStatus {
+    crate::try_println!("APIC ERROR! {:?}", local_apic().error_status());
+    IrqStatus::Handled
+}
@@ -0,0 +1,190 @@
+//! Module for interrupt entry and dispatch.
+//! 
+//! Every vector enters through a stub that pushes a uniform `TrapFrame` onto
+//! the stack, which is passed to `trap_dispatch` before being restored by `iretq`.
+//! Exceptions are handled by `exceptions`, all other vectors by `irq`.
+
+pub mod exceptions;
+pub mod ioapic;
+pub mod ipi;
+pub mod irq;
+pub mod lapic;
+pub mod pic;
+
+use core::fmt;
+
+use amd64::interrupts::{IDT, InterruptStackFrame};
+
+
+/// The size of each entry stub. Stubs are laid out contiguously by vector.
+pub const STUB_SIZE: usize = 16;
+
+/// The state of the interrupted context, as pushed by the processor and the entry stub.
+/// 
+/// Modifying the frame modifies the context that is returned to.
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct TrapFrame {
+    pub rax: u64,
+    pub rbx: u64,
+    pub rcx: u64,
+    pub rdx: u64,
+    pub rsi: u64,
+    pub rdi: u64,
+    pub rbp: u64,
+    pub r8: u64,
+    pub r9: u64,
+    pub r10: u64,
+    pub r11: u64,
+    pub r12: u64,
+    pub r13: u64,
+    pub r14: u64,
+    pub r15: u64,
+    /// The vector of the interrupt.
+    pub vector: u64,
+    /// The error code pushed by the processor, or zero for vectors without one.
+    pub err_code: u64,
+    pub stack_frame: InterruptStackFrame,
+}
+
+impl fmt::Debug for TrapFrame {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TrapFrame")
+            .field("vector", &self.vector)
+            .field("err_code", &format_args!("{:#x}", self.err_code))
+            .field("rax", &format_args!("{:#018x}", self.rax))
+            .field("rbx", &format_args!("{:#018x}", self.rbx))
+            .field("rcx", &format_args!("{:#018x}", self.rcx))
+            .field("rdx", &format_args!("{:#018x}", self.rdx))
+            .field("rsi", &format_args!("{:#018x}", self.rsi))
+            .field("rdi", &format_args!("{:#018x}", self.rdi))
+            .field("rbp", &format_args!("{:#018x}", self.rbp))
+            .field("r8", &format_args!("{:#018x}", self.r8))
+            .field("r9", &format_args!("{:#018x}", self.r9))
+            .field("r10", &format_args!("{:#018x}", self.r10))
+            .field("r11", &format_args!("{:#018x}", self.r11))
+            .field("r12", &format_args!("{:#018x}", self.r12))
+            .field("r13", &format_args!("{:#018x}", self.r13))
+            .field("r14", &format_args!("{:#018x}", self.r14))
+            .field("r15", &format_args!("{:#018x}", self.r15))
+            .field("stack_frame", &self.stack_frame)
+            .finish()
+    }
+}
+
+
+extern "C" {
+    /// The base of the entry stubs, see `stub_laddr`.
+    fn entry_stubs();
+}
+
+/// Returns the address of the entry stub of `vector`.
+pub fn stub_laddr(vector: u8) -> u64 {
+    entry_stubs as u64 + vector as u64 * STUB_SIZE as u64
+}
+
+/// Generates the entry stubs of the given exception vectors, in order, followed by
+/// those of the remaining vectors through 255, each padded to `STUB_SIZE`.
+/// 
+/// Vectors for which the processor doesn't push an error code push a zero in its place,
+/// such that every stub leaves a `TrapFrame` layout for `trap_entry`.
+macro_rules! entry_stubs {
+    ($($vector:literal $err_code:ident),* $(,)?) => {
+        core::arch::global_asm!(
+            ".balign 16",
+            "entry_stubs:",
+            $(
+                ".balign 16",
+                entry_stubs!(@$err_code),
+                concat!("push ", stringify!($vector)),
+                "jmp trap_entry",
+            )*
+            // interrupts never push an error code
+            ".set stub_vector, 32",
+            ".rept 224",
+            ".balign 16",
+            "push 0",
+            "push stub_vector",
+            "jmp trap_entry",
+            ".set stub_vector, stub_vector + 1",
+            ".endr",
+        );
+    };
+    (@pushed) => { "" };
+    (@none) => { "push 0" };
+}
+
+entry_stubs!(
+    0 none, 1 none, 2 none, 3 none, 4 none, 5 none, 6 none, 7 none,
+    8 pushed, 9 none, 10 pushed, 11 pushed, 12 pushed, 13 pushed, 14 pushed, 15 none,
+    16 none, 17 pushed, 18 none, 19 none, 20 none, 21 pushed, 22 none, 23 none,
+    24 none, 25 none, 26 none, 27 none, 28 none, 29 pushed, 30 pushed, 31 none,
+);
+
+// The processor aligns the stack to 16 bytes before pushing the interrupt stack frame,
+// which together with the vector, error code and registers keeps it aligned for the call.
+// Interrupts of user mode, per the RPL of the interrupted CS, swap in the kernel's GS base.
+core::arch::global_asm!("
+trap_entry:
+    test byte ptr [rsp + 24], 3
+    jz 1f
+    swapgs
+1:
+    push r15
+    push r14
+    push r13
+    push r12
+    push r11
+    push r10
+    push r9
+    push r8
+    push rbp
+    push rdi
+    push rsi
+    push rdx
+    push rcx
+    push rbx
+    push rax
+
+    cld
+    mov rdi, rsp
+    call trap_dispatch
+
+    pop rax
+    pop rbx
+    pop rcx
+    pop rdx
+    pop rsi
+    pop rdi
+    pop rbp
+    pop r8
+    pop r9
+    pop r10
+    pop r11
+    pop r12
+    pop r13
+    pop r14
+    pop r15
+
+    // discard the vector and error code
+    add rsp, 16
+
+    test byte ptr [rsp + 8], 3
+    jz 1f
+    swapgs
+1:
+    iretq"
+);
+
+/// Routes the interrupt of the frame to its handler.
+/// 
+/// Returning resumes the interrupted context as per the frame.
+#[no_mangle]
+extern "sysv64" fn trap_dispatch(frame: &mut TrapFrame) {
+    crate::percpu::irq_enter();
+    match frame.vector as usize {
+        vector if vector < IDT::EXCEPTION_COUNT => exceptions::dispatch(frame),
+        _ => irq::dispatch(frame),
+    }
+    crate::percpu::irq_exit();
+}
@@ -0,0 +1,66 @@
+//! Module for the legacy 8259 PIC pair.
+//! 
+//! The pair is remapped to the vectors from `PIC_VECTOR_BASE`, clear of the exception
+//! vectors, and either masked in favour of the APICs, or used as a fallback interrupt
+//! controller where no local APIC exists.
+
+use amd64::{interrupts, pic::{ChainedPics, PIC_IRQ_COUNT}};
+use spin::Mutex;
+
+use super::irq::{self, IRQ_VECTOR_BASE};
+
+
+/// The vector of IRQ 0, followed by those of IRQs 1-15.
+pub const PIC_VECTOR_BASE: u8 = IRQ_VECTOR_BASE;
+
+// SAFETY: this is the only ChainedPics, and the base is a multiple of 8
+static PICS: Mutex<ChainedPics> = Mutex::new(unsafe { ChainedPics::new(PIC_VECTOR_BASE) });
+
+
+/// Remaps the pair and masks all IRQs, such that only spurious interrupts,
+/// which can't be masked, are delivered, to the vectors of IRQs 7 and 15.
+/// ### Safety:
+/// Must be called by the BSP, before interrupts are enabled.
+pub unsafe fn disable() {
+    interrupts::without_interrupts(|| PICS.lock().remap());
+}
+
+/// Remaps the pair, masking all IRQs, and signals the end of each interrupt to it.
+/// IRQs are then unmasked by `unmask` once their vectors are handled.
+/// ### Safety:
+/// Must be called by the BSP, before interrupts are enabled,
+/// and only if no local APIC is in use.
+pub unsafe fn init_fallback() {
+    interrupts::without_interrupts(|| PICS.lock().remap());
+    irq::set_eoi_handler(eoi);
+}
+
+/// Returns the vector `irq` is delivered to.
+pub const fn vector(irq: u8) -> u8 {
+    PIC_VECTOR_BASE + irq
+}
+
+/// Masks `irq`.
+/// ### Panics:
+/// Panics if `irq` isn't below `PIC_IRQ_COUNT`.
+pub fn mask(irq: u8) {
+    assert!(irq < PIC_IRQ_COUNT);
+    interrupts::without_interrupts(|| PICS.lock().mask(irq));
+}
+
+/// Unmasks `irq`, delivering it to its vector, see `vector`.
+/// ### Panics:
+/// Panics if `irq` isn't below `PIC_IRQ_COUNT`.
+/// ### Safety:
+/// Caller must ensure the interrupts delivered are handled.
+pub unsafe fn unmask(irq: u8) {
+    assert!(irq < PIC_IRQ_COUNT);
+    interrupts::without_interrupts(|| PICS.lock().unmask(irq));
+}
+
+fn eoi(vector: u8) {
+    let mut pics = PICS.lock();
+    if let Some(irq) = pics.irq(vector) {
+        pics.eoi(irq);
+    }
+}
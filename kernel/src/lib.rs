@@ -18,8 +18,15 @@
 
 extern crate alloc;
 
+pub mod acpi;
 pub mod cfg;
 pub mod memm;
+pub mod interrupts;
 pub mod out;
+pub mod percpu;
+pub mod power;
+pub mod smp;
+pub mod time;
 pub mod utils;
+pub mod xcall;
 
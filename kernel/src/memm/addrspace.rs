@@ -0,0 +1,284 @@
+//! Module for per-process virtual address spaces.
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use amd64::{
+    paging::{self, PTE, InvpcidType},
+    registers::CR3,
+};
+use spin::{Lazy, Mutex};
+
+use super::{MAPPER, Mapper, Mapping, KRNL_HALF_IDX, frames::FrameOwner};
+
+
+/// The number of PCIDs, where PCID 0 is reserved for the kernel's PML4.
+const PCID_COUNT: usize = 0o10000;
+/// Shift of the PCID generation in a tagged PCID.
+const PCID_GEN_SHIFT: u32 = PCID_COUNT.trailing_zeros();
+
+/// Whether PCIDs and the INVPCID instruction are supported, respectively.
+static PCID_SUPPORT: Lazy<(bool, bool)> = Lazy::new(|| {
+    let cpuid = raw_cpuid::CpuId::new();
+    (
+        cpuid.get_feature_info().map_or(false, |info| info.has_pcid()),
+        cpuid.get_extended_feature_info().map_or(false, |info| info.has_invpcid()),
+    )
+});
+
+/// The current PCID generation. PCIDs tagged with a previous generation are invalid.
+static PCID_GENERATION: AtomicUsize = AtomicUsize::new(1);
+/// The next PCID to be assigned in the current generation.
+static PCID_NEXT: Mutex<usize> = Mutex::new(1);
+
+/// CPU-local PCID state. Each CPU must switch address spaces using its own.
+#[derive(Debug)]
+pub struct PcidCache {
+    /// The PCID generation for which this CPU last invalidated its TLB.
+    generation: usize,
+}
+
+impl PcidCache {
+    pub const fn new() -> Self {
+        Self { generation: 0 }
+    }
+}
+
+/// Invalidates all assigned PCIDs once they're exhausted, such that every CPU
+/// flushes its TLB upon its next address space switch.
+/// 
+/// Kernel mappings are global, hence needn't be, see `memm::krnl_global`.
+fn retire_pcids(next: &mut usize) {
+    PCID_GENERATION.fetch_add(1, Ordering::AcqRel);
+    *next = 1;
+}
+
+/// Switches the CPU to the kernel's PML4, as set up by `Mapper::setup`.
+/// ### Safety:
+/// Caller must ensure that no references into the lower half of
+/// the previous address space are used while the kernel's is active.
+pub unsafe fn activate_krnl(cache: &mut PcidCache) {
+    let krnl_pml4 = MAPPER.lock().krnl_pml4;
+    switch_pcid(krnl_pml4, 0, PCID_GENERATION.load(Ordering::Acquire), false, cache);
+}
+
+/// Loads CR3, invalidating the TLB beforehand if this CPU hasn't
+/// done so since the PCIDs of previous generations were retired.
+unsafe fn switch_pcid(pml4_paddr: usize, pcid: usize, generation: usize,
+is_fresh: bool, cache: &mut PcidCache) {
+    let (has_pcid, has_invpcid) = *PCID_SUPPORT;
+    if !has_pcid {
+        return CR3::set_nflags(pml4_paddr);
+    }
+
+    if cache.generation != generation {
+        // entries tagged with recycled PCIDs may remain
+        if has_invpcid {
+            paging::invpcid(InvpcidType::AllContexts, 0, core::ptr::null_mut::<u8>());
+        } else {
+            paging::flush_global();
+        }
+        cache.generation = generation;
+    }
+
+    CR3::set_pcid(pml4_paddr, pcid, !is_fresh);
+}
+
+
+/// A virtual address space with its own lower half, sharing the kernel's higher half.
+/// 
+/// The lower half page tables and the physical memory they map are owned by
+/// the `AddressSpace` and are returned to the `MAPPER` when dropped.
+#[derive(Debug)]
+pub struct AddressSpace {
+    pml4_paddr: usize,
+    /// The PCID assigned to this address space, tagged with its generation.
+    /// Zero when no PCID has been assigned.
+    pcid: AtomicUsize,
+}
+
+unsafe impl Send for AddressSpace {}
+unsafe impl Sync for AddressSpace {}
+
+impl AddressSpace {
+    /// Creates an address space with an empty lower half, and the higher
+    /// half of the kernel's PML4.
+    pub fn new() -> Self {
+        let mut mapper = MAPPER.lock();
+
+        // SAFETY: PTE_SIZE is nonzero, the new table is offset-identity mapped
+        // and the kernel PML4 is valid once `Mapper::setup` has been called
+        unsafe {
+            let pml4_paddr = mapper.alloc_phys(paging::PTE_SIZE, FrameOwner::PageTable);
+            let pml4 = crate::from_phys_addr!(pml4_paddr, PTE);
+            let krnl_pml4 = crate::from_phys_addr!(mapper.krnl_pml4, PTE);
+
+            pml4.write_bytes(0, KRNL_HALF_IDX);
+            pml4.add(KRNL_HALF_IDX).copy_from_nonoverlapping(
+                krnl_pml4.add(KRNL_HALF_IDX),
+                512 - KRNL_HALF_IDX
+            );
+
+            Self { pml4_paddr, pcid: AtomicUsize::new(0) }
+        }
+    }
+
+    /// Returns the physical address of the PML4.
+    #[inline]
+    pub fn pml4_paddr(&self) -> usize {
+        self.pml4_paddr
+    }
+    /// Returns the offset-identity mapped PML4.
+    #[inline]
+    pub fn pml4(&self) -> *mut [PTE] {
+        core::ptr::slice_from_raw_parts_mut(crate::from_phys_addr!(self.pml4_paddr, PTE), 512)
+    }
+
+    /// Returns whether this is the address space the CPU is currently using.
+    pub fn is_active(&self) -> bool {
+        CR3::read().paddr == self.pml4_paddr
+    }
+    /// Switches the CPU to this address space.
+    /// 
+    /// Where supported, the address space is assigned a PCID, such that the
+    /// TLB entries of this and other address spaces are retained across switches.
+    /// ### Safety:
+    /// Caller must ensure that no references into the lower half of
+    /// the previous address space are used while this one is active.
+    pub unsafe fn activate(&self, cache: &mut PcidCache) {
+        let mut is_fresh = false;
+        loop {
+            let generation = PCID_GENERATION.load(Ordering::Acquire);
+            let tagged_pcid = self.pcid.load(Ordering::Acquire);
+
+            if tagged_pcid >> PCID_GEN_SHIFT == generation {
+                let pcid = tagged_pcid & PCID_COUNT - 1;
+                return switch_pcid(self.pml4_paddr, pcid, generation, is_fresh, cache);
+            }
+
+            // assign a new PCID, starting a new generation if exhausted
+            let mut next = PCID_NEXT.lock();
+            if *next == PCID_COUNT {
+                retire_pcids(&mut next);
+            }
+            let generation = PCID_GENERATION.load(Ordering::Acquire);
+            self.pcid.store(generation << PCID_GEN_SHIFT | *next, Ordering::Release);
+            *next += 1;
+            is_fresh = true;
+        }
+    }
+
+    /// Unassigns the PCID of this address space, as entries of it may be stale.
+    /// A new PCID is assigned and flushed upon the next activation.
+    fn retire_pcid(&self) {
+        self.pcid.store(0, Ordering::Release);
+    }
+
+    /// Asserts that the span is within the lower half.
+    fn assert_lower_half(base: *mut u8, size: usize) {
+        let acme = (base as isize).checked_add(size as isize);
+        assert!((base as isize) >= 0 && acme.map_or(false, |acme| acme <= paging::LOWER_HALF),
+            "address space span must be within the lower half");
+    }
+
+    /// Maps base through acme in the lower half to available physical memory,
+    /// owned by `FrameOwner::User`.
+    /// 
+    /// See `Mapper::map`.
+    /// ### Safety:
+    /// * Any existing mappings within the span of virtual addresses will be remapped.
+    /// * The specified PTEs must be valid and usable, and not contain an address.
+    pub unsafe fn map(&self, base: *mut u8, size: usize, branches: PTE, leaves: PTE) -> Mapping {
+        Self::assert_lower_half(base, size);
+        MAPPER.lock().map(base, size, branches, leaves, FrameOwner::User, self.pml4())
+    }
+    /// Unmaps base through acme in the lower half.
+    /// 
+    /// See `Mapper::unmap`.
+    /// ### Safety:
+    /// * The span must have been mapped by `AddressSpace::map`.
+    /// * Memory within the span must not be accessed after it is unmapped.
+    pub unsafe fn unmap(&self, base: *mut u8, size: usize) {
+        Self::assert_lower_half(base, size);
+        MAPPER.lock().unmap(base, size, self.pml4());
+        self.retire_pcid();
+    }
+    /// Sets the flags of the mapped leaves within base through acme in the lower half.
+    /// 
+    /// See `Mapper::protect`.
+    /// ### Safety:
+    /// * The new flags must not violate memory safety for existing uses of the memory.
+    /// * The specified PTE must be valid and usable, and not contain an address.
+    pub unsafe fn protect(&self, base: *mut u8, size: usize, leaves: PTE) {
+        Self::assert_lower_half(base, size);
+        MAPPER.lock().protect(base, size, leaves, self.pml4());
+        self.retire_pcid();
+    }
+
+    /// Creates an address space sharing the lower half of this one, where
+    /// writable memory is copied once written through either address space.
+    /// 
+    /// See `Mapper::share`.
+    /// ### Safety:
+    /// Memory of the lower half must not be written to through other
+    /// mappings while it's shared, e.g. by DMA.
+    pub unsafe fn fork(&self) -> Self {
+        let forked = Self::new();
+        MAPPER.lock().share(
+            core::ptr::null_mut(),
+            paging::LOWER_HALF as usize,
+            self.pml4(),
+            forked.pml4()
+        );
+        // leaves were made read-only, TLB entries of this PCID may be stale
+        self.retire_pcid();
+        forked
+    }
+}
+
+impl Drop for AddressSpace {
+    fn drop(&mut self) {
+        assert!(!self.is_active(), "cannot drop the active address space");
+
+        let mut mapper = MAPPER.lock();
+        // SAFETY: the lower half tables and frames are exclusively owned,
+        // and the address space is not active, thus won't be accessed
+        unsafe {
+            let pml4 = self.pml4();
+            for i in 0..KRNL_HALF_IDX {
+                let pml4e = pml4.get_unchecked_mut(i);
+                if (*pml4e).contains(PTE::P) {
+                    drop_table::<{ paging::PDPT_LVL }>((*pml4e).get_paddr(), &mut mapper);
+                }
+            }
+            mapper.free_phys(self.pml4_paddr, paging::PTE_SIZE);
+        }
+    }
+}
+
+/// Frees the page table at `table_paddr`, all its descendant
+/// tables, and the physical memory they map.
+/// ### Safety:
+/// The table hierarchy must be exclusively owned,
+/// and the memory must not be accessed hereafter.
+unsafe fn drop_table<const LVL: usize>(table_paddr: usize, mapper: &mut Mapper) {
+    use paging::{PDPT_LVL, PD_LVL, PT_LVL};
+
+    let table = crate::from_phys_addr!(table_paddr, PTE);
+    for i in 0..512 {
+        let pte = *table.add(i);
+        if !pte.contains(PTE::P) { continue; }
+
+        if LVL == PT_LVL || pte.contains(PTE::PS) {
+            let page_size = paging::page_size(LVL);
+            mapper.free_phys(pte.get_leaf_paddr(page_size), page_size);
+        } else {
+            match LVL {
+                PDPT_LVL => drop_table::<PD_LVL>(pte.get_paddr(), mapper),
+                PD_LVL => drop_table::<PT_LVL>(pte.get_paddr(), mapper),
+                // SAFETY: PML4 entries are not dropped through this function
+                _ => core::hint::unreachable_unchecked(),
+            }
+        }
+    }
+    mapper.free_phys(table_paddr, paging::PTE_SIZE);
+}
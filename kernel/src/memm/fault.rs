@@ -0,0 +1,225 @@
+//! Module for classifying and resolving page faults against the reserved regions.
+
+use core::fmt;
+
+use amd64::{
+    interrupts::PfErrCode,
+    paging::{self, PTE},
+    registers::CR3,
+};
+
+use super::{MAPPER, Mapper, PHYS_LADDR_OFFSET, PTE_COW, region::{Region, RegionKind, REGIONS}};
+
+
+/// The cause of a page fault.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FaultCause {
+    /// The page was not present.
+    NotPresent,
+    /// The access was not permitted by the present page.
+    Protection,
+    /// A page table entry along the walk had a reserved bit set.
+    ReservedBit,
+}
+
+/// The kind of access that caused a page fault.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FaultAccess {
+    Read,
+    Write,
+    Execute,
+}
+
+/// A page fault, classified from its error code.
+#[derive(Debug, Clone, Copy)]
+pub struct PageFault {
+    /// The faulting virtual address, as per CR2.
+    pub laddr: *mut u8,
+    pub cause: FaultCause,
+    pub access: FaultAccess,
+    /// Whether the access was made in user mode.
+    pub is_user: bool,
+    pub err_code: PfErrCode,
+}
+
+impl PageFault {
+    pub fn new(laddr: *mut u8, err_code: PfErrCode) -> Self {
+        let cause = if err_code.contains(PfErrCode::RSV) {
+            FaultCause::ReservedBit
+        } else if err_code.contains(PfErrCode::P) {
+            FaultCause::Protection
+        } else {
+            FaultCause::NotPresent
+        };
+        let access = if err_code.contains(PfErrCode::ID) {
+            FaultAccess::Execute
+        } else if err_code.contains(PfErrCode::RW) {
+            FaultAccess::Write
+        } else {
+            FaultAccess::Read
+        };
+
+        Self { laddr, cause, access, is_user: err_code.contains(PfErrCode::US), err_code }
+    }
+
+    /// Returns whether the access is permitted by a leaf with the given flags.
+    fn is_permitted_by(&self, leaves: PTE) -> bool {
+        (!self.is_user || leaves.contains(PTE::US))
+        && (self.access != FaultAccess::Write || leaves.contains(PTE::RW))
+        && (self.access != FaultAccess::Execute || !leaves.contains(PTE::NX))
+    }
+}
+
+impl fmt::Display for PageFault {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?} {:?} fault at {:p} in {} mode ({:?})",
+            self.access,
+            self.cause,
+            self.laddr,
+            if self.is_user { "user" } else { "supervisor" },
+            self.err_code,
+        )
+    }
+}
+
+/// The reason a page fault could not be resolved.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Unresolved {
+    /// No region contains the faulting address.
+    Unreserved,
+    /// The faulting address is reserved, but not committed.
+    Uncommitted,
+    /// The faulting address is within a stack's guard span, or below its growth window.
+    StackOverflow,
+    /// The access is not permitted by the region.
+    AccessViolation,
+    /// A page table entry had a reserved bit set.
+    ReservedBit,
+    /// Physical memory was exhausted while backing the page.
+    OutOfMemory,
+}
+
+
+/// Resolves the page fault in the active address space, if recoverable:
+/// * Not-present faults within the committed span of a region are backed with zeroed memory.
+/// * Not-present faults within a stack's growth window grow the stack.
+/// * Write faults on `PTE_COW` leaves are resolved by copying or reclaiming the shared frame.
+/// 
+/// Returns `Ok(())` if the faulting access may be retried.
+/// ### Safety:
+/// Must only be called by the page fault handler, for the fault being handled.
+pub unsafe fn resolve(fault: &PageFault) -> Result<(), Unresolved> {
+    if fault.cause == FaultCause::ReservedBit {
+        return Err(Unresolved::ReservedBit);
+    }
+
+    // only not-present faults are resolved against the regions
+    let region = if fault.cause == FaultCause::NotPresent {
+        let region = find_region(fault)?;
+        if !fault.is_permitted_by(region.leaves) {
+            return Err(Unresolved::AccessViolation);
+        }
+        Some(region)
+    } else {
+        None
+    };
+
+    let page = (fault.laddr as usize & !(paging::PTE_SIZE - 1)) as *mut u8;
+    let pml4 = CR3::read().get_laddr_offset(PHYS_LADDR_OFFSET);
+
+    // hold the lock while checking, another CPU may have resolved the fault already
+    let mut mapper = MAPPER.lock();
+    match super::translate(page, pml4) {
+        None => match region {
+            Some(region) => back_page(&mut mapper, fault, page, &region, pml4)?,
+            // unmapped by another CPU, retry as not-present
+            None => (),
+        },
+        Some((_, _, leaf)) if fault.is_permitted_by(leaf) => {
+            // resolved by another CPU, or the TLB entry is stale
+            paging::invlpg(page);
+        },
+        Some((_, _, leaf)) if fault.access == FaultAccess::Write 
+        && leaf.contains(PTE_COW) && fault.is_permitted_by(leaf | PTE::RW) => {
+            mapper.unshare(page, pml4).map_err(|()| Unresolved::OutOfMemory)?;
+        },
+        Some(_) => return Err(Unresolved::AccessViolation),
+    }
+    Ok(())
+}
+
+/// Returns the region committed at the faulting address, growing stacks as necessary.
+fn find_region(fault: &PageFault) -> Result<Region, Unresolved> {
+    let mut regions = REGIONS.lock();
+    match regions.find(fault.laddr) {
+        None => Err(Unresolved::Unreserved),
+        Some(region) if region.is_committed(fault.laddr) => Ok(*region),
+        Some(region) if matches!(region.kind, RegionKind::Stack { .. }) =>
+            regions.grow_stack(fault.laddr).ok_or(Unresolved::StackOverflow),
+        Some(_) => Err(Unresolved::Uncommitted),
+    }
+}
+
+/// Backs `page` with zeroed physical memory as per the flags of `region`.
+/// 
+/// Reads of writable lower half pages map the shared zero frame until written.
+unsafe fn back_page(mapper: &mut Mapper, fault: &PageFault, page: *mut u8,
+region: &Region, pml4: *mut [PTE]) -> Result<(), Unresolved> {
+    let result = if fault.access == FaultAccess::Read && (page as isize) >= 0 {
+        mapper.try_map_zeroed_page(page, region.branches, region.leaves, pml4)
+    } else {
+        mapper.try_map_page(page, region.branches, region.leaves, region.owner, pml4)
+    };
+    result.map_err(|()| Unresolved::OutOfMemory)
+}
+
+
+/// The entries along the page table walk of a virtual address.
+/// 
+/// Displays each present entry from the PML4 down to the leaf, for diagnostics.
+#[derive(Debug, Clone, Copy)]
+pub struct PageWalk {
+    laddr: *mut u8,
+    /// The entries by level, where `None` follows a non-present entry or leaf.
+    entries: [Option<PTE>; 4],
+}
+
+impl PageWalk {
+    /// ### Safety:
+    /// Physical addresses of the page tables must be offset-identity mapped.
+    pub unsafe fn new(laddr: *mut u8, pml4: *mut [PTE]) -> Self {
+        use paging::{PML4_LVL, PT_LVL};
+
+        let mut entries = [None; 4];
+        let mut table = pml4.as_mut_ptr();
+        for lvl in (PT_LVL..=PML4_LVL).rev() {
+            let pte = *table.add(paging::table_index(laddr, lvl));
+            entries[lvl - 1] = Some(pte);
+
+            if !pte.contains(PTE::P) || lvl != PML4_LVL && pte.contains(PTE::PS) {
+                break;
+            }
+            table = crate::from_phys_addr!(pte.get_paddr(), PTE);
+        }
+
+        Self { laddr, entries }
+    }
+}
+
+impl fmt::Display for PageWalk {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        const NAMES: [&str; 4] = ["PTE", "PDE", "PDPTE", "PML4E"];
+
+        write!(f, "Page walk of {:p}:", self.laddr)?;
+        for lvl in (paging::PT_LVL..=paging::PML4_LVL).rev() {
+            if let Some(pte) = self.entries[lvl - 1] {
+                write!(f, "\n  {:>5}[{:#05o}] = {:#018x}",
+                    NAMES[lvl - 1],
+                    paging::table_index(self.laddr, lvl),
+                    pte.bits(),
+                )?;
+            }
+        }
+        Ok(())
+    }
+}
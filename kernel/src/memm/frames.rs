@@ -0,0 +1,260 @@
+//! Module for tracking the ownership and sharing of physical frames.
+
+use core::fmt;
+
+use amd64::paging;
+use bitflags::bitflags;
+
+
+/// The kind of owner of a physical frame.
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameOwner {
+    /// Unavailable to the kernel, e.g. firmware reserved memory or MMIO.
+    Reserved = 0,
+    /// Available to be allocated.
+    Free,
+    /// Page tables.
+    PageTable,
+    /// Kernel heaps.
+    Heap,
+    /// Other kernel memory, e.g. stacks.
+    Kernel,
+    /// The lower half of address spaces.
+    User,
+    /// Buffers accessed by devices.
+    Dma,
+    /// Memory that is never freed, e.g. this table.
+    Pinned,
+}
+
+impl FrameOwner {
+    /// The number of owner kinds.
+    pub const COUNT: usize = 8;
+    /// All owner kinds, in order of discriminant.
+    pub const ALL: [FrameOwner; Self::COUNT] = [
+        Self::Reserved,
+        Self::Free,
+        Self::PageTable,
+        Self::Heap,
+        Self::Kernel,
+        Self::User,
+        Self::Dma,
+        Self::Pinned,
+    ];
+}
+
+bitflags! {
+    pub struct FrameFlags: u8 {
+        /// The frame is the shared zero frame, which is never counted nor freed.
+        const ZERO = 1 << 0;
+        /// The frame is the first of a huge page allocation.
+        const HUGE = 1 << 1;
+        /// The last mapping of the frame was dropped while pinned,
+        /// it is to be freed once unpinned.
+        const ORPHANED = 1 << 2;
+    }
+}
+
+/// Metadata of a physical frame.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct FrameMeta {
+    /// The number of mappings referencing the frame.
+    pub refs: u32,
+    /// The number of outstanding pins, which prevent the frame from being freed.
+    pub pins: u16,
+    pub owner: FrameOwner,
+    pub flags: FrameFlags,
+}
+
+
+/// Metadata of every physical frame, indexed by physical frame number.
+/// 
+/// The table is located in physical memory, and accessed through the offset mapping.
+#[derive(Debug)]
+pub struct FrameTable {
+    metas: *mut FrameMeta,
+    frame_count: usize,
+    zero_frame: usize,
+}
+
+unsafe impl Send for FrameTable {}
+
+impl FrameTable {
+    pub const fn new_invalid() -> Self {
+        Self { metas: core::ptr::null_mut(), frame_count: 0, zero_frame: 0 }
+    }
+
+    /// Creates a table of which every frame is `FrameOwner::Reserved`.
+    /// ### Safety:
+    /// `metas` must be valid and exclusively owned for `frame_count` entries.
+    pub unsafe fn new(metas: *mut FrameMeta, frame_count: usize) -> Self {
+        // SAFETY: all-zero is a valid, reserved FrameMeta
+        metas.write_bytes(0, frame_count);
+        Self { metas, frame_count, zero_frame: 0 }
+    }
+
+    /// Returns the number of bytes required by the table for `frame_count` frames.
+    pub const fn size_of(frame_count: usize) -> usize {
+        frame_count * core::mem::size_of::<FrameMeta>()
+    }
+
+    /// Returns the physical address of the zeroed frame shared by untouched zeroed mappings.
+    #[inline]
+    pub fn zero_frame(&self) -> usize {
+        self.zero_frame
+    }
+    /// Designates the frame at `paddr` as the zero frame.
+    /// ### Safety:
+    /// The frame must be zeroed, and never written nor freed.
+    pub unsafe fn set_zero_frame(&mut self, paddr: usize) {
+        self.set_owner(paddr, paging::PTE_SIZE, FrameOwner::Pinned);
+        if let Some(meta) = self.get_mut(paddr) {
+            meta.flags = FrameFlags::ZERO;
+        }
+        self.zero_frame = paddr;
+    }
+
+    /// Returns the metadata of the frame at `paddr`, if tracked.
+    pub fn get(&self, paddr: usize) -> Option<&FrameMeta> {
+        let frame = paddr / paging::PTE_SIZE;
+        // SAFETY: in bounds, metas are valid as guaranteed by `new`
+        (frame < self.frame_count).then(|| unsafe { &*self.metas.add(frame) })
+    }
+    fn get_mut(&mut self, paddr: usize) -> Option<&mut FrameMeta> {
+        let frame = paddr / paging::PTE_SIZE;
+        // SAFETY: in bounds, metas are valid as guaranteed by `new`
+        (frame < self.frame_count).then(|| unsafe { &mut *self.metas.add(frame) })
+    }
+
+    /// Sets the owner of the frames within `paddr` through `paddr + size`.
+    pub fn set_owner(&mut self, paddr: usize, size: usize, owner: FrameOwner) {
+        for frame_paddr in (paddr..paddr + size).step_by(paging::PTE_SIZE) {
+            if let Some(meta) = self.get_mut(frame_paddr) {
+                meta.owner = owner;
+            }
+        }
+    }
+
+    /// Records the allocation of `paddr` through `paddr + size` to `owner`,
+    /// with a single reference.
+    pub fn allocated(&mut self, paddr: usize, size: usize, owner: FrameOwner) {
+        for frame_paddr in (paddr..paddr + size).step_by(paging::PTE_SIZE) {
+            if let Some(meta) = self.get_mut(frame_paddr) {
+                *meta = FrameMeta { refs: 1, pins: 0, owner, flags: FrameFlags::empty() };
+            }
+        }
+        if size > paging::PTE_SIZE {
+            if let Some(meta) = self.get_mut(paddr) {
+                meta.flags |= FrameFlags::HUGE;
+            }
+        }
+    }
+
+    /// Records the freeing of `paddr` through `paddr + size`.
+    pub fn freed(&mut self, paddr: usize, size: usize) {
+        for frame_paddr in (paddr..paddr + size).step_by(paging::PTE_SIZE) {
+            if let Some(meta) = self.get_mut(frame_paddr) {
+                *meta = FrameMeta { refs: 0, pins: 0, owner: FrameOwner::Free, flags: FrameFlags::empty() };
+            }
+        }
+    }
+
+    /// Records an additional mapping of the frame at `paddr`.
+    pub fn share(&mut self, paddr: usize) {
+        match self.get_mut(paddr) {
+            Some(meta) if !meta.flags.contains(FrameFlags::ZERO) => meta.refs += 1,
+            _ => (),
+        }
+    }
+
+    /// Records the removal of a mapping of the frame at `paddr`.
+    /// 
+    /// Returns whether the frame is no longer referenced nor pinned, such that it should be freed.
+    pub fn release(&mut self, paddr: usize) -> bool {
+        match self.get_mut(paddr) {
+            Some(meta) if meta.flags.contains(FrameFlags::ZERO) => false,
+            Some(meta) => {
+                meta.refs = meta.refs.saturating_sub(1);
+                if meta.refs == 0 && meta.pins != 0 {
+                    meta.flags |= FrameFlags::ORPHANED;
+                }
+                meta.refs == 0 && meta.pins == 0
+            },
+            None => true,
+        }
+    }
+
+    /// Returns whether the frame at `paddr` is solely referenced by the caller.
+    pub fn is_exclusive(&self, paddr: usize) -> bool {
+        match self.get(paddr) {
+            Some(meta) => !meta.flags.contains(FrameFlags::ZERO) && meta.refs <= 1,
+            None => true,
+        }
+    }
+
+    /// Pins the frame at `paddr`, preventing it from being freed until unpinned.
+    pub fn pin(&mut self, paddr: usize) {
+        if let Some(meta) = self.get_mut(paddr) {
+            meta.pins = meta.pins.checked_add(1).expect("frame pin count overflow");
+        }
+    }
+    /// Unpins the frame at `paddr`.
+    /// 
+    /// Returns whether the frame was orphaned and is no longer pinned, such that it should be freed.
+    pub fn unpin(&mut self, paddr: usize) -> bool {
+        match self.get_mut(paddr) {
+            Some(meta) => {
+                meta.pins = meta.pins.checked_sub(1).expect("frame is not pinned");
+                meta.pins == 0 && meta.flags.contains(FrameFlags::ORPHANED)
+            },
+            None => false,
+        }
+    }
+
+    /// Tallies the frames of each owner.
+    pub fn usage(&self) -> FrameUsage {
+        let mut frames = [0; FrameOwner::COUNT];
+        for i in 0..self.frame_count {
+            // SAFETY: in bounds, metas are valid as guaranteed by `new`
+            frames[unsafe { (*self.metas.add(i)).owner } as usize] += 1;
+        }
+        FrameUsage { frames }
+    }
+
+    /// Prints the physical memory usage per owner.
+    pub fn dump(&self) {
+        crate::println!("{}", self.usage());
+    }
+}
+
+
+/// The number of physical frames of each owner.
+#[derive(Debug, Clone, Copy)]
+pub struct FrameUsage {
+    /// Frame counts, indexed by `FrameOwner` discriminant.
+    pub frames: [usize; FrameOwner::COUNT],
+}
+
+impl FrameUsage {
+    /// Returns the number of frames owned by `owner`.
+    pub fn of(&self, owner: FrameOwner) -> usize {
+        self.frames[owner as usize]
+    }
+}
+
+impl fmt::Display for FrameUsage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Physical memory usage:")?;
+        for owner in FrameOwner::ALL {
+            let frames = self.of(owner);
+            write!(f, "\n  {:>9} frames {:>10} KiB  {:?}",
+                frames,
+                frames * paging::PTE_SIZE / 1024,
+                owner,
+            )?;
+        }
+        Ok(())
+    }
+}
@@ -1,15 +1,30 @@
 //! Memory management module.
 
 pub mod talloc;
-
-use core::{marker::PhantomData, ptr};
+pub mod addrspace;
+pub mod region;
+pub mod fault;
+pub mod frames;
+pub mod stack;
+pub mod tlb;
+
+use core::{
+    alloc::{GlobalAlloc, Layout},
+    marker::PhantomData,
+    mem::ManuallyDrop,
+    ops::{Deref, DerefMut},
+    ptr,
+    sync::atomic::{AtomicBool, Ordering},
+};
 
 use amd64::{
-    paging::{self, PTE, Pat, PatType},
-    registers::{CR0, CR3}
+    paging::{self, PTE, Pat, PatType, PageSize},
+    registers::{CR0, CR3, CR4}
 };
-use spin::Mutex;
+use spin::{Mutex, MutexGuard};
 use talloc::Talloc;
+use frames::{FrameTable, FrameOwner};
+use tlb::TlbBatch;
 
 use crate::utils;
 
@@ -17,11 +32,26 @@ use crate::utils;
 pub const RCRSV_IDX: usize = 0o400;
 /// The index to map a PML4 entry onto a different PML4.
 pub const GUEST_IDX: usize = 0o401; */
+/// The index of the first PML4 entry of the kernel's higher half.
+pub const KRNL_HALF_IDX: usize = 0o400;
 /// The index to map physical memory at an offset.
 pub const OFFSET_IDX: usize = 0o400;
 /// The offset of identity-mapped physical memory.
 pub const PHYS_LADDR_OFFSET: isize = -0o400_000_000_000_0000;
 
+/// Software-available PTE bit marking a leaf as a read-only mapping of a 
+/// shared frame, that is to be copied or reclaimed upon being written.
+pub const PTE_COW: PTE = PTE::from_bits_truncate(1 << 9);
+
+/// Returns `leaves` with `PTE::G` set if `base` is within the kernel's half.
+/// 
+/// The kernel's half is shared by every address space, hence its entries are global,
+/// such that they're cached once for every PCID, and are invalidated by `invlpg`
+/// and shootdowns regardless of the PCID they were cached under.
+fn krnl_global(base: *mut u8, leaves: PTE) -> PTE {
+    if (base as isize) < 0 { leaves | PTE::G } else { leaves }
+}
+
 #[macro_export]
 macro_rules! from_phys_addr {
     ($paddr:expr, $t:ty) => {
@@ -50,10 +80,13 @@ within the topmost 1 GiB huge page (along with its stacks below it). */
 pub const KRNL_BOOT_BASE: usize = 0usize.wrapping_sub(paging::PDPTE_SIZE);
 
 
-/// Acme of the topmost kernel stack (the bootstrap CPU).
+/// Acme of the topmost per-CPU stack and heap slot. See `stack`.
 pub const KRNL_STACK_ACME: usize = 0usize.wrapping_sub(paging::PDPTE_SIZE);
-/// Size of each kernel process stack, excluding seperation page.
-pub const KRNL_STACK_SIZE: usize = 4 * 1024 * 1024 - paging::PTE_SIZE;
+
+/// Acme of low memory, which is addressable from real mode. See `Mapper::alloc_low`.
+pub const LOW_MEM_ACME: usize = 0x100000;
+/// The maximum number of available blocks of low memory retained by the `Mapper`.
+const LOW_MEM_BLOCKS: usize = 8;
 
 
 /// Default PAT used. The table is as follows:
@@ -97,12 +130,128 @@ pub const fn pat_type_to_pte(pat_type: PatType, is_hpage: bool) -> PTE {
 
 
 
-pub static MAPPER: Mutex<Mapper> = Mutex::new(unsafe { Mapper::new_invalid() });
+pub static MAPPER: MapperLock = MapperLock::new(unsafe { Mapper::new_invalid() });
 fn mapper_oom_handler(_: &mut Talloc, _: core::alloc::Layout)
 -> Result<(), core::alloc::AllocError> {
     Err(core::alloc::AllocError)
 }
 
+/// The size of the kernel stack below the stack pointer that's backed before
+/// `MAPPER` is locked, see `MapperLock::lock`.
+const MAPPER_STACK_RESERVE: usize = 4 * paging::PTE_SIZE;
+
+/// A lock around a `Mapper`, which shoots down the TLB entries invalidated by
+/// the `Mapper` once it's unlocked, see `MapperGuard`.
+/// 
+/// Shootdowns wait upon the other CPUs, which may be waiting upon this lock with
+/// interrupts disabled, e.g. upon a page fault, hence they're acknowledged while waiting.
+pub struct MapperLock(Mutex<Mapper>);
+
+impl MapperLock {
+    pub const fn new(mapper: Mapper) -> Self {
+        Self(Mutex::new(mapper))
+    }
+
+    /// Locks the `Mapper`, acknowledging shootdowns while waiting upon it.
+    /// 
+    /// The kernel stack is backed `MAPPER_STACK_RESERVE` below the stack pointer
+    /// beforehand, as it's grown through the `Mapper`, see `stack::reserve`.
+    pub fn lock(&self) -> MapperGuard<'_> {
+        stack::reserve(MAPPER_STACK_RESERVE);
+        loop {
+            if let Some(guard) = self.0.try_lock() {
+                return MapperGuard { guard: ManuallyDrop::new(guard) };
+            }
+            // a shootdown's initiator may be waiting upon this CPU
+            tlb::acknowledge();
+            core::hint::spin_loop();
+        }
+    }
+}
+
+/// The guard of a locked `Mapper`, which shoots down the TLB entries the `Mapper`
+/// invalidated while locked once it's unlocked, such that the other CPUs aren't
+/// waited upon while they may be waiting upon the lock.
+pub struct MapperGuard<'a> {
+    guard: ManuallyDrop<MutexGuard<'a, Mapper>>,
+}
+
+impl Deref for MapperGuard<'_> {
+    type Target = Mapper;
+
+    fn deref(&self) -> &Mapper {
+        &self.guard
+    }
+}
+
+impl DerefMut for MapperGuard<'_> {
+    fn deref_mut(&mut self) -> &mut Mapper {
+        &mut self.guard
+    }
+}
+
+impl Drop for MapperGuard<'_> {
+    fn drop(&mut self) {
+        let batch = core::mem::replace(&mut self.guard.remote, TlbBatch::new());
+        // SAFETY: the guard isn't used hereafter
+        unsafe { ManuallyDrop::drop(&mut self.guard); }
+        batch.shootdown();
+    }
+}
+
+/// Whether the global allocator may allocate, see `PageAllocator::lend`.
+static LENT: AtomicBool = AtomicBool::new(false);
+
+/// Allocates physical memory through the offset mapping, in blocks of at least a page.
+/// 
+/// Each CPU has its own heap, which should be preferred. This is only lent to the
+/// global allocator for dependencies that require `alloc`'s types, e.g. `acpi`, and
+/// is only suited to infrequent allocations. Must not be used within interrupt
+/// handlers, as it locks `MAPPER`.
+pub struct PageAllocator;
+
+impl PageAllocator {
+    /// Runs `f`, during which the global allocator allocates through the `PageAllocator`,
+    /// such that the allocations of dependencies are explicit, see `is_lent`.
+    pub fn lend<R>(f: impl FnOnce() -> R) -> R {
+        let was_lent = LENT.swap(true, Ordering::AcqRel);
+        let result = f();
+        LENT.store(was_lent, Ordering::Release);
+        result
+    }
+
+    /// Returns whether the global allocator may allocate through the `PageAllocator`,
+    /// see `lend`. Memory it allocated may be freed regardless.
+    pub fn is_lent() -> bool {
+        LENT.load(Ordering::Acquire)
+    }
+
+    /// Returns the layout of the block of physical memory backing `layout`.
+    fn block_layout(layout: Layout) -> Layout {
+        let size = layout.size().max(layout.align()).next_power_of_two().max(paging::PTE_SIZE);
+        // SAFETY: size is a nonzero power of two
+        unsafe { Layout::from_size_align_unchecked(size, size) }
+    }
+}
+
+unsafe impl GlobalAlloc for PageAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let block = Self::block_layout(layout);
+        let mut mapper = MAPPER.lock();
+        match mapper.talloc.alloc(block) {
+            Ok(ptr) => {
+                mapper.frames.allocated(to_phys_addr!(ptr.as_ptr()), block.size(), FrameOwner::Heap);
+                ptr.as_ptr()
+            },
+            Err(_) => ptr::null_mut(),
+        }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        MAPPER.lock().free_phys(to_phys_addr!(ptr), Self::block_layout(layout).size());
+    }
+}
+
 
 
 /// Maps `base` through `acme` to physical memory.
@@ -110,12 +259,13 @@ fn mapper_oom_handler(_: &mut Talloc, _: core::alloc::Layout)
 /// * Any existing mappings within the span of virtual addresses will be remapped.
 /// * Physical addresses of the page tables must be offset-identity mapped.
 /// * `table` must fully contain the virtual span of memory.
-/// * `page_getter` should return sufficient valid pages for necessary physical pages
-/// and page table pages with the size as specified (either 4KiB, 2MiB, or 1GiB).
+/// * `page_getter` should return valid page table pages as necessary.
+/// * `frame_getter` should return sufficient valid pages for the mapped physical 
+/// memory with the size as specified (either 4KiB, 2MiB, or 1GiB).
 /// * The specified `PTE`s must be valid and usable, and not contain an address.
-pub unsafe fn map_offset<const LVL: usize, F>(mut base: *mut u8, acme: *mut u8,
-branches: PTE, leaves: PTE, table: *mut [PTE], page_getter: &mut F)
-where F: FnMut(usize) -> usize {
+pub unsafe fn map_offset<const LVL: usize, F, G>(mut base: *mut u8, acme: *mut u8,
+branches: PTE, leaves: PTE, table: *mut [PTE], page_getter: &mut F, frame_getter: &mut G)
+where F: FnMut(usize) -> usize, G: FnMut(usize) -> usize {
     use paging::{PML4_LVL, PDPT_LVL, PD_LVL, PT_LVL};
 
     if LVL < PT_LVL || LVL > PML4_LVL { panic!("INVALID PAGE TABLE LVL") }
@@ -133,7 +283,7 @@ where F: FnMut(usize) -> usize {
 
         if LVL == PT_LVL || remaining >= page_size && ps_aligned && LVL < 4 {
             // create leaf entry, mapping physical to virtual memory
-            let mut entry = PTE::P | PTE::from_paddr(page_getter(page_size)) | leaves;
+            let mut entry = PTE::P | PTE::from_paddr(frame_getter(page_size)) | leaves;
             if LVL != PT_LVL {
                 // determine whether PAT/PS is set, configure accordingly
                 if entry.contains(PTE::PAT) {
@@ -161,12 +311,12 @@ where F: FnMut(usize) -> usize {
             // navigate down the page table tree
             // FIXME: Use `{LVL - 1}` when const generics have better support?
             match LVL {
-                PML4_LVL => map_offset::<PDPT_LVL, F>(base, acme,
-                    branches, leaves, lower_table, page_getter),
-                PDPT_LVL => map_offset::<PD_LVL, F>(base, acme, 
-                    branches, leaves, lower_table, page_getter),
-                PD_LVL => map_offset::<PT_LVL, F>(base, acme, 
-                    branches, leaves, lower_table, page_getter),
+                PML4_LVL => map_offset::<PDPT_LVL, F, G>(base, acme,
+                    branches, leaves, lower_table, page_getter, frame_getter),
+                PDPT_LVL => map_offset::<PD_LVL, F, G>(base, acme, 
+                    branches, leaves, lower_table, page_getter, frame_getter),
+                PD_LVL => map_offset::<PT_LVL, F, G>(base, acme, 
+                    branches, leaves, lower_table, page_getter, frame_getter),
                 // SAFETY: this possiblity is checked for 
                 _ => core::hint::unreachable_unchecked(),
             }
@@ -246,22 +396,336 @@ where F: FnMut() -> usize {
     }
 }
 
+/// Replaces the huge page leaf `pte` at `lvl` with a table of `lvl - 1` leaves
+/// that map the same physical memory with the same flags.
+/// ### Safety:
+/// * Physical addresses of the page tables must be offset-identity mapped.
+/// * `pte` must be a present huge page leaf at `lvl`, being `PDPT_LVL` or `PD_LVL`.
+/// * `page_getter` should return a valid page table page as necessary.
+pub unsafe fn split_hpage<F>(pte: *mut PTE, lvl: usize, page_getter: &mut F)
+where F: FnMut(usize) -> usize {
+    let page_size = paging::page_size(lvl);
+    let lower_size = paging::page_size(lvl - 1);
+    let paddr = (*pte).get_leaf_paddr(page_size);
+    let is_pat = (*pte).contains(PTE::PAT_PS);
+
+    // carry over the flags, moving the PAT bit if the new leaves are PTEs
+    let mut leaves = *pte & !(PTE::BASE_MASK | PTE::PS);
+    if lvl - 1 == paging::PT_LVL {
+        if is_pat { leaves |= PTE::PAT; }
+    } else {
+        leaves |= PTE::PS;
+        if is_pat { leaves |= PTE::PAT_PS; }
+    }
+
+    let page = page_getter(paging::PTE_SIZE);
+    let lower_table = crate::from_phys_addr!(page, PTE);
+    for i in 0..512 {
+        lower_table.add(i).write(PTE::from_paddr(paddr + i * lower_size) | leaves);
+    }
+
+    // branches must not be more restrictive than the leaves they replace
+    *pte = PTE::P | PTE::RW | (*pte & PTE::US) | PTE::from_paddr(page);
+}
+
+/// Unmaps `base` through `acme`, invalidating the TLB for each page unmapped.
+/// 
+/// Huge pages that are only partially within the span are split. Page tables 
+/// emptied by the unmapping are unlinked and passed to `page_dropper`, as are 
+/// the physical pages of all unmapped leaves, as `(paddr, size)`.
+/// 
+/// Returns whether `table` no longer contains any present entries. Emptied 
+/// higher half PDPTs are retained.
+/// ### Safety:
+/// * Physical addresses of the page tables must be offset-identity mapped.
+/// * `table` must fully contain the virtual span of memory.
+/// * `page_getter` should return valid page table pages as necessary.
+/// * Memory within the span must not be accessed after it is unmapped.
+pub unsafe fn unmap_offset<const LVL: usize, F, G>(mut base: *mut u8, acme: *mut u8,
+table: *mut [PTE], page_getter: &mut F, page_dropper: &mut G) -> bool
+where F: FnMut(usize) -> usize, G: FnMut(usize, usize) {
+    use paging::{PML4_LVL, PDPT_LVL, PD_LVL, PT_LVL};
+
+    if LVL < PT_LVL || LVL > PML4_LVL { panic!("INVALID PAGE TABLE LVL") }
+
+    // loop across the entries
+    while (base as isize) < (acme as isize) {
+        let table_index = paging::table_index(base, LVL);
+        let pte = table.get_unchecked_mut(table_index);
+        let page_size = paging::page_size(LVL);
+        let entry_base = (base as usize & !(page_size - 1)) as *mut u8;
+        let is_covered = base == entry_base
+            && (acme as usize).wrapping_sub(entry_base as usize) >= page_size;
+
+        if (*pte).contains(PTE::P) {
+            if LVL == PT_LVL || LVL != PML4_LVL && (*pte).contains(PTE::PS) {
+                if is_covered {
+                    // remove the leaf entry entirely
+                    page_dropper((*pte).get_leaf_paddr(page_size), page_size);
+                    *pte = PTE::empty();
+                    paging::invlpg(entry_base);
+                } else {
+                    // only part of the huge page is unmapped, break it down
+                    split_hpage(pte, LVL, page_getter);
+                }
+            }
+
+            if (*pte).contains(PTE::P) {
+                let lower_table = core::ptr::slice_from_raw_parts_mut(
+                    crate::from_phys_addr!((*pte).get_paddr(), PTE), 
+                    512
+                );
+
+                // navigate down the page table tree
+                let is_emptied = match LVL {
+                    PML4_LVL => unmap_offset::<PDPT_LVL, F, G>(base, acme,
+                        lower_table, page_getter, page_dropper),
+                    PDPT_LVL => unmap_offset::<PD_LVL, F, G>(base, acme,
+                        lower_table, page_getter, page_dropper),
+                    PD_LVL => unmap_offset::<PT_LVL, F, G>(base, acme,
+                        lower_table, page_getter, page_dropper),
+                    // SAFETY: this possiblity is checked for 
+                    _ => core::hint::unreachable_unchecked(),
+                };
+
+                // higher half PDPTs are shared between address spaces, retain them
+                if is_emptied && !(LVL == PML4_LVL && table_index >= KRNL_HALF_IDX) {
+                    page_dropper((*pte).get_paddr(), paging::PTE_SIZE);
+                    *pte = PTE::empty();
+                    paging::invlpg(entry_base);
+                }
+            }
+        }
+
+        base = entry_base.wrapping_add(page_size);
+
+        if table_index == 511 { break; }
+    }
+
+    (*table).iter().all(|pte| !pte.contains(PTE::P))
+}
+
+/// Sets the flags of the present leaves within `base` through `acme` to `leaves`,
+/// preserving the mapped physical addresses, invalidating the TLB for each page.
+/// 
+/// `leaves` is expected as for a Page Table entry, i.e. with `PTE::PAT` indicating 
+/// the high bit of the PAT index, which is converted as necessary for huge pages.
+/// Huge pages that are only partially within the span are split. The accessed,
+/// dirty and available bits are preserved, and traversed branches are made to 
+/// allow `PTE::RW` and `PTE::US` if `leaves` does.
+/// 
+/// Writable leaves of frames that are shared, being `PTE_COW` leaves or those for
+/// which `is_exclusive` returns false, are made `PTE_COW` rather than `PTE::RW`.
+/// ### Safety:
+/// * Physical addresses of the page tables must be offset-identity mapped.
+/// * `table` must fully contain the virtual span of memory.
+/// * `page_getter` should return valid page table pages as necessary.
+/// * The new flags must not violate memory safety for existing uses of the memory.
+/// * The specified `PTE` must be valid and usable, and not contain an address.
+pub unsafe fn protect_offset<const LVL: usize, F, G>(mut base: *mut u8, acme: *mut u8,
+leaves: PTE, table: *mut [PTE], page_getter: &mut F, is_exclusive: &mut G)
+where F: FnMut(usize) -> usize, G: FnMut(usize) -> bool {
+    use paging::{PML4_LVL, PDPT_LVL, PD_LVL, PT_LVL};
+
+    if LVL < PT_LVL || LVL > PML4_LVL { panic!("INVALID PAGE TABLE LVL") }
+
+    let preserved = PTE::A | PTE::D | PTE::AVL_MASK_0 | PTE::AVL_MASK_1;
+
+    // loop across the entries
+    while (base as isize) < (acme as isize) {
+        let table_index = paging::table_index(base, LVL);
+        let pte = table.get_unchecked_mut(table_index);
+        let page_size = paging::page_size(LVL);
+        let entry_base = (base as usize & !(page_size - 1)) as *mut u8;
+        let is_covered = base == entry_base
+            && (acme as usize).wrapping_sub(entry_base as usize) >= page_size;
+
+        if (*pte).contains(PTE::P) {
+            if LVL == PT_LVL || LVL != PML4_LVL && (*pte).contains(PTE::PS) {
+                if is_covered {
+                    // rewrite the leaf entry's flags
+                    let mut entry = PTE::P 
+                        | PTE::from_paddr((*pte).get_leaf_paddr(page_size))
+                        | *pte & preserved
+                        | leaves;
+                    if LVL != PT_LVL {
+                        // determine whether PAT/PS is set, configure accordingly
+                        if entry.contains(PTE::PAT) {
+                            entry |= PTE::PAT_PS;
+                        } else {
+                            entry |= PTE::PS;
+                        }
+                    }
+                    // shared frames remain read-only until written, see `Mapper::unshare`
+                    if !leaves.contains(PTE::RW) {
+                        entry.remove(PTE_COW);
+                    } else if entry.contains(PTE_COW) || !is_exclusive(entry.get_leaf_paddr(page_size)) {
+                        entry = entry & !PTE::RW | PTE_COW;
+                    }
+                    *pte = entry;
+                    paging::invlpg(entry_base);
+                } else {
+                    // only part of the huge page is affected, break it down
+                    split_hpage(pte, LVL, page_getter);
+                }
+            }
+
+            if LVL != PT_LVL && !(*pte).contains(PTE::PS) {
+                *pte |= leaves & (PTE::RW | PTE::US);
+
+                let lower_table = core::ptr::slice_from_raw_parts_mut(
+                    crate::from_phys_addr!((*pte).get_paddr(), PTE), 
+                    512
+                );
+
+                // navigate down the page table tree
+                match LVL {
+                    PML4_LVL => protect_offset::<PDPT_LVL, F, G>(base, acme,
+                        leaves, lower_table, page_getter, is_exclusive),
+                    PDPT_LVL => protect_offset::<PD_LVL, F, G>(base, acme,
+                        leaves, lower_table, page_getter, is_exclusive),
+                    PD_LVL => protect_offset::<PT_LVL, F, G>(base, acme,
+                        leaves, lower_table, page_getter, is_exclusive),
+                    // SAFETY: this possiblity is checked for 
+                    _ => core::hint::unreachable_unchecked(),
+                }
+            }
+        }
+
+        base = entry_base.wrapping_add(page_size);
+
+        if table_index == 511 { break; }
+    }
+}
+
+/// Maps `base` through `acme` in `dst` to the physical memory mapped in `src`,
+/// passing the physical address of each shared frame to `frame_sharer`.
+/// 
+/// Writable leaves are made read-only and marked `PTE_COW` in both hierarchies,
+/// invalidating the TLB for each. Huge pages within the span are split, such
+/// that frames are shared individually. Unmapped pages are skipped.
+/// ### Safety:
+/// * Physical addresses of the page tables must be offset-identity mapped.
+/// * `src` and `dst` must fully contain the virtual span of memory.
+/// * The span must not be mapped in `dst`.
+/// * `page_getter` should return valid page table pages as necessary.
+pub unsafe fn share_offset<const LVL: usize, F, G>(mut base: *mut u8, acme: *mut u8,
+src: *mut [PTE], dst: *mut [PTE], page_getter: &mut F, frame_sharer: &mut G)
+where F: FnMut(usize) -> usize, G: FnMut(usize) {
+    use paging::{PML4_LVL, PDPT_LVL, PD_LVL, PT_LVL};
+
+    if LVL < PT_LVL || LVL > PML4_LVL { panic!("INVALID PAGE TABLE LVL") }
+
+    // loop across the entries
+    while (base as isize) < (acme as isize) {
+        let table_index = paging::table_index(base, LVL);
+        let src_pte = src.get_unchecked_mut(table_index);
+        let dst_pte = dst.get_unchecked_mut(table_index);
+        let page_size = paging::page_size(LVL);
+        let entry_base = (base as usize & !(page_size - 1)) as *mut u8;
+
+        if (*src_pte).contains(PTE::P) {
+            if LVL == PT_LVL {
+                if (*src_pte).contains(PTE::RW) {
+                    *src_pte = *src_pte & !PTE::RW | PTE_COW;
+                    paging::invlpg(entry_base);
+                }
+                frame_sharer((*src_pte).get_paddr());
+                *dst_pte = *src_pte;
+            } else {
+                if LVL != PML4_LVL && (*src_pte).contains(PTE::PS) {
+                    // frames are shared individually, break it down
+                    split_hpage(src_pte, LVL, page_getter);
+                }
+
+                // allocate new page table if none exists
+                if !(*dst_pte).contains(PTE::P) {
+                    let page = page_getter(paging::PTE_SIZE);
+                    crate::from_phys_addr!(page, PTE).write_bytes(0, 512);
+                    *dst_pte = PTE::P | PTE::from_paddr(page);
+                }
+                *dst_pte |= *src_pte & (PTE::RW | PTE::US);
+
+                let lower_src = core::ptr::slice_from_raw_parts_mut(
+                    crate::from_phys_addr!((*src_pte).get_paddr(), PTE), 
+                    512
+                );
+                let lower_dst = core::ptr::slice_from_raw_parts_mut(
+                    crate::from_phys_addr!((*dst_pte).get_paddr(), PTE), 
+                    512
+                );
+
+                // navigate down the page table tree
+                match LVL {
+                    PML4_LVL => share_offset::<PDPT_LVL, F, G>(base, acme,
+                        lower_src, lower_dst, page_getter, frame_sharer),
+                    PDPT_LVL => share_offset::<PD_LVL, F, G>(base, acme,
+                        lower_src, lower_dst, page_getter, frame_sharer),
+                    PD_LVL => share_offset::<PT_LVL, F, G>(base, acme,
+                        lower_src, lower_dst, page_getter, frame_sharer),
+                    // SAFETY: this possiblity is checked for 
+                    _ => core::hint::unreachable_unchecked(),
+                }
+            }
+        }
+
+        base = entry_base.wrapping_add(page_size);
+
+        if table_index == 511 { break; }
+    }
+}
+
 /// Returns the page table entry that translates `laddr` at `lvl`.
 /// 
 /// Use `paging::table_of_entry` on the result to get the corresponding table.
+/// Returns `None` if an entry above `lvl` along the walk is not present,
+/// or is a huge page leaf, as there is no table at `lvl`.
 /// ### Safety:
 /// * Physical addresses of the page tables must be offset-identity mapped.
-/// * `pml4` must map laddr to the given `lvl` (i.e. a full mapping is not required).
-pub unsafe fn get_entry_offset(laddr: *mut u8, lvl: usize, pml4: *mut [PTE]) -> *mut PTE {
-    let mut entry_ptr = pml4.as_mut_ptr();
-    let mut lvl_idx = 4;
+pub unsafe fn get_entry_offset(laddr: *mut u8, lvl: usize, pml4: *mut [PTE]) -> Option<*mut PTE> {
+    use paging::PML4_LVL;
+
+    let mut table = pml4.as_mut_ptr();
+    let mut lvl_idx = PML4_LVL;
     while lvl_idx > lvl {
-        let pte = *entry_ptr.wrapping_add(paging::table_index(entry_ptr, lvl_idx));
-        crate::println!("pte {:?}", pte);
-        entry_ptr = from_phys_addr!(pte.get_paddr(), PTE);
+        let pte = *table.add(paging::table_index(laddr, lvl_idx));
+        if !pte.contains(PTE::P) || lvl_idx != PML4_LVL && pte.contains(PTE::PS) {
+            return None;
+        }
+        table = from_phys_addr!(pte.get_paddr(), PTE);
         lvl_idx -= 1;
     }
-    entry_ptr.wrapping_add(paging::table_index(laddr, lvl_idx))
+    Some(table.add(paging::table_index(laddr, lvl_idx)))
+}
+
+/// Translates `laddr` into the physical address it is mapped to, along with 
+/// the size of the page and the leaf entry that maps it.
+/// 
+/// The walk terminates at huge page leaves. Returns `None` if any entry 
+/// along the walk is not present.
+/// ### Safety:
+/// * Physical addresses of the page tables must be offset-identity mapped.
+pub unsafe fn translate<T>(laddr: *mut T, pml4: *mut [PTE]) -> Option<(usize, PageSize, PTE)> {
+    use paging::{PML4_LVL, PT_LVL};
+
+    let mut table = pml4.as_mut_ptr();
+    let mut lvl = PML4_LVL;
+    loop {
+        let pte = *table.add(paging::table_index(laddr, lvl));
+        if !pte.contains(PTE::P) {
+            return None;
+        }
+
+        if lvl == PT_LVL || lvl != PML4_LVL && pte.contains(PTE::PS) {
+            let page_size = paging::page_size(lvl);
+            let paddr = pte.get_leaf_paddr(page_size) | laddr as usize & page_size - 1;
+            // SAFETY: page_size is the size of a PDPTE, PDE, or PTE
+            return Some((paddr, PageSize::from_usize(page_size), pte));
+        }
+
+        table = from_phys_addr!(pte.get_paddr(), PTE);
+        lvl -= 1;
+    }
 }
 
 
@@ -275,6 +739,11 @@ pub struct Mapper {
     pub krnl_pml4: usize,
     //pub mem_size: usize,
     pub talloc: Talloc,
+    pub frames: FrameTable,
+    /// Available low memory, withheld from `talloc`, as (base, size) pairs.
+    low_mem: [(usize, usize); LOW_MEM_BLOCKS],
+    /// The ranges to shoot down once unlocked, see `MapperGuard`.
+    remote: TlbBatch,
 }
 
 impl Mapper {
@@ -282,7 +751,10 @@ impl Mapper {
         Self {
             krnl_pml4: 0,
             /*  mem_size: 0, */
-            talloc: Talloc::new_invalid(paging::PTE_SIZE, mapper_oom_handler)
+            talloc: Talloc::new_invalid(paging::PTE_SIZE, mapper_oom_handler),
+            frames: FrameTable::new_invalid(),
+            low_mem: [(0, 0); LOW_MEM_BLOCKS],
+            remote: TlbBatch::new(),
         }
     }
 
@@ -304,6 +776,23 @@ impl Mapper {
         // disable write-protection in case the existing tables are protected
         CR0::write(CR0::read() & !CR0::WP);
 
+        // available low memory is withheld for real-mode code, see `alloc_low`
+        let mut low_mem = [(0, 0); LOW_MEM_BLOCKS];
+        let low_blks = mmap.clone().filter(|&(base, _)| base < LOW_MEM_ACME);
+        for (slot, (base, size)) in low_mem.iter_mut().zip(low_blks) {
+            // the first page holds the real-mode interrupt vector table
+            let low_base = base.max(PTE_SIZE) + PTE_SIZE-1 & !(PTE_SIZE-1);
+            let low_acme = (base + size).min(LOW_MEM_ACME) & !(PTE_SIZE-1);
+            if low_acme > low_base {
+                *slot = (low_base, low_acme - low_base);
+            }
+        }
+        let mmap = mmap.clone().filter_map(|(base, size)| {
+            let acme = base + size;
+            let base = base.max(LOW_MEM_ACME);
+            (acme > base).then(|| (base, acme - base))
+        });
+
         // determine mem size
         //let mem_size_free = mmap.clone().fold(0, |acc, (_, size)| acc + size);
 
@@ -373,14 +862,27 @@ impl Mapper {
         );
         for i in 0..512 {
             if i < (hi_phys_addr + PDPTE_SIZE-1) / PDPTE_SIZE {
-                let entry = PTE::P | PTE::RW | PTE::PS | PTE::from_paddr(i*PDPTE_SIZE);
+                let entry = PTE::P | PTE::RW | PTE::PS | PTE::G | PTE::from_paddr(i*PDPTE_SIZE);
                 *offset_map_table.get_unchecked_mut(i) = entry;
             } else {
                 *offset_map_table.get_unchecked_mut(i) = PTE::empty();
             }
         }
 
+        // ----- Preallocate the remaining higher half PDPTs ----- //
+        // Address spaces share the higher half by copying the PML4 entries, 
+        // hence these must not change once address spaces are created.
+        for i in KRNL_HALF_IDX..512 {
+            if !(*pml4.get_unchecked_mut(i)).contains(PTE::P) {
+                let pdpt_paddr = page_getter();
+                (pdpt_paddr as *mut PTE).write_bytes(0, 512);
+                *pml4.get_unchecked_mut(i) = PTE::P | PTE::RW | PTE::from_paddr(pdpt_paddr);
+            }
+        }
+
         // ----- Set new PML4 as active ----- //
+        // the kernel's half is mapped global, see `krnl_global`
+        CR4::write(CR4::read() | CR4::PGE);
         CR3::set_nflags(pml4.as_mut_ptr() as usize);
 
         // Done modifying page tables for now;
@@ -406,30 +908,111 @@ impl Mapper {
             talloc.release(ptr::slice_from_raw_parts_mut(from_phys_addr!(base, u8), size));
         }
 
+        // ----- Setup frame metadata ----- //
+
+        let frame_count = hi_phys_addr / PTE_SIZE;
+        let metas_size = FrameTable::size_of(frame_count) + PTE_SIZE-1 & !(PTE_SIZE-1);
+        let metas = talloc.alloc(core::alloc::Layout::from_size_align_unchecked(metas_size, PTE_SIZE))
+            .expect("Frame metadata allocation failed.")
+            .as_ptr();
+        let mut frames = FrameTable::new(metas.cast(), frame_count);
+        for (base, size) in mmap.clone() {
+            frames.set_owner(base, size, FrameOwner::Free);
+        }
+        // the page tables allocated above
+        frames.set_owner(lgst_blk.0, page_getter_offset, FrameOwner::PageTable);
+        frames.set_owner(to_phys_addr!(metas), metas_size, FrameOwner::Pinned);
+
+        let zero_frame = talloc.alloc(core::alloc::Layout::from_size_align_unchecked(PTE_SIZE, PTE_SIZE))
+            .expect("Zero frame allocation failed.")
+            .as_ptr();
+        zero_frame.write_bytes(0, PTE_SIZE);
+        frames.set_zero_frame(to_phys_addr!(zero_frame));
+
         // set MAPPER
-        *MAPPER.lock() = Self { krnl_pml4: CR3::read().paddr, talloc };
+        *MAPPER.lock() = Self { krnl_pml4: CR3::read().paddr, talloc, frames, low_mem, remote: TlbBatch::new() };
 
         // return the pml4 paddr
         CR3::read().paddr
     }
 
+    /// Allocates physical memory on behalf of `owner`, with a single reference.
     /// ### Safety:
     /// Size must be nonzero.
-    unsafe fn alloc_phys(&mut self, size: usize) -> usize {
-        to_phys_addr!(
-            self.talloc.alloc(core::alloc::Layout::from_size_align_unchecked(size, size))
-                // todo: handle more gracefully?
-                .expect("Out of physical memory exception!")
-                .as_ptr()
-        )
+    unsafe fn alloc_phys(&mut self, size: usize, owner: FrameOwner) -> usize {
+        // todo: handle more gracefully?
+        self.try_alloc_phys(size, owner).expect("Out of physical memory exception!")
     }
 
-    /// Maps base through acme to avaialable physical memory.
+    /// Allocates physical memory on behalf of `owner`, with a single reference,
+    /// or returns `None` if physical memory is exhausted.
+    /// ### Safety:
+    /// Size must be nonzero.
+    unsafe fn try_alloc_phys(&mut self, size: usize, owner: FrameOwner) -> Option<usize> {
+        let ptr = self.talloc.alloc(core::alloc::Layout::from_size_align_unchecked(size, size)).ok()?;
+        let paddr = to_phys_addr!(ptr.as_ptr());
+        self.frames.allocated(paddr, size, owner);
+        Some(paddr)
+    }
+
+    /// Drops a reference to the physical memory, freeing it 
+    /// unless it remains shared or pinned.
+    /// ### Safety:
+    /// `paddr` must have been returned by `alloc_phys`, or be a `size`-aligned 
+    /// part thereof, and must not be in use.
+    unsafe fn free_phys(&mut self, paddr: usize, size: usize) {
+        // shared and pinned frames are freed once their last mapping is dropped
+        if !self.frames.release(paddr) {
+            return;
+        }
+
+        self.frames.freed(paddr, size);
+        self.talloc.dealloc(
+            core::ptr::NonNull::new_unchecked(from_phys_addr!(paddr, u8)),
+            core::alloc::Layout::from_size_align_unchecked(size, size)
+        );
+    }
+
+    /// Allocates `size` bytes of page-aligned low memory on behalf of `owner`, 
+    /// e.g. for code executed in real mode, which is never freed.
+    /// 
+    /// Returns `None` if insufficient low memory is available.
+    pub fn alloc_low(&mut self, size: usize, owner: FrameOwner) -> Option<usize> {
+        let size = size + paging::PTE_SIZE-1 & !(paging::PTE_SIZE-1);
+        let blk = self.low_mem.iter_mut().find(|blk| blk.1 >= size)?;
+
+        let paddr = blk.0;
+        *blk = (blk.0 + size, blk.1 - size);
+        self.frames.set_owner(paddr, size, owner);
+        Some(paddr)
+    }
+
+    /// Pins the frame at `paddr`, such that it isn't freed until unpinned,
+    /// even if all its mappings are dropped.
+    pub fn pin(&mut self, paddr: usize) {
+        self.frames.pin(paddr & !(paging::PTE_SIZE-1));
+    }
+    /// Unpins the frame at `paddr`, freeing it if its mappings have all been dropped.
+    /// ### Safety:
+    /// The frame must have been pinned, and must not be used hereafter 
+    /// on behalf of this pin.
+    pub unsafe fn unpin(&mut self, paddr: usize) {
+        let paddr = paddr & !(paging::PTE_SIZE-1);
+        if self.frames.unpin(paddr) {
+            self.frames.freed(paddr, paging::PTE_SIZE);
+            self.talloc.dealloc(
+                core::ptr::NonNull::new_unchecked(from_phys_addr!(paddr, u8)),
+                core::alloc::Layout::from_size_align_unchecked(paging::PTE_SIZE, paging::PTE_SIZE)
+            );
+        }
+    }
+
+    /// Maps base through acme to avaialable physical memory, owned by `owner`.
     /// ### Safety:
     /// * Any existing mappings within the span of virtual addresses will be remapped.
     /// * The specified PTEs must be valid and usable, and not contain an address.
     pub unsafe fn map(&mut self, base: *mut u8, size: usize,
-    branches: PTE, leaves: PTE, pml4: *mut [PTE]) -> Mapping {
+    branches: PTE, leaves: PTE, owner: FrameOwner, pml4: *mut [PTE]) -> Mapping {
         assert!(size != 0);
 
         let base = ((base as usize) & !(paging::PTE_SIZE-1)) as *mut u8;
@@ -437,19 +1020,292 @@ impl Mapper {
 
         //crate::println!("{:p} {:#x} {:p}", base, size, pml4);
         
-        map_offset::<4, _>(
+        // both closures require the allocator
+        let this = self as *mut Self;
+        map_offset::<4, _, _>(
             base, acme,
-            branches, leaves,
+            branches, krnl_global(base, leaves),
             pml4,
-            &mut |size: usize| self.alloc_phys(size)
+            &mut |size: usize| (*this).alloc_phys(size, FrameOwner::PageTable),
+            &mut |size: usize| (*this).alloc_phys(size, owner)
         );
 
         Mapping { base, acme, pml4 }
     }
 
-    // todo:
-    // invlpg stuff
-    // unmap/configure convenience funcs?
+    /// Unmaps base through acme, returning the physical memory and any emptied 
+    /// page tables to the physical memory allocator.
+    /// ### Safety:
+    /// * The span must have been mapped by `Mapper::map`, or otherwise map 
+    /// physical memory allocated by this `Mapper`.
+    /// * Memory within the span must not be accessed after it is unmapped.
+    pub unsafe fn unmap(&mut self, base: *mut u8, size: usize, pml4: *mut [PTE]) {
+        assert!(size != 0);
+
+        let base = ((base as usize) & !(paging::PTE_SIZE-1)) as *mut u8;
+        let acme = base.wrapping_add(size + paging::PTE_SIZE-1 & !(paging::PTE_SIZE-1));
+
+        // both closures require the allocator
+        let this = self as *mut Self;
+        unmap_offset::<4, _, _>(
+            base, acme,
+            pml4,
+            &mut |size: usize| (*this).alloc_phys(size, FrameOwner::PageTable),
+            &mut |paddr: usize, size: usize| (*this).free_phys(paddr, size)
+        );
+        self.invalidate_remote(base, acme);
+    }
+
+    /// Sets the flags of the mapped leaves within base through acme to `leaves`,
+    /// without modifying which physical memory is mapped. Unmapped pages are skipped.
+    /// 
+    /// See `protect_offset` for details.
+    /// ### Safety:
+    /// * The new flags must not violate memory safety for existing uses of the memory.
+    /// * The specified PTE must be valid and usable, and not contain an address.
+    pub unsafe fn protect(&mut self, base: *mut u8, size: usize,
+    leaves: PTE, pml4: *mut [PTE]) {
+        assert!(size != 0);
+
+        let base = ((base as usize) & !(paging::PTE_SIZE-1)) as *mut u8;
+        let acme = base.wrapping_add(size + paging::PTE_SIZE-1 & !(paging::PTE_SIZE-1));
+
+        // both closures require the mapper
+        let this = self as *mut Self;
+        protect_offset::<4, _, _>(
+            base, acme,
+            krnl_global(base, leaves),
+            pml4,
+            &mut |size: usize| (*this).alloc_phys(size, FrameOwner::PageTable),
+            &mut |paddr: usize| (*this).frames.is_exclusive(paddr)
+        );
+        self.invalidate_remote(base, acme);
+    }
+
+    /// Maps base through acme in `dst_pml4` to the physical memory mapped in 
+    /// `src_pml4`, such that each frame is shared until it is written.
+    /// 
+    /// See `share_offset` for details.
+    /// ### Safety:
+    /// * The span must not be mapped in `dst_pml4`.
+    /// * Memory within the span in `src_pml4` must not be written to through
+    /// other mappings while it's shared, e.g. by DMA.
+    pub unsafe fn share(&mut self, base: *mut u8, size: usize,
+    src_pml4: *mut [PTE], dst_pml4: *mut [PTE]) -> Mapping {
+        assert!(size != 0);
+
+        let base = ((base as usize) & !(paging::PTE_SIZE-1)) as *mut u8;
+        let acme = base.wrapping_add(size + paging::PTE_SIZE-1 & !(paging::PTE_SIZE-1));
+
+        // both closures require the mapper
+        let this = self as *mut Self;
+        share_offset::<4, _, _>(
+            base, acme,
+            src_pml4, dst_pml4,
+            &mut |size: usize| (*this).alloc_phys(size, FrameOwner::PageTable),
+            &mut |paddr: usize| (*this).frames.share(paddr)
+        );
+        // the source's writable leaves were made read-only
+        self.invalidate_remote(base, acme);
+
+        Mapping { base, acme, pml4: dst_pml4 }
+    }
+
+    /// Maps base through acme to the shared zero frame, such that each page is
+    /// backed by its own memory only once written, if `leaves` is writable.
+    /// ### Safety:
+    /// * Any existing mappings within the span of virtual addresses will be remapped.
+    /// * The specified PTEs must be valid and usable, and not contain an address.
+    pub unsafe fn map_zeroed(&mut self, base: *mut u8, size: usize,
+    branches: PTE, leaves: PTE, pml4: *mut [PTE]) -> Mapping {
+        assert!(size != 0);
+
+        let base = ((base as usize) & !(paging::PTE_SIZE-1)) as *mut u8;
+        let acme = base.wrapping_add(size + paging::PTE_SIZE-1 & !(paging::PTE_SIZE-1));
+
+        let leaf = self.zeroed_leaf(krnl_global(base, leaves));
+        let mut page = base;
+        while page != acme {
+            *self.get_or_create_pte(page, branches, pml4) = leaf;
+            paging::invlpg(page);
+            page = page.wrapping_add(paging::PTE_SIZE);
+        }
+        self.invalidate_remote(base, acme);
+
+        Mapping { base, acme, pml4 }
+    }
+
+    /// Maps `page` to a newly allocated, zeroed frame owned by `owner`.
+    /// 
+    /// Unlike `map`, physical memory being exhausted isn't fatal: `Err(())` is
+    /// returned and `page` remains unmapped, though page tables may have been created.
+    /// ### Safety:
+    /// * `page` must not be mapped.
+    /// * The specified PTEs must be valid and usable, and not contain an address.
+    pub unsafe fn try_map_page(&mut self, page: *mut u8, branches: PTE, leaves: PTE,
+    owner: FrameOwner, pml4: *mut [PTE]) -> Result<(), ()> {
+        let page = ((page as usize) & !(paging::PTE_SIZE-1)) as *mut u8;
+
+        let paddr = self.try_alloc_phys(paging::PTE_SIZE, owner).ok_or(())?;
+        let pte = match self.try_get_or_create_pte(page, branches, pml4) {
+            Some(pte) => pte,
+            None => {
+                self.free_phys(paddr, paging::PTE_SIZE);
+                return Err(());
+            },
+        };
+
+        from_phys_addr!(paddr, u8).write_bytes(0, paging::PTE_SIZE);
+        *pte = PTE::P | PTE::from_paddr(paddr) | krnl_global(page, leaves);
+        paging::invlpg(page);
+        self.invalidate_remote(page, page.wrapping_add(paging::PTE_SIZE));
+        Ok(())
+    }
+
+    /// Maps `page` to the shared zero frame, as per `map_zeroed`.
+    /// 
+    /// Unlike `map_zeroed`, physical memory being exhausted by the page tables
+    /// isn't fatal: `Err(())` is returned and `page` remains unmapped.
+    /// ### Safety:
+    /// * `page` must not be mapped.
+    /// * The specified PTEs must be valid and usable, and not contain an address.
+    pub unsafe fn try_map_zeroed_page(&mut self, page: *mut u8, branches: PTE, leaves: PTE,
+    pml4: *mut [PTE]) -> Result<(), ()> {
+        let page = ((page as usize) & !(paging::PTE_SIZE-1)) as *mut u8;
+
+        *self.try_get_or_create_pte(page, branches, pml4).ok_or(())? = self.zeroed_leaf(krnl_global(page, leaves));
+        paging::invlpg(page);
+        self.invalidate_remote(page, page.wrapping_add(paging::PTE_SIZE));
+        Ok(())
+    }
+
+    /// Maps the physical memory `paddr` through `paddr + size` at its offset-identity
+    /// mapped address in the kernel's PML4 with the flags `leaves`, e.g. such that 
+    /// memory-mapped I/O is accessed uncacheably. Pages already covered by the offset
+    /// mapping are split from their huge pages as necessary.
+    /// 
+    /// Returns the linear address of `paddr`, as per `from_phys_addr!`.
+    /// ### Safety:
+    /// * The physical memory must not be allocated by this `Mapper`, 
+    /// and the span must never be unmapped.
+    /// * The specified PTE must be valid and usable, and not contain an address.
+    pub unsafe fn map_mmio(&mut self, paddr: usize, size: usize, leaves: PTE) -> *mut u8 {
+        assert!(size != 0);
+
+        let pml4 = ptr::slice_from_raw_parts_mut(from_phys_addr!(self.krnl_pml4, PTE), 512);
+        let leaves = leaves | PTE::G;
+        let base_paddr = paddr & !(paging::PTE_SIZE-1);
+        let acme_paddr = paddr + size + paging::PTE_SIZE-1 & !(paging::PTE_SIZE-1);
+
+        // both closures require the allocator
+        let this = self as *mut Self;
+        for page_paddr in (base_paddr..acme_paddr).step_by(paging::PTE_SIZE) {
+            let page = from_phys_addr!(page_paddr, u8);
+            let acme = page.wrapping_add(paging::PTE_SIZE);
+
+            if translate(page, pml4).is_some() {
+                // the offset mapping already maps the page, only the flags change
+                protect_offset::<4, _, _>(
+                    page, acme,
+                    leaves,
+                    pml4,
+                    &mut |size: usize| (*this).alloc_phys(size, FrameOwner::PageTable),
+                    &mut |paddr: usize| (*this).frames.is_exclusive(paddr)
+                );
+            } else {
+                map_offset::<4, _, _>(
+                    page, acme,
+                    PTE::RW, leaves,
+                    pml4,
+                    &mut |size: usize| (*this).alloc_phys(size, FrameOwner::PageTable),
+                    &mut |_| page_paddr
+                );
+            }
+        }
+
+        // other CPUs may have cached the offset mapping's memory types
+        self.invalidate_remote(from_phys_addr!(base_paddr, u8), from_phys_addr!(acme_paddr, u8));
+
+        from_phys_addr!(paddr, u8)
+    }
+
+    /// Makes the present `PTE_COW` leaf of `page` writable, reclaiming its 
+    /// frame if no longer shared, else remapping it to a copy of the frame.
+    /// 
+    /// Returns `Err(())` if physical memory is exhausted by the copy,
+    /// in which case the leaf is unchanged.
+    /// ### Safety:
+    /// `page` must be mapped by a `PTE_COW` leaf in `pml4`.
+    pub unsafe fn unshare(&mut self, page: *mut u8, pml4: *mut [PTE]) -> Result<(), ()> {
+        let page = ((page as usize) & !(paging::PTE_SIZE-1)) as *mut u8;
+        let pte = self.get_or_create_pte(page, PTE::RW, pml4);
+        let paddr = (*pte).get_paddr();
+
+        if self.frames.is_exclusive(paddr) {
+            *pte = *pte & !PTE_COW | PTE::RW;
+        } else {
+            // copies of the zero frame are owned as per the half they're mapped in
+            let owner = match self.frames.get(paddr) {
+                Some(meta) if paddr != self.frames.zero_frame() => meta.owner,
+                _ if (page as isize) < 0 => FrameOwner::Kernel,
+                _ => FrameOwner::User,
+            };
+            let copy = self.try_alloc_phys(paging::PTE_SIZE, owner).ok_or(())?;
+            from_phys_addr!(copy, u8).copy_from_nonoverlapping(
+                from_phys_addr!(paddr, u8),
+                paging::PTE_SIZE
+            );
+            // drops this mapping's reference to the shared frame
+            self.free_phys(paddr, paging::PTE_SIZE);
+            *pte = *pte & !(PTE::BASE_MASK | PTE_COW) | PTE::from_paddr(copy) | PTE::RW;
+        }
+        paging::invlpg(page);
+        self.invalidate_remote(page, page.wrapping_add(paging::PTE_SIZE));
+        Ok(())
+    }
+
+    /// Returns the leaf mapping the shared zero frame with the flags `leaves`,
+    /// which is copied upon being written if `leaves` is writable.
+    fn zeroed_leaf(&self, leaves: PTE) -> PTE {
+        let leaf = PTE::P | PTE::from_paddr(self.frames.zero_frame()) | leaves & !PTE::RW;
+        if leaves.contains(PTE::RW) { leaf | PTE_COW } else { leaf }
+    }
+
+    /// Invalidates the TLB entries of `base` through `acme` that may remain in the
+    /// other CPUs' TLBs once the page tables are modified. Entries of other PCIDs
+    /// needn't be, as those of the kernel's half are global, see `krnl_global`.
+    /// 
+    /// The other CPUs' entries are batched, and shot down once unlocked, see `MapperGuard`.
+    unsafe fn invalidate_remote(&mut self, base: *mut u8, acme: *mut u8) {
+        self.remote.add(base, acme);
+    }
+
+    /// Returns the Page Table entry of `page`, creating branches with the flags
+    /// `branches` and splitting huge pages as necessary.
+    unsafe fn get_or_create_pte(&mut self, page: *mut u8, branches: PTE, pml4: *mut [PTE]) -> *mut PTE {
+        self.try_get_or_create_pte(page, branches, pml4).expect("Out of physical memory exception!")
+    }
+
+    /// Returns the Page Table entry of `page` as per `get_or_create_pte`, or `None`
+    /// if physical memory is exhausted, in which case some branches may have been created.
+    unsafe fn try_get_or_create_pte(&mut self, page: *mut u8, branches: PTE, pml4: *mut [PTE]) -> Option<*mut PTE> {
+        use paging::{PML4_LVL, PD_LVL, PT_LVL};
+
+        let mut table = pml4.as_mut_ptr();
+        for lvl in (PD_LVL..=PML4_LVL).rev() {
+            let pte = table.add(paging::table_index(page, lvl));
+            if !(*pte).contains(PTE::P) {
+                let table_paddr = self.try_alloc_phys(paging::PTE_SIZE, FrameOwner::PageTable)?;
+                from_phys_addr!(table_paddr, PTE).write_bytes(0, 512);
+                *pte = PTE::P | PTE::from_paddr(table_paddr) | branches;
+            } else if lvl != PML4_LVL && (*pte).contains(PTE::PS) {
+                let table_paddr = self.try_alloc_phys(paging::PTE_SIZE, FrameOwner::PageTable)?;
+                split_hpage(pte, lvl, &mut |_| table_paddr);
+            }
+            table = from_phys_addr!((*pte).get_paddr(), PTE);
+        }
+        Some(table.add(paging::table_index(page, PT_LVL)))
+    }
 }
 
 
@@ -478,18 +1334,18 @@ impl Mapping {
 
     /// Get an iterator over the addresses mapped by this mapping.
     /// 
-    /// Yeilds `(address, page table level, mapping entry)` in increasing order.
+    /// Yeilds `(address, page table level, mapping entry)` in increasing order,
+    /// skipping addresses without a table at the level, see `get_entry_offset`.
     /// ### Safety:
     /// * Physical addresses of the page tables must be offset-identity mapped.
     /// * Returned `address`es are not safe to dereference if the same pml4
     /// is not active.
     pub fn iter_entries<'a>(&'a self)
     -> impl 'a + Iterator<Item = (*mut u8, usize, *mut PTE)> {
-        IterMut::new(self).map(|(laddr, lvl)| (
-            laddr,
-            lvl,
-            unsafe { get_entry_offset(laddr, lvl, self.pml4) }
-        ))
+        IterMut::new(self).filter_map(|(laddr, lvl)| {
+            let entry = unsafe { get_entry_offset(laddr, lvl, self.pml4) }?;
+            Some((laddr, lvl, entry))
+        })
     }
 }
 
@@ -0,0 +1,161 @@
+//! Module for tracking reserved regions of virtual memory that are mapped lazily.
+
+use core::alloc::AllocError;
+
+use amd64::paging::{self, PTE};
+use spin::Mutex;
+
+use super::frames::FrameOwner;
+
+
+/// The maximum number of regions that can be reserved at once.
+pub const REGION_CAPACITY: usize = 64;
+/// How far below the committed span of a stack region an access may
+/// fault while still being considered growth of the stack.
+pub const STACK_GROWTH_WINDOW: usize = 16 * paging::PTE_SIZE;
+
+/// The manner in which a region's committed span grows.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegionKind {
+    /// The committed span grows upwards from the base by `RegionTable::commit`, e.g. heaps.
+    Anonymous,
+    /// The committed span grows downwards from the acme upon faults within
+    /// `STACK_GROWTH_WINDOW` below it. The lowest `guard` bytes are never backed.
+    Stack { guard: usize },
+}
+
+/// A reserved span of virtual memory, of which the committed
+/// portion is backed by physical memory upon first access.
+#[derive(Debug, Clone, Copy)]
+pub struct Region {
+    pub kind: RegionKind,
+    /// Base of the reserved span.
+    pub base: *mut u8,
+    /// Base of the committed span; pages above are backed when touched.
+    pub commit_base: *mut u8,
+    /// Acme of the committed span; pages below are backed when touched.
+    pub commit_acme: *mut u8,
+    /// Acme of the reserved span; the committed span cannot grow past this.
+    pub acme: *mut u8,
+    /// Flags of page table branches created when backing pages.
+    pub branches: PTE,
+    /// Flags of the leaves created when backing pages.
+    pub leaves: PTE,
+    /// Owner of the physical memory backing pages.
+    pub owner: FrameOwner,
+}
+
+impl Region {
+    /// Creates an anonymous region of which `base` through `commit_acme` is committed.
+    pub fn anonymous(base: *mut u8, commit_acme: *mut u8, acme: *mut u8,
+    branches: PTE, leaves: PTE, owner: FrameOwner) -> Self {
+        Self { kind: RegionKind::Anonymous, base, commit_base: base, commit_acme, acme, branches, leaves, owner }
+    }
+    /// Creates a stack region of which `commit_base` through `acme` is committed,
+    /// with a `guard` size span at its base that is never backed.
+    pub fn stack(base: *mut u8, commit_base: *mut u8, acme: *mut u8, guard: usize,
+    branches: PTE, leaves: PTE, owner: FrameOwner) -> Self {
+        Self { kind: RegionKind::Stack { guard }, base, commit_base, commit_acme: acme, acme, branches, leaves, owner }
+    }
+
+    #[inline]
+    pub fn contains(&self, laddr: *mut u8) -> bool {
+        (self.base as isize) <= (laddr as isize) && (laddr as isize) < (self.acme as isize)
+    }
+    #[inline]
+    pub fn is_committed(&self, laddr: *mut u8) -> bool {
+        (self.commit_base as isize) <= (laddr as isize) && (laddr as isize) < (self.commit_acme as isize)
+    }
+    /// Returns whether `laddr` is within the guard span of a stack region.
+    #[inline]
+    pub fn is_guard(&self, laddr: *mut u8) -> bool {
+        match self.kind {
+            RegionKind::Stack { guard } => self.contains(laddr)
+                && (laddr as isize) < (self.base as isize).wrapping_add(guard as isize),
+            RegionKind::Anonymous => false,
+        }
+    }
+}
+
+/// Table of the reserved regions of virtual memory.
+#[derive(Debug)]
+pub struct RegionTable {
+    regions: [Option<Region>; REGION_CAPACITY],
+}
+
+unsafe impl Send for RegionTable {}
+
+pub static REGIONS: Mutex<RegionTable> = Mutex::new(RegionTable { regions: [None; REGION_CAPACITY] });
+
+impl RegionTable {
+    /// Reserves `region`, returning `Err(())` if the table is full.
+    /// # Panics
+    /// Panics if the region overlaps an existing region.
+    pub fn reserve(&mut self, region: Region) -> Result<(), ()> {
+        assert!((region.base as isize) <= (region.commit_base as isize));
+        assert!((region.commit_base as isize) <= (region.commit_acme as isize));
+        assert!((region.commit_acme as isize) <= (region.acme as isize));
+        assert!(self.regions.iter().flatten().all(|r|
+            (r.acme as isize) <= (region.base as isize) || (region.acme as isize) <= (r.base as isize)
+        ), "region overlaps an existing region");
+
+        let slot = self.regions.iter_mut().find(|r| r.is_none()).ok_or(())?;
+        *slot = Some(region);
+        Ok(())
+    }
+
+    /// Removes and returns the region of the given base, if any.
+    /// 
+    /// Pages of the region that have been backed remain mapped.
+    pub fn release(&mut self, base: *mut u8) -> Option<Region> {
+        self.regions.iter_mut()
+            .find(|r| r.map_or(false, |r| r.base == base))
+            .and_then(|r| r.take())
+    }
+
+    /// Returns the region containing `laddr`, if any.
+    pub fn find(&self, laddr: *mut u8) -> Option<&Region> {
+        self.regions.iter().flatten().find(|r| r.contains(laddr))
+    }
+
+    /// Grows the committed span of the anonymous region of the given base up to `commit_acme`.
+    /// 
+    /// Returns `Err(AllocError)` if no such region exists,
+    /// or if `commit_acme` is beyond the reserved span.
+    pub fn commit(&mut self, base: *mut u8, commit_acme: *mut u8) -> Result<(), AllocError> {
+        let region = self.regions.iter_mut()
+            .flatten()
+            .find(|r| r.base == base && r.kind == RegionKind::Anonymous)
+            .ok_or(AllocError)?;
+
+        if (commit_acme as isize) > (region.acme as isize) {
+            return Err(AllocError);
+        }
+        if (commit_acme as isize) > (region.commit_acme as isize) {
+            region.commit_acme = commit_acme;
+        }
+        Ok(())
+    }
+
+    /// Grows the committed span of the stack region containing `laddr` down to the
+    /// page of `laddr`, if it is within `STACK_GROWTH_WINDOW` of the committed span
+    /// and not within the guard span.
+    /// 
+    /// Returns the updated region if the stack was grown.
+    pub fn grow_stack(&mut self, laddr: *mut u8) -> Option<Region> {
+        let region = self.regions.iter_mut()
+            .flatten()
+            .find(|r| r.contains(laddr) && matches!(r.kind, RegionKind::Stack { .. }))?;
+
+        let window_base = (region.commit_base as isize).wrapping_sub(STACK_GROWTH_WINDOW as isize);
+        if region.is_guard(laddr) || (laddr as isize) < window_base {
+            return None;
+        }
+
+        let page = (laddr as usize & !(paging::PTE_SIZE - 1)) as *mut u8;
+        if (page as isize) < (region.commit_base as isize) {
+            region.commit_base = page;
+        }
+        Some(*region)
+    }
+}
@@ -0,0 +1,156 @@
+//! Module for the layout of the per-CPU kernel stacks.
+//! 
+//! Each CPU is allotted a `PDPTE_SIZE` slot below `KRNL_STACK_ACME`, which is laid
+//! out from the top down as a guard, the kernel stack, a guard, then each interrupt
+//! stack followed by a guard. The rest of the slot, below the stacks, is the CPU's heap.
+//! 
+//! Only the top of the kernel stack is mapped upfront, the rest is backed upon page
+//! faults as the stack grows, see `RegionTable::grow_stack`. The interrupt stacks are
+//! mapped entirely, as page faults are handled on their own interrupt stack.
+
+use amd64::paging::{self, PTE};
+
+use crate::cfg;
+use super::{
+    MAPPER, KRNL_STACK_ACME,
+    frames::FrameOwner,
+    region::{Region, REGIONS},
+};
+
+
+/// The number of interrupt stacks of each CPU, see `ist_acme`.
+pub const IST_COUNT: usize = 5;
+/// The size of each interrupt stack.
+pub const IST_SIZE: usize = 4 * paging::PTE_SIZE;
+/// The Interrupt Stack Table index of the double fault stack.
+pub const DOUBLE_FAULT_IST: u8 = 1;
+/// The Interrupt Stack Table index of the non-maskable interrupt stack.
+pub const NMI_IST: u8 = 2;
+/// The Interrupt Stack Table index of the machine check stack.
+pub const MACHINE_CHECK_IST: u8 = 3;
+/// The Interrupt Stack Table index of the debug exception stack.
+pub const DEBUG_IST: u8 = 4;
+/// The Interrupt Stack Table index of the page fault stack, such that page faults
+/// upon the kernel stack's unbacked pages can be delivered and resolved.
+pub const PAGE_FAULT_IST: u8 = 5;
+
+/// The size of the top of the kernel stack that is mapped upfront.
+pub const KRNL_STACK_INIT_SIZE: usize = 16 * paging::PTE_SIZE;
+
+/// Returns the size of each guard, being `cfg::stack_guard_size` rounded up to whole pages.
+pub fn guard_size() -> usize {
+    (cfg::stack_guard_size() + paging::PTE_SIZE-1 & !(paging::PTE_SIZE-1)).max(paging::PTE_SIZE)
+}
+
+/// Returns the acme of the slot of `cpu`.
+#[inline]
+pub fn slot_acme(cpu: usize) -> usize {
+    KRNL_STACK_ACME - paging::PDPTE_SIZE * cpu
+}
+/// Returns the base of the slot of `cpu`, which is the base of its heap.
+#[inline]
+pub fn slot_base(cpu: usize) -> usize {
+    slot_acme(cpu) - paging::PDPTE_SIZE
+}
+/// Returns the size of the top of each slot that is used by stacks and their guards.
+pub fn stacks_size() -> usize {
+    guard_size() + cfg::stack_size() + guard_size() + IST_COUNT * (IST_SIZE + guard_size())
+}
+
+/// Returns the acme of the kernel stack of `cpu`.
+#[inline]
+pub fn krnl_stack_acme(cpu: usize) -> usize {
+    slot_acme(cpu) - guard_size()
+}
+/// Returns the acme of the interrupt stack of `cpu` at Interrupt Stack Table index `ist`.
+/// ### Panics:
+/// Panics if `ist` isn't within `1..=IST_COUNT`.
+pub fn ist_acme(cpu: usize, ist: u8) -> usize {
+    assert!(ist >= 1 && ist as usize <= IST_COUNT, "invalid IST index");
+    krnl_stack_acme(cpu) - cfg::stack_size() - guard_size() - (ist as usize - 1) * (IST_SIZE + guard_size())
+}
+
+/// Returns the CPU of the slot that contains `laddr` within the guard of one of its stacks.
+pub fn guard_owner(laddr: *const u8) -> Option<usize> {
+    let laddr = laddr as usize;
+    if laddr >= KRNL_STACK_ACME || laddr < paging::HIGHER_HALF as usize {
+        return None;
+    }
+
+    let cpu = (KRNL_STACK_ACME - 1 - laddr) / paging::PDPTE_SIZE;
+    let offset = slot_acme(cpu) - laddr;
+    if offset > stacks_size() {
+        return None;
+    }
+
+    // the offset from the slot acme is in a guard unless it's in a stack
+    let krnl_stack = guard_size()..guard_size() + cfg::stack_size();
+    let is_in_stack = krnl_stack.contains(&(offset - 1)) || (1..=IST_COUNT as u8).any(|ist| {
+        let acme_offset = slot_acme(cpu) - ist_acme(cpu, ist);
+        (acme_offset..acme_offset + IST_SIZE).contains(&(offset - 1))
+    });
+    (!is_in_stack).then(|| cpu)
+}
+
+/// Backs up to `size` bytes of the executing kernel stack below the stack pointer,
+/// such that the stack needn't grow while locks required to grow it are held,
+/// e.g. `MAPPER`. Does nothing if another stack is executing.
+#[inline(never)]
+pub fn reserve(size: usize) {
+    let rsp: usize;
+    // SAFETY: reading the stack pointer has no side effects
+    unsafe { core::arch::asm!("mov {}, rsp", out(reg) rsp, options(nomem, nostack, preserves_flags)); }
+    if rsp >= KRNL_STACK_ACME || rsp < paging::HIGHER_HALF as usize {
+        return;
+    }
+
+    let cpu = (KRNL_STACK_ACME - 1 - rsp) / paging::PDPTE_SIZE;
+    let acme = krnl_stack_acme(cpu);
+    let base = acme - cfg::stack_size();
+    if rsp <= base || rsp > acme {
+        return;
+    }
+
+    // each page is within the growth window of the page above
+    let lowest = rsp.saturating_sub(size).max(base);
+    let mut page = rsp & !(paging::PTE_SIZE-1);
+    while page >= lowest {
+        // SAFETY: the page is within the kernel stack's region, hence is backed upon fault
+        unsafe { (page as *const u8).read_volatile(); }
+        page -= paging::PTE_SIZE;
+    }
+}
+
+/// Maps the top of the kernel stack and the interrupt stacks of `cpu`, reserving
+/// them as stack regions, and ensures their guards are unmapped.
+/// ### Safety:
+/// * Must only be called once per CPU, after `Mapper::setup` and `cfg::init_boot_cfg`.
+/// * `pml4` must be the kernel's PML4.
+pub unsafe fn map_stacks(cpu: usize, pml4: *mut [PTE]) {
+    let guard = guard_size();
+    let stacks = core::iter::once((krnl_stack_acme(cpu), cfg::stack_size()))
+        .chain((1..=IST_COUNT as u8).map(|ist| (ist_acme(cpu, ist), IST_SIZE)));
+
+    let mut mapper = MAPPER.lock();
+    for (i, (acme, size)) in stacks.enumerate() {
+        let base = acme - size;
+        // the kernel stack grows down from its top, see `RegionTable::grow_stack`
+        let commit_base = if i == 0 { acme - size.min(KRNL_STACK_INIT_SIZE) } else { base };
+        mapper.map(commit_base as *mut u8, acme - commit_base, PTE::RW, PTE::RW, FrameOwner::Kernel, pml4);
+
+        REGIONS.lock().reserve(Region::stack(
+            (base - guard) as *mut u8,
+            commit_base as *mut u8,
+            acme as *mut u8,
+            guard,
+            PTE::RW,
+            PTE::RW,
+            FrameOwner::Kernel,
+        )).expect("Stack region reservation failed.");
+
+        // the guards must never be backed, lest an overflow go unnoticed
+        for page in (base - guard..base).chain(acme..acme + guard).step_by(paging::PTE_SIZE) {
+            assert!(super::translate(page as *mut u8, pml4).is_none(), "stack guard page is mapped");
+        }
+    }
+}
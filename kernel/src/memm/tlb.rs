@@ -0,0 +1,218 @@
+//! Module for keeping the TLBs of the CPUs coherent with the page tables.
+//! 
+//! Modifying the page tables invalidates the executing CPU's TLB entries as
+//! the modifications are made. Those of the other CPUs are invalidated by a
+//! shootdown: the modified ranges are batched, then each CPU that has `join`ed
+//! is interrupted to invalidate them, being waited upon to acknowledge.
+//! 
+//! The `Mapper` batches the ranges it modifies, which are shot down once `MAPPER`
+//! is unlocked, see `MapperGuard`.
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use amd64::{interrupts, paging};
+use spin::{Mutex, Once};
+
+use crate::{
+    interrupts::{TrapFrame, ipi, irq::{self, IrqStatus}},
+    percpu,
+    smp,
+};
+
+
+/// The maximum number of ranges in a batch, beyond which TLBs are flushed entirely.
+pub const MAX_RANGES: usize = 8;
+/// The number of pages in a batch beyond which TLBs are flushed entirely.
+pub const FULL_FLUSH_PAGES: usize = 32;
+
+/// The vector of shootdown IPIs.
+static VECTOR: Once<u8> = Once::new();
+/// The CPUs that take part in shootdowns, indexed by bit.
+static MEMBERS: AtomicU64 = AtomicU64::new(0);
+/// The CPUs yet to acknowledge the current shootdown, indexed by bit.
+static PENDING: AtomicU64 = AtomicU64::new(0);
+/// The batch of the current shootdown.
+static REQUEST: Mutex<TlbBatch> = Mutex::new(TlbBatch::new());
+/// Held by the initiator of the current shootdown.
+static SHOOTDOWN_LOCK: Mutex<()> = Mutex::new(());
+
+/// A batch of linear address ranges of which TLB entries are to be invalidated.
+#[derive(Debug, Clone, Copy)]
+pub struct TlbBatch {
+    /// Page-aligned `(base, acme)` ranges.
+    ranges: [(usize, usize); MAX_RANGES],
+    len: usize,
+    pages: usize,
+    /// Whether TLBs are to be flushed entirely, rather than by range.
+    is_full: bool,
+}
+
+impl TlbBatch {
+    pub const fn new() -> Self {
+        Self { ranges: [(0, 0); MAX_RANGES], len: 0, pages: 0, is_full: false }
+    }
+
+    /// Returns whether the batch invalidates nothing.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0 && !self.is_full
+    }
+
+    /// Adds the pages of `base` through `acme` to the batch, merging the range with
+    /// an adjoining range. The batch becomes a full flush once it spans more than
+    /// `FULL_FLUSH_PAGES`, or more than `MAX_RANGES` ranges.
+    pub fn add(&mut self, base: *mut u8, acme: *mut u8) {
+        let base = base as usize & !(paging::PTE_SIZE - 1);
+        let acme = (acme as usize).wrapping_add(paging::PTE_SIZE - 1) & !(paging::PTE_SIZE - 1);
+        let pages = acme.wrapping_sub(base) / paging::PTE_SIZE;
+        if self.is_full || pages == 0 {
+            return;
+        }
+
+        self.pages += pages;
+        if self.pages > FULL_FLUSH_PAGES {
+            return self.add_all();
+        }
+
+        if let Some(range) = self.ranges[..self.len].iter_mut().find(|range| range.1 == base) {
+            range.1 = acme;
+        } else if let Some(range) = self.ranges[..self.len].iter_mut().find(|range| range.0 == acme) {
+            range.0 = base;
+        } else if self.len == MAX_RANGES {
+            self.add_all();
+        } else {
+            self.ranges[self.len] = (base, acme);
+            self.len += 1;
+        }
+    }
+
+    /// Makes the batch a full flush.
+    pub fn add_all(&mut self) {
+        self.is_full = true;
+        self.len = 0;
+    }
+
+    /// Invalidates the batch in the TLBs of the other CPUs that have joined,
+    /// returning once each has done so. The executing CPU's TLB is unaffected.
+    /// 
+    /// Must not be called with interrupts disabled while another CPU may be
+    /// waiting for this one to acknowledge a shootdown, e.g. upon a lock, bar
+    /// `MAPPER`, of which waiters acknowledge shootdowns.
+    pub fn shootdown(&self) {
+        if self.is_empty() || VECTOR.get().is_none() {
+            return;
+        }
+        // CPUs yet to set up their block are yet to join
+        let cpu = percpu::try_this_cpu().map(|this| this.cpu());
+        let targets = MEMBERS.load(Ordering::SeqCst) & !cpu.map_or(0, |cpu| 1 << cpu);
+        if targets == 0 {
+            return;
+        }
+
+        let _shootdown = loop {
+            if let Some(lock) = SHOOTDOWN_LOCK.try_lock() {
+                break lock;
+            }
+            // the initiator may be waiting upon this CPU
+            acknowledge();
+            core::hint::spin_loop();
+        };
+
+        *REQUEST.lock() = *self;
+        PENDING.store(targets, Ordering::SeqCst);
+
+        let vector = *VECTOR.get().unwrap();
+        for target in (0..smp::MAX_CPUS).filter(|target| targets & 1 << target != 0) {
+            // SAFETY: the vector is registered by `init`
+            if unsafe { ipi::send(target, vector) }.is_err() {
+                PENDING.fetch_and(!(1 << target), Ordering::SeqCst);
+            }
+        }
+
+        // CPUs that `leave` meanwhile can't acknowledge
+        while PENDING.load(Ordering::Acquire) & MEMBERS.load(Ordering::Acquire) != 0 {
+            core::hint::spin_loop();
+        }
+    }
+
+    /// Invalidates the batch in the executing CPU's TLB.
+    pub fn invalidate(&self) {
+        if self.is_full {
+            paging::flush_global();
+            return;
+        }
+
+        for &(base, acme) in &self.ranges[..self.len] {
+            let mut page = base;
+            while page != acme {
+                paging::invlpg(page as *mut u8);
+                page = page.wrapping_add(paging::PTE_SIZE);
+            }
+        }
+    }
+}
+
+/// Invalidates `base` through `acme` in the TLBs of the other CPUs,
+/// see `TlbBatch::shootdown`.
+pub fn shootdown(base: *mut u8, acme: *mut u8) {
+    let mut batch = TlbBatch::new();
+    batch.add(base, acme);
+    batch.shootdown();
+}
+
+
+/// Registers the shootdown IPI vector, before which shootdowns are skipped.
+/// ### Safety:
+/// Must be called by the BSP before other CPUs `join`.
+pub unsafe fn init() {
+    VECTOR.call_once(|| {
+        irq::register_irq(None, shootdown_interrupt, core::ptr::null_mut())
+            .expect("TLB shootdown vector registration failed.")
+    });
+}
+
+/// Includes the executing CPU in shootdowns, flushing its TLB of entries
+/// made stale beforehand.
+/// ### Safety:
+/// Must be called once per CPU, after `percpu::init`. Interrupts must be enabled
+/// thereafter, such that shootdowns are acknowledged.
+pub unsafe fn join() {
+    let cpu = percpu::this_cpu().cpu();
+    MEMBERS.fetch_or(1 << cpu, Ordering::SeqCst);
+    paging::flush_global();
+}
+
+/// Excludes the executing CPU from shootdowns, e.g. upon being frozen,
+/// after which its TLB may be stale.
+pub(crate) fn leave() {
+    if let Some(this) = percpu::try_this_cpu() {
+        MEMBERS.fetch_and(!(1 << this.cpu()), Ordering::SeqCst);
+        PENDING.fetch_and(!(1 << this.cpu()), Ordering::SeqCst);
+    }
+}
+
+/// Invalidates the current shootdown's batch if the executing CPU is yet to,
+/// acknowledging it, e.g. while waiting upon a lock with interrupts disabled.
+pub(crate) fn acknowledge() {
+    // CPUs yet to set up their block are yet to join
+    let cpu = match percpu::try_this_cpu() {
+        Some(this) => this.cpu(),
+        None => return,
+    };
+    if PENDING.load(Ordering::Acquire) & 1 << cpu == 0 {
+        return;
+    }
+
+    // the shootdown IPI mustn't interrupt this CPU while it holds the REQUEST lock
+    interrupts::without_interrupts(|| {
+        if PENDING.load(Ordering::Acquire) & 1 << cpu != 0 {
+            let batch = *REQUEST.lock();
+            batch.invalidate();
+            PENDING.fetch_and(!(1 << cpu), Ordering::Release);
+        }
+    });
+}
+
+fn shootdown_interrupt(_: &mut TrapFrame, _: *mut ()) -> IrqStatus {
+    acknowledge();
+    IrqStatus::Handled
+}
@@ -16,6 +16,20 @@ macro_rules! println {
     ($($arg:tt)*) => ($crate::print!("\n{}", format_args!($($arg)*)));
 }
 
+/// Prints as per `print!`, unless the output is locked, in which case the
+/// output is dropped, e.g. such that NMI handlers can't deadlock.
+#[macro_export]
+macro_rules! try_print {
+    ($($arg:tt)*) => ($crate::out::__try_print(format_args!($($arg)*)));
+}
+
+/// Prints as per `println!`, unless the output is locked, see `try_print!`.
+#[macro_export]
+macro_rules! try_println {
+    () => ($crate::try_print!("\n"));
+    ($($arg:tt)*) => ($crate::try_print!("\n{}", format_args!($($arg)*)));
+}
+
 #[doc(hidden)]
 pub fn __print(args: core::fmt::Arguments) {
     use core::fmt::Write;
@@ -28,3 +42,15 @@ pub fn __print(args: core::fmt::Arguments) {
     // fixme: framebuffer output
     terminal::TERM1.lock().write_fmt(args).unwrap();
 }
+
+#[doc(hidden)]
+pub fn __try_print(args: core::fmt::Arguments) {
+    use core::fmt::Write;
+
+    if let Some(mut lock) = uart::UART_COM1.0.try_lock() {
+        lock.write_fmt(args).unwrap();
+    }
+    if let Some(mut lock) = terminal::TERM1.try_lock() {
+        lock.write_fmt(args).unwrap();
+    }
+}
@@ -0,0 +1,205 @@
+//! Module for the data block of each CPU, addressed through the GS base.
+//! 
+//! While in the kernel, the GS base addresses the executing CPU's `PerCpu`, and the
+//! `KERNEL_GS_BASE` MSR holds the user's GS base; the two are exchanged by `swapgs`
+//! upon entering from and returning to user mode, see `sys::interrupts`.
+//! 
+//! Until `init` is called, the GS base addresses a placeholder block, see `init_boot`.
+
+use core::{arch::asm, ptr, sync::atomic::{AtomicPtr, AtomicUsize, Ordering}};
+
+use alloc::boxed::Box;
+use amd64::{
+    interrupts::IDT,
+    registers::{self, CR4},
+    segmentation::{self, TaskStateSeg},
+};
+use spin::Lazy;
+
+use crate::{interrupts::lapic, memm::talloc::Tallock};
+
+
+/// Whether `wrgsbase` is supported, else the GS base MSR is written.
+static FSGSBASE_SUPPORT: Lazy<bool> = Lazy::new(|| {
+    raw_cpuid::CpuId::new().get_extended_feature_info().map_or(false, |info| info.has_fsgsbase())
+});
+
+/// Addressed by CPUs that haven't called `init`, such that interrupts can be accounted.
+static BOOT_BLOCK: PerCpu = PerCpu {
+    this: ptr::null(),
+    irq_depth: AtomicUsize::new(0),
+    cpu: 0,
+    apic_id: 0,
+    talloc: ptr::null(),
+    gdt: ptr::slice_from_raw_parts_mut(ptr::null_mut(), 0),
+    idt: ptr::null_mut(),
+    tss: ptr::null_mut(),
+    current_thread: AtomicPtr::new(ptr::null_mut()),
+};
+
+/// The data of a CPU, only ever accessed by that CPU, see `this_cpu`.
+/// 
+/// The field offsets are relied upon by the assembly.
+#[repr(C)]
+pub struct PerCpu {
+    /// The block itself, such that it's read from `gs:[0]`. Null in the `BOOT_BLOCK`.
+    this: *const PerCpu,
+    /// At `gs:[8]`, see `irq_enter`.
+    irq_depth: AtomicUsize,
+    cpu: usize,
+    apic_id: u32,
+    talloc: *const Tallock,
+    gdt: *mut [u64],
+    idt: *mut IDT,
+    tss: *mut TaskStateSeg,
+    current_thread: AtomicPtr<()>,
+}
+
+// SAFETY: blocks are only accessed by their own CPU, bar the BOOT_BLOCK, which is shared
+// but only modified through `irq_depth`, with locked instructions, see `irq_enter`
+unsafe impl Send for PerCpu {}
+unsafe impl Sync for PerCpu {}
+
+impl PerCpu {
+    /// Returns the index of the CPU, see `sys::smp`.
+    pub fn cpu(&self) -> usize {
+        self.cpu
+    }
+    /// Returns the local APIC ID of the CPU, or zero if there's no local APIC.
+    pub fn apic_id(&self) -> u32 {
+        self.apic_id
+    }
+    /// Returns the CPU's heap allocator.
+    pub fn talloc(&self) -> &'static Tallock {
+        // SAFETY: the allocator is leaked by `init`
+        unsafe { &*self.talloc }
+    }
+    /// Returns the CPU's loaded GDT.
+    pub fn gdt(&self) -> *mut [u64] {
+        self.gdt
+    }
+    /// Returns the CPU's loaded IDT.
+    pub fn idt(&self) -> *mut IDT {
+        self.idt
+    }
+    /// Returns the CPU's loaded TSS, e.g. to set the stack entered from user mode.
+    pub fn tss(&self) -> *mut TaskStateSeg {
+        self.tss
+    }
+
+    /// Returns the thread executing on the CPU, or null if there's none.
+    pub fn current_thread(&self) -> *mut () {
+        self.current_thread.load(Ordering::Relaxed)
+    }
+    /// Sets the thread executing on the CPU.
+    pub fn set_current_thread(&self, thread: *mut ()) {
+        self.current_thread.store(thread, Ordering::Relaxed);
+    }
+
+    /// Returns the number of interrupts the CPU is nested within.
+    pub fn irq_depth(&self) -> usize {
+        self.irq_depth.load(Ordering::Relaxed)
+    }
+}
+
+
+/// Returns the data block of the executing CPU.
+/// ### Panics:
+/// Panics if `init` hasn't been called by the executing CPU.
+pub fn this_cpu() -> &'static PerCpu {
+    try_this_cpu().expect("Per-CPU block is not initialized.")
+}
+
+/// Returns the data block of the executing CPU, or `None` if `init` hasn't been called by it.
+pub fn try_this_cpu() -> Option<&'static PerCpu> {
+    let this: *const PerCpu;
+    // SAFETY: the GS base always addresses a block, see `init_boot`
+    unsafe {
+        asm!("mov {}, gs:[0]", out(reg) this, options(nostack, readonly, preserves_flags));
+        this.as_ref()
+    }
+}
+
+/// Returns whether the executing CPU is servicing an interrupt.
+pub fn in_interrupt() -> bool {
+    irq_depth() != 0
+}
+
+/// Returns the number of interrupts the executing CPU is nested within.
+/// 
+/// Unlike `PerCpu::irq_depth`, this may be called before `init`, though the count
+/// is then shared with the other CPUs yet to call `init`, see `irq_enter`.
+pub fn irq_depth() -> usize {
+    let depth: usize;
+    // SAFETY: the GS base always addresses a block, see `init_boot`
+    unsafe {
+        asm!("mov {}, gs:[8]", out(reg) depth, options(nostack, readonly, preserves_flags));
+    }
+    depth
+}
+
+/// Accounts entry into an interrupt, see `irq_exit`.
+/// 
+/// The count is modified atomically, as the `BOOT_BLOCK` is shared by CPUs that
+/// haven't called `init`, such that its count is the sum of theirs.
+pub(crate) fn irq_enter() {
+    // SAFETY: the GS base always addresses a block, see `init_boot`
+    unsafe { asm!("lock inc qword ptr gs:[8]", options(nostack)); }
+}
+
+/// Accounts return from an interrupt, see `irq_enter`.
+pub(crate) fn irq_exit() {
+    // SAFETY: the GS base always addresses a block, see `init_boot`
+    unsafe { asm!("lock dec qword ptr gs:[8]", options(nostack)); }
+}
+
+
+/// Points the GS base of the executing CPU at a placeholder block, shared by
+/// all CPUs that haven't yet called `init`.
+/// ### Safety:
+/// Must be called by each CPU upon starting, before interrupts may be raised.
+pub unsafe fn init_boot() {
+    if *FSGSBASE_SUPPORT {
+        CR4::write(CR4::read() | CR4::FSGSBASE);
+    }
+    write_gs_base(&BOOT_BLOCK as *const _ as u64);
+    registers::wrmsr(segmentation::KERNEL_GS_BASE as u64, 0);
+}
+
+/// Sets up the data block of the executing CPU, which holds its system tables
+/// and heap allocator from then on, and points the GS base at it.
+/// ### Safety:
+/// Must be called once per CPU, after `init_boot`, `lapic::init` if supported, and
+/// loading the given tables. `cpu` must be the executing CPU's index.
+pub unsafe fn init(
+    cpu: usize,
+    talloc: &'static Tallock,
+    gdt: Box<[u64], &'static Tallock>,
+    idt: Box<IDT, &'static Tallock>,
+    tss: Box<TaskStateSeg, &'static Tallock>,
+) {
+    let apic_id = if lapic::is_supported() { lapic::local_apic().id() } else { 0 };
+
+    let block = Box::leak(Box::new_in(PerCpu {
+        this: ptr::null(),
+        irq_depth: AtomicUsize::new(0),
+        cpu,
+        apic_id,
+        talloc,
+        gdt: Box::leak(gdt),
+        idt: Box::leak(idt),
+        tss: Box::leak(tss),
+        current_thread: AtomicPtr::new(ptr::null_mut()),
+    }, talloc));
+    block.this = block;
+
+    write_gs_base(block as *const _ as u64);
+}
+
+unsafe fn write_gs_base(base: u64) {
+    if *FSGSBASE_SUPPORT {
+        segmentation::wrgsbase(base);
+    } else {
+        registers::wrmsr(segmentation::GS_BASE as u64, base);
+    }
+}
@@ -0,0 +1,207 @@
+//! Module for powering off and resetting the machine.
+//! 
+//! ACPI is used where available: the machine is reset through the FADT's reset
+//! register, and powered off by entering the S5 sleep state through the PM1 control
+//! blocks, as per the `\_S5` package of the DSDT or SSDTs. Resetting falls back to
+//! the keyboard controller, and lastly to a triple fault.
+
+use core::ptr;
+
+use ::acpi::fadt::Fadt;
+use amd64::{interrupts, ports};
+
+use crate::acpi;
+
+
+/// SCI_EN: Set once the ACPI hardware registers are controlled by the OS, PM1 control.
+const PM1_SCI_EN: u64 = 1 << 0;
+/// SLP_TYP: The sleep type to enter upon setting SLP_EN, PM1 control.
+const PM1_SLP_TYP_SHIFT: u64 = 10;
+const PM1_SLP_TYP_MASK: u64 = 0b111 << PM1_SLP_TYP_SHIFT;
+/// SLP_EN: Enters the sleep type when set, PM1 control.
+const PM1_SLP_EN: u64 = 1 << 13;
+/// SLP_TYP: The sleep type to enter upon setting SLP_EN, hardware-reduced sleep control.
+const SLEEP_CONTROL_SLP_TYP_SHIFT: u64 = 2;
+/// SLP_EN: Enters the sleep type when set, hardware-reduced sleep control.
+const SLEEP_CONTROL_SLP_EN: u64 = 1 << 5;
+
+/// The 8042 keyboard controller's status and command port.
+const KBC_COMMAND_PORT: u16 = 0x64;
+/// Status flag set while the controller's input buffer is full.
+const KBC_INPUT_FULL: u8 = 1 << 1;
+/// Pulses the reset line of the processor.
+const KBC_PULSE_RESET: u8 = 0xFE;
+
+/// The number of spins to wait for each method to take effect before falling back.
+const FALLBACK_SPINS: usize = 1 << 24;
+
+// AML encoding, see the ACPI specification's AML grammar
+const AML_ZERO_OP: u8 = 0x00;
+const AML_ONE_OP: u8 = 0x01;
+const AML_NAME_OP: u8 = 0x08;
+const AML_BYTE_PREFIX: u8 = 0x0A;
+const AML_WORD_PREFIX: u8 = 0x0B;
+const AML_DWORD_PREFIX: u8 = 0x0C;
+const AML_PACKAGE_OP: u8 = 0x12;
+const AML_ROOT_CHAR: u8 = b'\\';
+
+
+/// Powers off the machine, halting if it can't be.
+pub fn shutdown() -> ! {
+    interrupts::cli();
+
+    if let Some(fadt) = acpi::fadt() {
+        // SAFETY: the FADT describes the PM1 control blocks, the machine is powered off
+        if unsafe { enter_s5(&fadt) }.is_ok() {
+            spin(FALLBACK_SPINS);
+        }
+    }
+
+    crate::println!("Shutdown failed, halting.");
+    amd64::hlt_loop()
+}
+
+/// Resets the machine.
+pub fn reboot() -> ! {
+    interrupts::cli();
+
+    if let Some(fadt) = acpi::fadt() {
+        // SAFETY: the FADT describes the reset register, the machine is reset
+        if unsafe { acpi_reset(&fadt) }.is_ok() {
+            spin(FALLBACK_SPINS);
+        }
+    }
+
+    // SAFETY: the machine is reset
+    unsafe {
+        kbc_reset();
+        spin(FALLBACK_SPINS);
+
+        triple_fault()
+    }
+}
+
+
+fn spin(count: usize) {
+    for _ in 0..count {
+        core::hint::spin_loop();
+    }
+}
+
+/// Writes the reset value to the FADT's reset register, if supported.
+unsafe fn acpi_reset(fadt: &Fadt) -> Result<(), ()> {
+    if !{ fadt.flags }.supports_system_reset_via_fadt() {
+        return Err(());
+    }
+    let reset_reg = fadt.reset_register().map_err(|_| ())?;
+    acpi::write_gas(&reset_reg, fadt.reset_value as u64)
+}
+
+/// Pulses the reset line through the keyboard controller.
+unsafe fn kbc_reset() {
+    for _ in 0..FALLBACK_SPINS {
+        if ports::in8(KBC_COMMAND_PORT) & KBC_INPUT_FULL == 0 {
+            break;
+        }
+        core::hint::spin_loop();
+    }
+    ports::out8(KBC_COMMAND_PORT, KBC_PULSE_RESET);
+}
+
+/// Resets the processor by raising an exception without a valid IDT.
+unsafe fn triple_fault() -> ! {
+    interrupts::lidt_raw(0, ptr::null());
+    core::arch::asm!("int3", options(noreturn));
+}
+
+/// Enters the S5 sleep state, being soft off.
+unsafe fn enter_s5(fadt: &Fadt) -> Result<(), ()> {
+    let (slp_typ_a, slp_typ_b) = s5_sleep_types().ok_or(())?;
+
+    if { fadt.flags }.system_is_hw_reduced_acpi() {
+        let sleep_control = fadt.sleep_control_register().map_err(|_| ())?.ok_or(())?;
+        return acpi::write_gas(&sleep_control,
+            (slp_typ_a as u64) << SLEEP_CONTROL_SLP_TYP_SHIFT | SLEEP_CONTROL_SLP_EN);
+    }
+
+    let pm1a_control = fadt.pm1a_control_block().map_err(|_| ())?;
+    let pm1b_control = fadt.pm1b_control_block().map_err(|_| ())?;
+
+    // the firmware controls the registers until ACPI mode is enabled
+    if acpi::read_gas(&pm1a_control)? & PM1_SCI_EN == 0 {
+        if fadt.smi_cmd_port == 0 || fadt.acpi_enable == 0 {
+            return Err(());
+        }
+        ports::out8(fadt.smi_cmd_port as u16, fadt.acpi_enable);
+
+        let mut spins = 0;
+        while acpi::read_gas(&pm1a_control)? & PM1_SCI_EN == 0 {
+            spins += 1;
+            if spins == FALLBACK_SPINS {
+                return Err(());
+            }
+            core::hint::spin_loop();
+        }
+    }
+
+    let pm1a = acpi::read_gas(&pm1a_control)? & !PM1_SLP_TYP_MASK;
+    acpi::write_gas(&pm1a_control, pm1a | (slp_typ_a as u64) << PM1_SLP_TYP_SHIFT | PM1_SLP_EN)?;
+    if let Some(pm1b_control) = pm1b_control {
+        let pm1b = acpi::read_gas(&pm1b_control)? & !PM1_SLP_TYP_MASK;
+        acpi::write_gas(&pm1b_control, pm1b | (slp_typ_b as u64) << PM1_SLP_TYP_SHIFT | PM1_SLP_EN)?;
+    }
+    Ok(())
+}
+
+/// Returns the SLP_TYPa and SLP_TYPb values of the S5 sleep state,
+/// as per the `\_S5` package of the DSDT or else of an SSDT.
+fn s5_sleep_types() -> Option<(u8, u8)> {
+    acpi::dsdt().into_iter()
+        .chain(acpi::ssdts())
+        .find_map(|table| find_s5(acpi::aml_bytes(table)))
+}
+
+/// Finds the named `_S5_` package in `aml`, returning its first two elements.
+/// 
+/// Rather than interpreting the AML, this relies on the package being defined
+/// by a name object with constant elements, which is the convention.
+fn find_s5(aml: &[u8]) -> Option<(u8, u8)> {
+    aml.windows(4)
+        .enumerate()
+        .filter(|&(_, name)| name == b"_S5_")
+        .find_map(|(i, _)| {
+            // the name object may be scoped from the root
+            let is_named = match i {
+                0 => false,
+                1 => aml[0] == AML_NAME_OP,
+                _ => aml[i-1] == AML_NAME_OP || aml[i-1] == AML_ROOT_CHAR && aml[i-2] == AML_NAME_OP,
+            };
+            if !is_named || *aml.get(i + 4)? != AML_PACKAGE_OP {
+                return None;
+            }
+
+            // the lead byte's top two bits count the following bytes of the package length
+            let mut j = i + 5;
+            j += 1 + (*aml.get(j)? >> 6) as usize;
+            // skip the element count
+            j += 1;
+
+            let slp_typ_a = aml_integer(aml, &mut j)?;
+            let slp_typ_b = aml_integer(aml, &mut j)?;
+            Some((slp_typ_a, slp_typ_b))
+        })
+}
+
+/// Parses the constant integer at `aml[*j]` and advances past it, returning its low byte.
+fn aml_integer(aml: &[u8], j: &mut usize) -> Option<u8> {
+    let (value, len) = match *aml.get(*j)? {
+        AML_ZERO_OP => (0, 1),
+        AML_ONE_OP => (1, 1),
+        AML_BYTE_PREFIX => (*aml.get(*j + 1)?, 2),
+        AML_WORD_PREFIX => (*aml.get(*j + 1)?, 3),
+        AML_DWORD_PREFIX => (*aml.get(*j + 1)?, 5),
+        _ => return None,
+    };
+    *j += len;
+    Some(value)
+}
@@ -0,0 +1,451 @@
+//! Module for the enumeration and startup of the processors.
+//! 
+//! CPUs are enumerated from the MADT and indexed from zero, being the BSP. The BSP
+//! starts each application processor (AP) through INIT-SIPI-SIPI into a real-mode
+//! trampoline in low memory, which enters long mode, switches onto the AP's kernel
+//! stack and the kernel's PML4, then calls `ap_entry`.
+//! 
+//! An online AP may take itself `offline`, idling until brought back `online`.
+
+use core::{ptr, sync::atomic::{AtomicBool, AtomicU32, AtomicU8, AtomicUsize, Ordering}, time::Duration};
+
+use acpi::platform::ProcessorState;
+use amd64::{
+    apic::ApicMode,
+    interrupts,
+    paging::{self, PTE},
+    ports,
+    registers::{CR0, CR4, EFER},
+};
+use spin::{Mutex, Once};
+
+use crate::{
+    interrupts::{TrapFrame, ipi, irq::{self, IrqStatus}, lapic},
+    memm::{self, MAPPER, frames::FrameOwner},
+};
+
+
+/// The maximum number of CPUs supported. CPUs beyond are left offline.
+pub const MAX_CPUS: usize = 64;
+
+/// The offset of the `TrampolineData` within the trampoline's page.
+const TRAMPOLINE_DATA_OFFSET: usize = 0xF00;
+/// The trampoline's code and data page, followed by its PML4 and identity-mapping PDPT.
+const TRAMPOLINE_SIZE: usize = 3 * paging::PTE_SIZE;
+
+/// The number of microseconds to wait after the INIT IPI.
+const INIT_DELAY_US: usize = 10_000;
+/// The number of microseconds to wait after each startup IPI.
+const STARTUP_DELAY_US: usize = 200;
+/// The number of microseconds to wait for a started AP to come online.
+const ONLINE_TIMEOUT_US: usize = 1_000_000;
+
+/// The state of a CPU.
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CpuState {
+    /// The CPU hasn't been started, or failed to start.
+    Offline,
+    /// The CPU has been sent startup IPIs, but isn't yet online.
+    Starting,
+    Online,
+    /// The CPU has taken itself offline, see `offline`.
+    Parked,
+}
+
+/// The entry of APs, called with the AP's index on its kernel stack, with interrupts disabled.
+pub type ApEntry = extern "sysv64" fn(cpu: usize) -> !;
+
+/// The startup parameters read by the trampoline and `ap_long_mode_entry`.
+/// 
+/// The field offsets are relied upon by the assembly.
+#[repr(C)]
+struct TrampolineData {
+    /// The physical address of the trampoline's PML4, which must be below 4GiB.
+    pml4: u64,
+    efer: u64,
+    cr0: u64,
+    /// The kernel's PML4.
+    cr3: u64,
+    cr4: u64,
+    stack: u64,
+    entry: u64,
+    cpu: u64,
+}
+
+const ZERO_ID: AtomicU32 = AtomicU32::new(0);
+const OFFLINE: AtomicU8 = AtomicU8::new(CpuState::Offline as u8);
+const NOT_STARTED: AtomicBool = AtomicBool::new(false);
+
+static CPU_COUNT: AtomicUsize = AtomicUsize::new(1);
+/// The local APIC ID of each CPU.
+static APIC_IDS: [AtomicU32; MAX_CPUS] = [ZERO_ID; MAX_CPUS];
+static STATES: [AtomicU8; MAX_CPUS] = [OFFLINE; MAX_CPUS];
+/// Whether each CPU's stacks have been mapped, upon first being started.
+static STARTED: [AtomicBool; MAX_CPUS] = [NOT_STARTED; MAX_CPUS];
+
+/// The physical address of the trampoline.
+static TRAMPOLINE: Once<usize> = Once::new();
+/// The vector of the IPI that wakes parked CPUs.
+static WAKE_VECTOR: Once<u8> = Once::new();
+static AP_ENTRY: Once<ApEntry> = Once::new();
+/// Serializes startups, which share the trampoline.
+static STARTUP_LOCK: Mutex<()> = Mutex::new(());
+
+
+extern "C" {
+    /// The base of the real-mode trampoline, which is copied to low memory.
+    fn ap_trampoline();
+    /// The acme of the real-mode trampoline.
+    fn ap_trampoline_end();
+}
+
+// The SIPI starts the AP at CS:IP = page << 8 : 0, hence the trampoline addresses itself and
+// its data through DS = CS, and keeps its physical base in EBX. The GDT pointer and far jump
+// target are relative to the trampoline, and are relocated by adding the base.
+// Paging is enabled through the trampoline's PML4, which identity maps the trampoline and
+// shares the kernel's higher half, such that `ap_long_mode_entry` can switch to the kernel's.
+core::arch::global_asm!("
+.pushsection .rodata.ap_trampoline, \"a\"
+.global ap_trampoline
+.global ap_trampoline_end
+.code16
+ap_trampoline:
+    cli
+    cld
+    mov ax, cs
+    mov ds, ax
+    xor ebx, ebx
+    mov bx, ax
+    shl ebx, 4
+
+    add dword ptr [TRAMPOLINE_GDTR + 2], ebx
+    add dword ptr [TRAMPOLINE_FAR_PTR], ebx
+    lgdt [TRAMPOLINE_GDTR]
+
+    // PAE
+    mov eax, cr4
+    or eax, 1 << 5
+    mov cr4, eax
+    // TrampolineData::pml4
+    mov eax, dword ptr [0xF00]
+    mov cr3, eax
+    // TrampolineData::efer, which sets LME
+    mov ecx, 0xC0000080
+    mov eax, dword ptr [0xF08]
+    xor edx, edx
+    wrmsr
+    // PG | PE
+    mov eax, cr0
+    or eax, 0x80000001
+    mov cr0, eax
+
+    jmp fword ptr [TRAMPOLINE_FAR_PTR]
+
+.code64
+ap_trampoline_long_mode:
+    mov ax, 0x10
+    mov ds, ax
+    mov es, ax
+    mov ss, ax
+    xor eax, eax
+    mov fs, ax
+    mov gs, ax
+
+    movabs rax, offset ap_long_mode_entry
+    jmp rax
+
+.balign 8
+ap_trampoline_gdt:
+    .quad 0
+    // 64-bit code segment
+    .quad 0x00209A0000000000
+    // data segment
+    .quad 0x0000920000000000
+ap_trampoline_gdtr:
+    .word ap_trampoline_gdtr - ap_trampoline_gdt - 1
+    .long ap_trampoline_gdt - ap_trampoline
+ap_trampoline_far_ptr:
+    .long ap_trampoline_long_mode - ap_trampoline
+    .word 0x08
+ap_trampoline_end:
+
+.set TRAMPOLINE_GDTR, ap_trampoline_gdtr - ap_trampoline
+.set TRAMPOLINE_FAR_PTR, ap_trampoline_far_ptr - ap_trampoline
+.popsection
+
+// The trampoline's identity mapping is dropped upon loading the kernel's PML4,
+// hence the TrampolineData is read beforehand.
+ap_long_mode_entry:
+    mov rax, [rbx + 0xF18]
+    mov rcx, [rbx + 0xF10]
+    mov rdx, [rbx + 0xF20]
+    mov rsp, [rbx + 0xF28]
+    mov rsi, [rbx + 0xF30]
+    mov rdi, [rbx + 0xF38]
+    mov cr3, rax
+    mov cr4, rdx
+    mov cr0, rcx
+    xor ebp, ebp
+    call rsi
+    ud2
+");
+
+
+/// Returns the number of CPUs enumerated, whether online or not.
+pub fn cpu_count() -> usize {
+    CPU_COUNT.load(Ordering::Acquire)
+}
+
+/// Returns the local APIC ID of `cpu`, or `None` if there's no such CPU.
+pub fn apic_id(cpu: usize) -> Option<u32> {
+    (cpu < cpu_count()).then(|| APIC_IDS[cpu].load(Ordering::Relaxed))
+}
+
+/// Returns the state of `cpu`, or `None` if there's no such CPU.
+pub fn state(cpu: usize) -> Option<CpuState> {
+    (cpu < cpu_count()).then(|| match STATES[cpu].load(Ordering::Acquire) {
+        0 => CpuState::Offline,
+        1 => CpuState::Starting,
+        2 => CpuState::Online,
+        _ => CpuState::Parked,
+    })
+}
+
+/// Returns the index of the executing CPU.
+/// ### Panics:
+/// Panics if `percpu::init` hasn't been called by the executing CPU.
+pub fn current() -> usize {
+    crate::percpu::this_cpu().cpu()
+}
+
+
+/// Enumerates the CPUs from the MADT, and prepares the trampoline in low memory
+/// and the vectors of the IPIs between CPUs.
+/// 
+/// Only the BSP is enumerated if the MADT lists no processors.
+/// ### Safety:
+/// Must be called once by the BSP, after `acpi::init` and `lapic::init`.
+pub unsafe fn init() {
+    let bsp_id = lapic::local_apic().id();
+    APIC_IDS[0].store(bsp_id, Ordering::Relaxed);
+    STATES[0].store(CpuState::Online as u8, Ordering::Release);
+    STARTED[0].store(true, Ordering::Relaxed);
+
+    // the ICR destination is 8-bit in xAPIC mode
+    let max_id = match lapic::local_apic().mode() {
+        ApicMode::X2Apic => u32::MAX,
+        ApicMode::XApic(_) => u8::MAX as u32,
+    };
+
+    let mut count = 1;
+    if let Some(info) = crate::acpi::platform().and_then(|platform| platform.processor_info.as_ref()) {
+        let processors = core::iter::once(&info.boot_processor).chain(info.application_processors.iter());
+        for processor in processors {
+            if processor.local_apic_id == bsp_id
+            || processor.state == ProcessorState::Disabled
+            || processor.local_apic_id > max_id {
+                continue;
+            }
+            if count == MAX_CPUS {
+                crate::println!("Too many CPUs, only {} are used.", MAX_CPUS);
+                break;
+            }
+
+            APIC_IDS[count].store(processor.local_apic_id, Ordering::Relaxed);
+            count += 1;
+        }
+    }
+    CPU_COUNT.store(count, Ordering::Release);
+
+    if count > 1 {
+        TRAMPOLINE.call_once(|| setup_trampoline());
+        let vector = irq::register_irq(None, wake_interrupt, ptr::null_mut())
+            .expect("CPU wake vector registration failed.");
+        WAKE_VECTOR.call_once(|| vector);
+        crate::memm::tlb::init();
+        crate::xcall::init();
+    }
+}
+
+/// Allocates the trampoline in low memory, and sets up its page tables.
+unsafe fn setup_trampoline() -> usize {
+    let mut mapper = MAPPER.lock();
+    let trampoline = mapper.alloc_low(TRAMPOLINE_SIZE, FrameOwner::Pinned)
+        .expect("Insufficient low memory for the AP trampoline.");
+
+    let pml4_paddr = trampoline + paging::PTE_SIZE;
+    let pdpt_paddr = trampoline + 2 * paging::PTE_SIZE;
+    let pml4 = crate::from_phys_addr!(pml4_paddr, PTE);
+    let pdpt = crate::from_phys_addr!(pdpt_paddr, PTE);
+    pml4.write_bytes(0, 512);
+    pdpt.write_bytes(0, 512);
+
+    // identity map the first GiB, containing the trampoline
+    pml4.write(PTE::P | PTE::RW | PTE::from_paddr(pdpt_paddr));
+    pdpt.write(PTE::P | PTE::RW | PTE::PS | PTE::from_paddr(0));
+
+    // the higher half's PML4 entries never change, see `Mapper::setup`
+    let krnl_pml4 = crate::from_phys_addr!(mapper.krnl_pml4, PTE);
+    ptr::copy_nonoverlapping(
+        krnl_pml4.add(memm::KRNL_HALF_IDX),
+        pml4.add(memm::KRNL_HALF_IDX),
+        512 - memm::KRNL_HALF_IDX
+    );
+
+    trampoline
+}
+
+/// Brings each offline AP online, each calling `entry` upon starting.
+/// 
+/// APs that fail to start are reported and left offline.
+/// ### Safety:
+/// Must be called by the BSP, after `init`. `entry` must set up the AP's
+/// execution environment, e.g. its descriptor tables and local APIC.
+pub unsafe fn start_aps(entry: ApEntry) {
+    AP_ENTRY.call_once(|| entry);
+
+    for cpu in 1..cpu_count() {
+        if state(cpu) == Some(CpuState::Offline) {
+            if online(cpu).is_err() {
+                crate::println!("CPU {} (APIC ID {}) failed to start.", cpu, APIC_IDS[cpu].load(Ordering::Relaxed));
+            }
+        }
+    }
+}
+
+/// Brings `cpu` online, starting it if it's offline, or waking it if it's parked.
+/// 
+/// Returns `Err(())` if there's no such CPU, `start_aps` hasn't been called,
+/// or the CPU didn't come online in time.
+/// ### Safety:
+/// `start_aps` must have been called, see `start_aps`.
+pub unsafe fn online(cpu: usize) -> Result<(), ()> {
+    match state(cpu).ok_or(())? {
+        CpuState::Online => Ok(()),
+        CpuState::Starting => Err(()),
+        CpuState::Parked => {
+            STATES[cpu].store(CpuState::Online as u8, Ordering::Release);
+            let vector = *WAKE_VECTOR.get().ok_or(())?;
+            ipi::send(cpu, vector)
+        },
+        CpuState::Offline => startup(cpu),
+    }
+}
+
+/// Takes the executing CPU offline, idling with interrupts enabled until
+/// it's brought back `online`. The BSP can't be taken offline.
+pub fn offline() {
+    let cpu = current();
+    assert!(cpu != 0, "The BSP can't be taken offline.");
+
+    STATES[cpu].store(CpuState::Parked as u8, Ordering::Release);
+    while STATES[cpu].load(Ordering::Acquire) == CpuState::Parked as u8 {
+        interrupts::sti_hlt();
+    }
+}
+
+
+/// Starts `cpu` through INIT-SIPI-SIPI, waiting for it to come online.
+/// 
+/// A CPU that doesn't come online in time is sent an INIT IPI, such that it
+/// has abandoned the trampoline before it's reused.
+unsafe fn startup(cpu: usize) -> Result<(), ()> {
+    let trampoline = *TRAMPOLINE.get().ok_or(())?;
+    AP_ENTRY.get().ok_or(())?;
+    let _startup = STARTUP_LOCK.lock();
+
+    // the stacks remain mapped should the CPU be restarted
+    if !STARTED[cpu].swap(true, Ordering::Relaxed) {
+        let krnl_pml4 = MAPPER.lock().krnl_pml4;
+        memm::stack::map_stacks(cpu, ptr::slice_from_raw_parts_mut(crate::from_phys_addr!(krnl_pml4, PTE), 512));
+    }
+
+    // the trampoline is relocated as it's executed, hence is copied afresh
+    let code_size = ap_trampoline_end as usize - ap_trampoline as usize;
+    assert!(code_size <= TRAMPOLINE_DATA_OFFSET, "AP trampoline overlaps its data.");
+    ptr::copy_nonoverlapping(ap_trampoline as *const u8, crate::from_phys_addr!(trampoline, u8), code_size);
+
+    crate::from_phys_addr!(trampoline + TRAMPOLINE_DATA_OFFSET, TrampolineData).write(TrampolineData {
+        pml4: (trampoline + paging::PTE_SIZE) as u64,
+        efer: (EFER::read() - EFER::LMA).bits(),
+        cr0: CR0::read().bits(),
+        cr3: MAPPER.lock().krnl_pml4 as u64,
+        cr4: CR4::read().bits(),
+        // the stack acme itself is unmapped, thus reduce by sixteen (preserves alignment)
+        stack: (memm::stack::krnl_stack_acme(cpu) - 0x10) as u64,
+        entry: ap_entry as u64,
+        cpu: cpu as u64,
+    });
+
+    STATES[cpu].store(CpuState::Starting as u8, Ordering::Release);
+
+    let lapic = lapic::local_apic();
+    let apic_id = APIC_IDS[cpu].load(Ordering::Relaxed);
+    lapic.send_init(apic_id);
+    io_delay(INIT_DELAY_US);
+
+    // a second startup IPI is sent in case the first is missed
+    for _ in 0..2 {
+        lapic.send_startup(apic_id, (trampoline / paging::PTE_SIZE) as u8);
+        io_delay(STARTUP_DELAY_US);
+        if state(cpu) == Some(CpuState::Online) {
+            return Ok(());
+        }
+    }
+
+    for _ in 0..ONLINE_TIMEOUT_US / STARTUP_DELAY_US {
+        if state(cpu) == Some(CpuState::Online) {
+            return Ok(());
+        }
+        io_delay(STARTUP_DELAY_US);
+    }
+
+    // the AP may yet be executing the trampoline, hence is reset before the
+    // trampoline is reused, unless it came online in the meantime, see `ap_entry`
+    if STATES[cpu].compare_exchange(
+        CpuState::Starting as u8, CpuState::Offline as u8, Ordering::AcqRel, Ordering::Acquire
+    ).is_err() {
+        return Ok(());
+    }
+    lapic.send_init(apic_id);
+    io_delay(INIT_DELAY_US);
+    Err(())
+}
+
+/// Called by the trampoline on the AP's kernel stack.
+extern "sysv64" fn ap_entry(cpu: usize) -> ! {
+    // SAFETY: the PAT is the same for every CPU, this AP has just started
+    unsafe {
+        memm::KRNL_DEFAULT_PAT.write();
+        crate::percpu::init_boot();
+    }
+
+    // the BSP gives up on APs that are too slow, and resets them, see `startup`
+    if STATES[cpu].compare_exchange(
+        CpuState::Starting as u8, CpuState::Online as u8, Ordering::AcqRel, Ordering::Relaxed
+    ).is_err() {
+        amd64::hlt_loop();
+    }
+
+    let entry = AP_ENTRY.get().expect("AP started without an entry.");
+    entry(cpu)
+}
+
+fn wake_interrupt(_: &mut TrapFrame, _: *mut ()) -> IrqStatus {
+    // parked CPUs check their state upon waking
+    IrqStatus::Handled
+}
+
+/// Waits `us` microseconds as per the clocksource, else approximately, each write
+/// to the unused POST code port taking about a microsecond on legacy chipsets.
+fn io_delay(us: usize) {
+    if crate::time::is_available() {
+        return crate::time::delay(Duration::from_micros(us as u64));
+    }
+
+    for _ in 0..us {
+        // SAFETY: port 0x80 is the POST code port, which is unused after boot
+        unsafe { ports::out8(0x80, 0); }
+    }
+}
@@ -0,0 +1,312 @@
+//! Module for the High Precision Event Timer (HPET), located by the ACPI HPET table.
+//! 
+//! The main counter is registered as a clocksource, and is the reference against
+//! which the TSC is calibrated. The timers are allocated to interrupt a CPU once or
+//! periodically, delivered as messages where supported, else through an I/O APIC.
+
+use core::time::Duration;
+
+use amd64::{
+    apic,
+    hpet::{Hpet, HpetConfig, TimerConfig, HPET_REGS_SIZE},
+    interrupts,
+    paging::{PTE, PatType},
+};
+use spin::{Mutex, Once};
+
+use super::Clocksource;
+use crate::{
+    interrupts::{ioapic::{self, IrqLine, Polarity, TriggerMode, ISA_IRQ_COUNT}, irq::{self, IrqHandler}},
+    memm::{self, MAPPER},
+    smp,
+};
+
+
+const FEMTOS_PER_NANO: u128 = 1_000_000;
+const FEMTOS_PER_SEC: u64 = 1_000_000_000_000_000;
+/// The longest period of the main counter permitted by the specification, being 100ns.
+const MAX_PERIOD_FS: u64 = 100_000_000;
+
+/// The HPET, as set up by `init`.
+struct Device {
+    hpet: Hpet,
+    /// The period of the main counter in femtoseconds.
+    period_fs: u64,
+    /// The bits of the main counter, which wraps beyond them.
+    mask: u64,
+    timer_count: usize,
+    /// The fewest ticks a periodic timer may be set to without losing interrupts.
+    min_periodic_ticks: u64,
+}
+
+impl Device {
+    fn duration_to_ticks(&self, duration: Duration) -> u64 {
+        (duration.as_nanos() * FEMTOS_PER_NANO / self.period_fs as u128).min(u64::MAX as u128) as u64
+    }
+}
+
+/// The allocated timers and the I/O APIC inputs they're routed to, indexed by bit.
+struct Allocations {
+    timers: u32,
+    inputs: u32,
+}
+
+static HPET: Once<Device> = Once::new();
+static ALLOCATIONS: Mutex<Allocations> = Mutex::new(Allocations { timers: 0, inputs: 0 });
+
+/// How a timer's interrupts are delivered.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Delivery {
+    /// As message signaled interrupts, written to the local APIC over the FSB.
+    Fsb,
+    /// Through the I/O APIC input of the GSI.
+    IoApic(u32),
+}
+
+/// A timer of the HPET, interrupting a CPU upon the vector it's allocated, see `alloc_timer`.
+/// 
+/// The timer is stopped and freed when dropped.
+#[derive(Debug)]
+pub struct HpetTimer {
+    index: usize,
+    vector: u8,
+    handler: IrqHandler,
+    ctx: *mut (),
+    delivery: Delivery,
+    /// The bits compared by the timer, which may be fewer than the main counter's.
+    mask: u64,
+}
+
+// SAFETY: the handler may be run on any CPU regardless, see `irq::register_irq`
+unsafe impl Send for HpetTimer {}
+
+impl HpetTimer {
+    /// Returns the index of the timer within the HPET.
+    pub fn index(&self) -> usize {
+        self.index
+    }
+    /// Returns the vector the timer interrupts upon.
+    pub fn vector(&self) -> u8 {
+        self.vector
+    }
+    pub fn delivery(&self) -> Delivery {
+        self.delivery
+    }
+
+    /// Interrupts once `delay` has elapsed, replacing any previous expiry.
+    /// 
+    /// Returns `Err(())` if the expiry passed before the timer was armed, in which
+    /// case it doesn't interrupt, e.g. as `delay` is too short.
+    pub fn start_oneshot(&self, delay: Duration) -> Result<(), ()> {
+        let device = HPET.get().unwrap();
+        let ticks = device.duration_to_ticks(delay).clamp(1, self.mask / 2);
+
+        interrupts::without_interrupts(|| {
+            let hpet = device.hpet;
+            let config = hpet.timer_config(self.index) - TimerConfig::PERIODIC;
+            // SAFETY: the vector is handled, see `alloc_timer`
+            unsafe {
+                hpet.set_timer_config(self.index, config - TimerConfig::INT_ENABLE);
+                let deadline = hpet.counter().wrapping_add(ticks) & self.mask;
+                hpet.set_comparator(self.index, deadline);
+                hpet.set_timer_config(self.index, config | TimerConfig::INT_ENABLE);
+
+                // the comparator only matches the counter upon reaching it, not beyond it
+                let overdue = hpet.counter().wrapping_sub(deadline) & self.mask;
+                if overdue != 0 && overdue <= self.mask / 2 {
+                    hpet.set_timer_config(self.index, config - TimerConfig::INT_ENABLE);
+                    return Err(());
+                }
+            }
+            Ok(())
+        })
+    }
+
+    /// Interrupts every `period`, beginning once `period` has elapsed.
+    /// 
+    /// Returns `Err(())` if the timer isn't capable of periodic interrupts, or
+    /// `period` is shorter than the HPET's minimum or longer than its comparator allows.
+    pub fn start_periodic(&self, period: Duration) -> Result<(), ()> {
+        let device = HPET.get().unwrap();
+        let hpet = device.hpet;
+        let ticks = device.duration_to_ticks(period);
+        let config = hpet.timer_config(self.index);
+        if !config.contains(TimerConfig::PERIODIC_CAPABLE)
+        || ticks < device.min_periodic_ticks.max(1) || ticks > self.mask / 2 {
+            return Err(());
+        }
+
+        interrupts::without_interrupts(|| {
+            // SAFETY: the vector is handled, see `alloc_timer`
+            unsafe {
+                let disabled = config - TimerConfig::INT_ENABLE;
+                hpet.set_timer_config(self.index, disabled);
+                // the first write sets the comparator, clearing VALUE_SET,
+                // such that the second write sets the period
+                hpet.set_timer_config(self.index, disabled | TimerConfig::PERIODIC | TimerConfig::VALUE_SET);
+                hpet.set_comparator(self.index, hpet.counter().wrapping_add(ticks) & self.mask);
+                hpet.set_comparator(self.index, ticks);
+                hpet.set_timer_config(self.index, config | TimerConfig::PERIODIC | TimerConfig::INT_ENABLE);
+            }
+        });
+        Ok(())
+    }
+
+    /// Stops the timer interrupting.
+    pub fn stop(&self) {
+        let hpet = HPET.get().unwrap().hpet;
+        let config = hpet.timer_config(self.index) - TimerConfig::INT_ENABLE - TimerConfig::PERIODIC;
+        // SAFETY: disabling the timer doesn't deliver interrupts
+        unsafe { hpet.set_timer_config(self.index, config); }
+    }
+}
+
+impl Drop for HpetTimer {
+    fn drop(&mut self) {
+        self.stop();
+
+        interrupts::without_interrupts(|| {
+            let mut allocations = ALLOCATIONS.lock();
+            if let Delivery::IoApic(gsi) = self.delivery {
+                let _ = ioapic::mask(gsi);
+                allocations.inputs &= !(1 << gsi);
+            }
+            let _ = irq::unregister_irq(self.vector, self.handler, self.ctx);
+            allocations.timers &= !(1 << self.index);
+        });
+    }
+}
+
+
+/// Maps the HPET's registers uncacheable, restarts its main counter from zero
+/// with every timer disabled, and registers the counter as a clocksource.
+/// 
+/// Returns `Err(())` if there's no HPET table, the HPET's period is invalid,
+/// or the clocksource can't be registered.
+/// ### Safety:
+/// Must be called once, after `sys::acpi::init` and before `time::init`.
+pub unsafe fn init() -> Result<(), ()> {
+    let info = crate::acpi::hpet().ok_or(())?;
+
+    let leaves = PTE::RW | memm::pat_type_to_pte(PatType::Uncacheable, false);
+    let hpet = Hpet::new(MAPPER.lock().map_mmio(info.base_address, HPET_REGS_SIZE, leaves));
+
+    let period_fs = hpet.period_fs();
+    if period_fs == 0 || period_fs > MAX_PERIOD_FS {
+        return Err(());
+    }
+
+    // the counter may only be set while halted, and the legacy route is left disabled
+    hpet.set_config(HpetConfig::empty());
+    hpet.set_counter(0);
+    let timer_count = hpet.timer_count();
+    for timer in 0..timer_count {
+        let config = hpet.timer_config(timer);
+        hpet.set_timer_config(timer, config - TimerConfig::INT_ENABLE - TimerConfig::PERIODIC - TimerConfig::FSB_ENABLE);
+    }
+    hpet.set_config(HpetConfig::ENABLE);
+
+    let device = HPET.call_once(|| Device {
+        hpet,
+        period_fs,
+        mask: if hpet.is_counter_64bit() { u64::MAX } else { u32::MAX as u64 },
+        timer_count,
+        min_periodic_ticks: info.clock_tick_unit as u64,
+    });
+
+    let frequency = FEMTOS_PER_SEC / device.period_fs;
+    crate::println!("HPET: {} kHz, {} timers", frequency / 1000, device.timer_count);
+
+    super::register_clocksource(Clocksource {
+        name: "HPET",
+        read: read_counter,
+        mask: device.mask,
+        frequency,
+        rating: 250,
+    })
+}
+
+/// Returns the main counter, or `None` if `init` hasn't set up the HPET.
+pub fn counter() -> Option<u64> {
+    HPET.get().map(|device| device.hpet.counter())
+}
+
+/// Returns the bits of the main counter, which wraps beyond them,
+/// or `None` if `init` hasn't set up the HPET.
+pub fn counter_mask() -> Option<u64> {
+    HPET.get().map(|device| device.mask)
+}
+
+/// Returns the frequency of the main counter in hertz, or `None` if `init` hasn't set up the HPET.
+pub fn frequency() -> Option<u64> {
+    HPET.get().map(|device| FEMTOS_PER_SEC / device.period_fs)
+}
+
+/// Allocates a timer that interrupts `cpu` upon a newly registered vector, handled
+/// by `handler` with `ctx`. The timer is stopped until started.
+/// 
+/// If `periodic`, only timers capable of periodic interrupts are allocated. Interrupts
+/// are delivered as messages where supported, else through an I/O APIC input beyond
+/// the ISA IRQs that no other timer is routed to, taken to be the GSI of that number.
+/// 
+/// Returns `Err(())` if `init` hasn't set up the HPET, there's no such CPU,
+/// no free timer can be routed, or no vector is free.
+pub fn alloc_timer(periodic: bool, cpu: usize, handler: IrqHandler, ctx: *mut ()) -> Result<HpetTimer, ()> {
+    let device = HPET.get().ok_or(())?;
+    let hpet = device.hpet;
+    let apic_id = smp::apic_id(cpu).ok_or(())?;
+
+    interrupts::without_interrupts(|| {
+        let mut allocations = ALLOCATIONS.lock();
+
+        let (index, delivery) = (0..device.timer_count)
+            .filter(|&timer| allocations.timers & 1 << timer == 0)
+            .find_map(|timer| {
+                let config = hpet.timer_config(timer);
+                if periodic && !config.contains(TimerConfig::PERIODIC_CAPABLE) {
+                    return None;
+                }
+                // messages only address 8-bit APIC IDs
+                if config.contains(TimerConfig::FSB_CAPABLE) && apic_id <= u8::MAX as u32 {
+                    return Some((timer, Delivery::Fsb));
+                }
+                let inputs = config.route_capabilities() & !allocations.inputs & !((1 << ISA_IRQ_COUNT) - 1);
+                (inputs != 0).then(|| (timer, Delivery::IoApic(31 - inputs.leading_zeros())))
+            })
+            .ok_or(())?;
+
+        let vector = irq::register_irq(None, handler, ctx)?;
+
+        let config = hpet.timer_config(index)
+            - TimerConfig::INT_ENABLE - TimerConfig::PERIODIC
+            - TimerConfig::LEVEL_TRIGGERED - TimerConfig::MODE_32BIT;
+        // SAFETY: the timer is disabled, and the vector is registered
+        unsafe {
+            match delivery {
+                Delivery::Fsb => {
+                    let (address, data) = apic::msi_message(vector, apic_id as u8);
+                    hpet.set_fsb_route(index, address, data);
+                    hpet.set_timer_config(index, config | TimerConfig::FSB_ENABLE);
+                },
+                Delivery::IoApic(gsi) => {
+                    let line = IrqLine { gsi, polarity: Polarity::ActiveHigh, trigger: TriggerMode::Edge };
+                    if ioapic::route(line, vector, apic_id).and_then(|()| ioapic::unmask(gsi)).is_err() {
+                        let _ = irq::unregister_irq(vector, handler, ctx);
+                        return Err(());
+                    }
+                    hpet.set_timer_config(index, (config - TimerConfig::FSB_ENABLE).with_route(gsi as u8));
+                    allocations.inputs |= 1 << gsi;
+                },
+            }
+        }
+        allocations.timers |= 1 << index;
+
+        let mask = if config.contains(TimerConfig::COMPARATOR_64BIT) { device.mask } else { u32::MAX as u64 };
+        Ok(HpetTimer { index, vector, handler, ctx, delivery, mask })
+    })
+}
+
+
+fn read_counter() -> u64 {
+    HPET.get().expect("HPET is not initialized.").hpet.counter()
+}
@@ -0,0 +1,401 @@
+//! Module for monotonic kernel time.
+//! 
+//! Time is kept by the TSC if it's invariant, being calibrated at boot, else by the
+//! highest rated fallback clocksource registered, e.g. the HPET or ACPI PM timer. As the TSCs
+//! of the CPUs may differ, each AP measures its TSC's offset from the BSP's, which
+//! is corrected for.
+//! 
+//! Time is monotonic across CPUs, beginning upon `init`.
+
+use core::{ops::{Add, AddAssign, Sub, SubAssign}, sync::atomic::{AtomicI64, AtomicU64, Ordering}};
+
+pub use core::time::Duration;
+
+use ::acpi::platform::address::AddressSpace;
+use amd64::{interrupts, pit, ports, registers::{self, APERF_MSR, MPERF_MSR}};
+use spin::{Mutex, Once};
+
+use crate::{percpu, smp::MAX_CPUS, xcall};
+
+pub mod hpet;
+
+
+/// The maximum number of fallback clocksources registered.
+pub const MAX_CLOCKSOURCES: usize = 4;
+
+const NANOS_PER_SEC: u64 = 1_000_000_000;
+/// The frequency of the ACPI PM timer, in hertz.
+const PM_TIMER_FREQUENCY: u64 = 3_579_545;
+/// The number of PIT ticks of each TSC calibration run, being about 10ms.
+const CALIBRATION_PIT_TICKS: u16 = 11_932;
+/// The number of TSC calibration runs, the shortest of which is used.
+const CALIBRATION_RUNS: usize = 5;
+/// The fraction of a second of each TSC calibration run against the HPET, being 10ms.
+const CALIBRATION_HPET_DIVISOR: u64 = 100;
+/// The number of polls after which a calibration reference is taken to be absent, being about a second.
+const CALIBRATION_TIMEOUT_POLLS: usize = 1_000_000;
+/// The number of round trips to the BSP when measuring a TSC's offset, the fastest of which is used.
+const OFFSET_ROUNDS: usize = 8;
+/// The period over which the effective frequency is measured.
+const FREQUENCY_SAMPLE: Duration = Duration::from_millis(1);
+
+/// A counter from which time is kept.
+#[derive(Debug, Clone, Copy)]
+pub struct Clocksource {
+    pub name: &'static str,
+    /// Reads the counter.
+    pub read: fn() -> u64,
+    /// The bits of the counter, which wraps beyond them.
+    pub mask: u64,
+    /// The number of counts per second.
+    pub frequency: u64,
+    /// Higher rated clocksources are preferred.
+    pub rating: u32,
+}
+
+/// The clocksource chosen by `init`.
+enum Clock {
+    /// The TSC, as corrected by each CPU's offset, read `base` upon `init`.
+    Tsc { frequency: u64, base: u64 },
+    Fallback(Mutex<Counter>),
+}
+
+/// A fallback clocksource's count, extended beyond its mask.
+struct Counter {
+    source: Clocksource,
+    /// The last masked count read.
+    last: u64,
+    /// The number of counts since `init`.
+    count: u64,
+}
+
+const ZERO_OFFSET: AtomicI64 = AtomicI64::new(0);
+
+static CLOCK: Once<Clock> = Once::new();
+static TSC_FREQUENCY: Once<u64> = Once::new();
+/// The difference of the BSP's TSC from each CPU's.
+static TSC_OFFSETS: [AtomicI64; MAX_CPUS] = [ZERO_OFFSET; MAX_CPUS];
+static FALLBACKS: Mutex<[Option<Clocksource>; MAX_CLOCKSOURCES]> = Mutex::new([None; MAX_CLOCKSOURCES]);
+/// The latest time read by any CPU, in nanoseconds.
+static LAST_NANOS: AtomicU64 = AtomicU64::new(0);
+/// The I/O port of the ACPI PM timer.
+static PM_TIMER_PORT: Once<u16> = Once::new();
+
+impl Counter {
+    fn read_nanos(&mut self) -> u64 {
+        let now = (self.source.read)() & self.source.mask;
+        self.count += now.wrapping_sub(self.last) & self.source.mask;
+        self.last = now;
+        count_to_nanos(self.count, self.source.frequency)
+    }
+}
+
+
+/// A point in time, see `uptime`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Instant(Duration);
+
+impl Instant {
+    /// Returns the current time, being zero until `init`.
+    pub fn now() -> Self {
+        Self(uptime())
+    }
+
+    /// Returns the time elapsed from `earlier` until `self`, or zero if `earlier` is later.
+    pub fn duration_since(&self, earlier: Instant) -> Duration {
+        self.0.saturating_sub(earlier.0)
+    }
+    /// Returns the time elapsed from `earlier` until `self`, or `None` if `earlier` is later.
+    pub fn checked_duration_since(&self, earlier: Instant) -> Option<Duration> {
+        self.0.checked_sub(earlier.0)
+    }
+    /// Returns the time elapsed since `self`.
+    pub fn elapsed(&self) -> Duration {
+        Self::now().duration_since(*self)
+    }
+
+    pub fn checked_add(&self, duration: Duration) -> Option<Instant> {
+        self.0.checked_add(duration).map(Self)
+    }
+    pub fn checked_sub(&self, duration: Duration) -> Option<Instant> {
+        self.0.checked_sub(duration).map(Self)
+    }
+}
+
+impl Add<Duration> for Instant {
+    type Output = Instant;
+
+    fn add(self, duration: Duration) -> Instant {
+        self.checked_add(duration).expect("Overflow when adding duration to instant.")
+    }
+}
+impl AddAssign<Duration> for Instant {
+    fn add_assign(&mut self, duration: Duration) {
+        *self = *self + duration;
+    }
+}
+impl Sub<Duration> for Instant {
+    type Output = Instant;
+
+    fn sub(self, duration: Duration) -> Instant {
+        self.checked_sub(duration).expect("Overflow when subtracting duration from instant.")
+    }
+}
+impl SubAssign<Duration> for Instant {
+    fn sub_assign(&mut self, duration: Duration) {
+        *self = *self - duration;
+    }
+}
+impl Sub<Instant> for Instant {
+    type Output = Duration;
+
+    fn sub(self, earlier: Instant) -> Duration {
+        self.duration_since(earlier)
+    }
+}
+
+
+/// Registers a fallback clocksource, used if the TSC is unreliable.
+/// 
+/// Time is lost should it not be read within each period of the counter's wrapping.
+/// Returns `Err(())` if `MAX_CLOCKSOURCES` are registered, or `init` has been called.
+pub fn register_clocksource(source: Clocksource) -> Result<(), ()> {
+    if CLOCK.is_completed() {
+        return Err(());
+    }
+    let mut fallbacks = FALLBACKS.lock();
+    let slot = fallbacks.iter_mut().find(|slot| slot.is_none()).ok_or(())?;
+    *slot = Some(source);
+    Ok(())
+}
+
+/// Calibrates the TSC and chooses the clocksource, from which time begins.
+/// 
+/// The TSC is used if it's invariant, else the highest rated fallback clocksource,
+/// including the ACPI PM timer if present, and failing that, the TSC regardless.
+/// ### Safety:
+/// Must be called once by the BSP, after `acpi::init`, `hpet::init` and registering
+/// any fallback clocksources, and before other CPUs call `init_cpu`.
+pub unsafe fn init() {
+    if let Some(pm_timer) = crate::acpi::platform().and_then(|platform| platform.pm_timer.as_ref()) {
+        if pm_timer.base.address_space == AddressSpace::SystemIo {
+            PM_TIMER_PORT.call_once(|| pm_timer.base.address as u16);
+            let _ = register_clocksource(Clocksource {
+                name: "ACPI PM timer",
+                read: read_pm_timer,
+                mask: if pm_timer.supports_32bit { u32::MAX as u64 } else { 0xFF_FFFF },
+                frequency: PM_TIMER_FREQUENCY,
+                rating: 100,
+            });
+        }
+    }
+
+    let cpuid = raw_cpuid::CpuId::new();
+    let has_tsc = cpuid.get_feature_info().map_or(false, |info| info.has_tsc());
+    let is_invariant = cpuid.get_advanced_power_mgmt_info().map_or(false, |info| info.has_invariant_tsc());
+    let tsc_frequency = if has_tsc { calibrate_tsc() } else { None };
+    if let Some(frequency) = tsc_frequency {
+        TSC_FREQUENCY.call_once(|| frequency);
+        crate::println!("TSC: {} kHz{}", frequency / 1000, if is_invariant { ", invariant" } else { "" });
+    }
+
+    let fallback = FALLBACKS.lock().iter().flatten().max_by_key(|source| source.rating).copied();
+    let clock = match (tsc_frequency, fallback) {
+        (Some(frequency), _) if is_invariant || fallback.is_none() => {
+            Clock::Tsc { frequency, base: amd64::rdtsc() }
+        },
+        (_, Some(source)) => {
+            let last = (source.read)() & source.mask;
+            Clock::Fallback(Mutex::new(Counter { source, last, count: 0 }))
+        },
+        (_, None) => {
+            crate::println!("No clocksource, time is unavailable.");
+            return;
+        },
+    };
+
+    crate::println!("Clocksource: {}", match &clock {
+        Clock::Tsc { .. } => "TSC",
+        Clock::Fallback(counter) => counter.lock().source.name,
+    });
+    CLOCK.call_once(|| clock);
+}
+
+/// Measures the executing AP's TSC offset from the BSP's, if time is kept by the TSC.
+/// 
+/// Offsets within the measurement's error, being half the fastest round trip
+/// to the BSP, are taken to be zero.
+/// ### Panics:
+/// Panics if `percpu::init` hasn't been called by the executing CPU.
+pub fn init_cpu() {
+    let cpu = percpu::this_cpu().cpu();
+    if cpu == 0 || !matches!(CLOCK.get(), Some(Clock::Tsc { .. })) {
+        return;
+    }
+
+    let mut fastest: Option<(u64, i64)> = None;
+    for _ in 0..OFFSET_ROUNDS {
+        let before = amd64::rdtsc();
+        let bsp_tsc = match xcall::run_on(0, amd64::rdtsc) {
+            Ok(tsc) => tsc,
+            Err(()) => return,
+        };
+        let round_trip = amd64::rdtsc().wrapping_sub(before);
+
+        // the BSP's TSC is taken to be read halfway through the round trip
+        let offset = bsp_tsc.wrapping_sub(before.wrapping_add(round_trip / 2)) as i64;
+        if fastest.map_or(true, |(fastest, _)| round_trip < fastest) {
+            fastest = Some((round_trip, offset));
+        }
+    }
+
+    if let Some((round_trip, offset)) = fastest {
+        if offset.unsigned_abs() > round_trip / 2 {
+            TSC_OFFSETS[cpu].store(offset, Ordering::Relaxed);
+        }
+    }
+}
+
+
+/// Returns the time elapsed since `init`, monotonic across CPUs.
+pub fn uptime() -> Duration {
+    let nanos = match CLOCK.get() {
+        None => return Duration::ZERO,
+        Some(Clock::Tsc { frequency, base }) => count_to_nanos(tsc().saturating_sub(*base), *frequency),
+        Some(Clock::Fallback(counter)) => interrupts::without_interrupts(|| counter.lock().read_nanos()),
+    };
+
+    // offsets are corrected imprecisely, hence the TSC of one CPU may lag another's
+    let latest = LAST_NANOS.fetch_max(nanos, Ordering::AcqRel).max(nanos);
+    Duration::from_nanos(latest)
+}
+
+/// Returns whether `init` has chosen a clocksource, such that time is available.
+pub fn is_available() -> bool {
+    CLOCK.is_completed()
+}
+
+/// Waits for `duration` to elapse.
+/// ### Panics:
+/// Panics if `init` hasn't chosen a clocksource.
+pub fn delay(duration: Duration) {
+    assert!(CLOCK.is_completed(), "Time is unavailable.");
+
+    let start = Instant::now();
+    while start.elapsed() < duration {
+        core::hint::spin_loop();
+    }
+}
+
+/// Returns the calibrated frequency of the TSC in hertz, or `None` if it's uncalibrated.
+pub fn tsc_frequency() -> Option<u64> {
+    TSC_FREQUENCY.get().copied()
+}
+
+/// Returns the average effective frequency of the executing CPU in hertz over
+/// `FREQUENCY_SAMPLE`, as per the APERF and MPERF MSRs, or `None` if they're
+/// unsupported, the TSC is uncalibrated, or time is unavailable.
+pub fn effective_frequency() -> Option<u64> {
+    // CPUID.06H:ECX[0] enumerates the architectural MSRs, not AMD's read-only copies
+    let has_aperf_mperf = raw_cpuid::CpuId::new().get_thermal_power_info()
+        .map_or(false, |info| info.has_hw_coord_feedback());
+    let tsc_frequency = tsc_frequency()?;
+    if !has_aperf_mperf || !CLOCK.is_completed() {
+        return None;
+    }
+
+    let (aperf, mperf) = (registers::rdmsr(APERF_MSR), registers::rdmsr(MPERF_MSR));
+    delay(FREQUENCY_SAMPLE);
+    let aperf = registers::rdmsr(APERF_MSR).wrapping_sub(aperf);
+    let mperf = registers::rdmsr(MPERF_MSR).wrapping_sub(mperf);
+    if mperf == 0 {
+        return None;
+    }
+
+    // MPERF counts at the TSC's frequency, APERF at the effective frequency
+    Some((tsc_frequency as u128 * aperf as u128 / mperf as u128) as u64)
+}
+
+
+/// Returns the executing CPU's TSC, corrected by its offset from the BSP's.
+fn tsc() -> u64 {
+    let cpu = percpu::try_this_cpu().map_or(0, |this| this.cpu());
+    amd64::rdtsc().wrapping_add(TSC_OFFSETS[cpu].load(Ordering::Relaxed) as u64)
+}
+
+fn count_to_nanos(count: u64, frequency: u64) -> u64 {
+    (count as u128 * NANOS_PER_SEC as u128 / frequency as u128) as u64
+}
+
+/// Returns the TSC's frequency as enumerated by CPUID, else as measured
+/// against the HPET, else the PIT.
+fn calibrate_tsc() -> Option<u64> {
+    if let Some(frequency) = raw_cpuid::CpuId::new().get_tsc_info().and_then(|info| info.tsc_frequency()) {
+        return Some(frequency);
+    }
+    if let Some(frequency) = calibrate_tsc_hpet() {
+        return Some(frequency);
+    }
+
+    // runs are only lengthened by interruptions, e.g. SMIs, hence the shortest is used
+    let mut shortest = u64::MAX;
+    for _ in 0..CALIBRATION_RUNS {
+        let run = interrupts::without_interrupts(|| {
+            // SAFETY: channel 2 isn't otherwise used
+            unsafe { pit::start_oneshot2(CALIBRATION_PIT_TICKS); }
+            let start = amd64::rdtsc();
+            for _ in 0..CALIBRATION_TIMEOUT_POLLS {
+                if pit::is_oneshot2_done() {
+                    return Some(amd64::rdtsc() - start);
+                }
+            }
+            None
+        })?;
+        shortest = shortest.min(run);
+    }
+
+    Some(shortest * pit::PIT_FREQUENCY / CALIBRATION_PIT_TICKS as u64)
+}
+
+/// Returns the TSC's frequency as measured against the HPET, or `None` if it's absent.
+fn calibrate_tsc_hpet() -> Option<u64> {
+    let (hpet_frequency, mask) = (hpet::frequency()?, hpet::counter_mask()?);
+    let ticks = hpet_frequency / CALIBRATION_HPET_DIVISOR;
+
+    // reads the HPET between two reads of the TSC, returning the TSC's midpoint and their difference
+    let read = || {
+        let before = amd64::rdtsc();
+        let counter = hpet::counter().unwrap();
+        let after = amd64::rdtsc();
+        (before + (after - before) / 2, counter, after - before)
+    };
+
+    // runs are only made imprecise by interruptions between reads, hence
+    // the run of the closest reads is used
+    let mut best: Option<(u64, u64)> = None;
+    for _ in 0..CALIBRATION_RUNS {
+        let run = interrupts::without_interrupts(|| {
+            let (tsc_start, counter_start, start_error) = read();
+            for _ in 0..CALIBRATION_TIMEOUT_POLLS {
+                let (tsc_end, counter_end, end_error) = read();
+                let elapsed = counter_end.wrapping_sub(counter_start) & mask;
+                if elapsed >= ticks {
+                    let frequency = (tsc_end - tsc_start) as u128 * hpet_frequency as u128 / elapsed as u128;
+                    return Some((start_error + end_error, frequency as u64));
+                }
+            }
+            None
+        })?;
+        if best.map_or(true, |(error, _)| run.0 < error) {
+            best = Some(run);
+        }
+    }
+
+    best.map(|(_, frequency)| frequency)
+}
+
+fn read_pm_timer() -> u64 {
+    let port = *PM_TIMER_PORT.get().expect("ACPI PM timer is not registered.");
+    // SAFETY: reading the PM timer has no side effects
+    unsafe { ports::in32(port) as u64 }
+}
@@ -0,0 +1,359 @@
+//! Module for running functions on other CPUs.
+//! 
+//! Calls are queued upon the target CPU, which is interrupted to run them in turn.
+//! Synchronous calls borrow the function from the caller, which waits for it to have
+//! run; asynchronous calls are moved into the `CallPool` and return immediately.
+//! 
+//! While waiting, callers run the calls queued upon themselves, such that CPUs
+//! calling each other with interrupts disabled don't deadlock.
+
+use core::{
+    alloc::{AllocError, Allocator, Layout},
+    cell::UnsafeCell,
+    ptr::NonNull,
+    sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+};
+
+use alloc::boxed::Box;
+use amd64::interrupts;
+use spin::{Mutex, Once};
+
+use crate::{
+    interrupts::{TrapFrame, ipi, irq::{self, IrqStatus}},
+    percpu,
+    smp::{self, CpuState, MAX_CPUS},
+};
+
+
+/// The maximum number of calls queued upon each CPU.
+pub const QUEUE_CAPACITY: usize = 16;
+/// The maximum size of the functions of asynchronous calls, see `CallPool`.
+pub const ASYNC_CALL_SIZE: usize = 64;
+/// The number of asynchronous calls that may be queued at once, across the CPUs.
+const POOL_SLOTS: usize = 256;
+
+/// The number of iterations to wait for CPUs to freeze before sending them NMIs.
+const FREEZE_TIMEOUT_SPINS: usize = 1 << 24;
+
+enum Call {
+    /// Owned by the queue, run once.
+    Async(Box<dyn FnOnce() + Send, &'static CallPool>),
+    /// Borrowed from a caller waiting for `pending` to reach zero.
+    Sync {
+        func: unsafe fn(*const ()),
+        data: *const (),
+        pending: *const AtomicUsize,
+    },
+    /// Halts the CPU, see `freeze_others`.
+    Freeze,
+}
+
+// SAFETY: the data of synchronous calls is Send or Sync, see `run_on` and `run_on_all`
+unsafe impl Send for Call {}
+
+/// A ring of calls queued upon a CPU.
+struct CallQueue {
+    calls: [Option<Call>; QUEUE_CAPACITY],
+    head: usize,
+    len: usize,
+}
+
+const NO_CALL: Option<Call> = None;
+
+impl CallQueue {
+    const fn new() -> Self {
+        Self { calls: [NO_CALL; QUEUE_CAPACITY], head: 0, len: 0 }
+    }
+
+    fn push(&mut self, call: Call) -> Result<(), ()> {
+        if self.len == QUEUE_CAPACITY {
+            return Err(());
+        }
+        self.calls[(self.head + self.len) % QUEUE_CAPACITY] = Some(call);
+        self.len += 1;
+        Ok(())
+    }
+
+    fn pop(&mut self) -> Option<Call> {
+        if self.len == 0 {
+            return None;
+        }
+        let call = self.calls[self.head].take();
+        self.head = (self.head + 1) % QUEUE_CAPACITY;
+        self.len -= 1;
+        call
+    }
+}
+
+/// A slot of the `CallPool`.
+#[repr(C, align(16))]
+struct PoolSlot(UnsafeCell<[u8; ASYNC_CALL_SIZE]>);
+
+/// The allocator of asynchronous calls, being a pool of fixed-size slots that are
+/// claimed and released atomically.
+/// 
+/// Calls are freed by the CPUs that run them, in interrupt context, hence can't be
+/// freed upon the caller's `Tallock`, of which the lock may be held by the
+/// interrupted context, or by a CPU waiting upon the one that's interrupted.
+struct CallPool {
+    slots: [PoolSlot; POOL_SLOTS],
+    /// The slots in use, indexed by bit.
+    used: [AtomicU64; POOL_SLOTS / 64],
+}
+
+// SAFETY: each slot is only accessed by the owner of the call it holds
+unsafe impl Sync for CallPool {}
+
+unsafe impl Allocator for CallPool {
+    fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        if layout.size() > ASYNC_CALL_SIZE || layout.align() > core::mem::align_of::<PoolSlot>() {
+            return Err(AllocError);
+        }
+        // zero-sized functions needn't a slot
+        if layout.size() == 0 {
+            return Ok(NonNull::slice_from_raw_parts(NonNull::<PoolSlot>::dangling().cast(), 0));
+        }
+
+        for (word, used) in self.used.iter().enumerate() {
+            let mut bits = used.load(Ordering::Relaxed);
+            while bits != u64::MAX {
+                let bit = bits.trailing_ones() as usize;
+                match used.compare_exchange_weak(bits, bits | 1 << bit, Ordering::Acquire, Ordering::Relaxed) {
+                    Ok(_) => {
+                        let slot = self.slots[word * 64 + bit].0.get().cast::<u8>();
+                        // SAFETY: the slot is within the pool, hence non-null
+                        let slot = unsafe { NonNull::new_unchecked(slot) };
+                        return Ok(NonNull::slice_from_raw_parts(slot, ASYNC_CALL_SIZE));
+                    },
+                    Err(current) => bits = current,
+                }
+            }
+        }
+        Err(AllocError)
+    }
+
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+        if layout.size() == 0 {
+            return;
+        }
+        let index = (ptr.as_ptr() as usize - self.slots.as_ptr() as usize) / core::mem::size_of::<PoolSlot>();
+        self.used[index / 64].fetch_and(!(1 << index % 64), Ordering::Release);
+    }
+}
+
+const EMPTY_SLOT: PoolSlot = PoolSlot(UnsafeCell::new([0; ASYNC_CALL_SIZE]));
+const UNUSED: AtomicU64 = AtomicU64::new(0);
+const EMPTY_QUEUE: Mutex<CallQueue> = Mutex::new(CallQueue::new());
+const NOT_FROZEN: AtomicBool = AtomicBool::new(false);
+
+static QUEUES: [Mutex<CallQueue>; MAX_CPUS] = [EMPTY_QUEUE; MAX_CPUS];
+/// The vector of the IPI that has CPUs run their queued calls.
+static VECTOR: Once<u8> = Once::new();
+/// Set once a CPU has begun freezing the others.
+static FREEZING: AtomicBool = AtomicBool::new(false);
+static FROZEN: [AtomicBool; MAX_CPUS] = [NOT_FROZEN; MAX_CPUS];
+static POOL: CallPool = CallPool { slots: [EMPTY_SLOT; POOL_SLOTS], used: [UNUSED; POOL_SLOTS / 64] };
+
+
+/// Registers the call IPI vector, before which only the executing CPU can be called.
+/// ### Safety:
+/// Must be called by the BSP before other CPUs are started.
+pub unsafe fn init() {
+    VECTOR.call_once(|| {
+        irq::register_irq(None, call_interrupt, core::ptr::null_mut())
+            .expect("Cross-CPU call vector registration failed.")
+    });
+}
+
+/// Runs `f` on `cpu`, returning its result once it has run.
+/// 
+/// `f` is run directly, with interrupts disabled, if `cpu` is the executing CPU.
+/// Returns `Err(())` if `cpu` isn't online, or its queue is full.
+pub fn run_on<F, R>(cpu: usize, f: F) -> Result<R, ()>
+where F: FnOnce() -> R + Send, R: Send {
+    if cpu == smp::current() {
+        return Ok(interrupts::without_interrupts(f));
+    }
+
+    let mut slot = (Some(f), None);
+    let pending = AtomicUsize::new(1);
+    queue(cpu, Call::Sync {
+        func: call_once::<F, R>,
+        data: &mut slot as *mut _ as *const (),
+        pending: &pending,
+    })?;
+
+    wait(&pending);
+    Ok(slot.1.take().unwrap())
+}
+
+/// Runs `f` on every online CPU, including the executing CPU, returning once each has.
+/// `f` is run with interrupts disabled on each CPU.
+/// 
+/// Returns `Err(())` if the queue of any other CPU is full, in which case `f` isn't
+/// run on that CPU, but is on the others.
+pub fn run_on_all<F>(f: F) -> Result<(), ()>
+where F: Fn() + Sync {
+    let current = smp::current();
+    let pending = AtomicUsize::new(0);
+    let mut result = Ok(());
+
+    for cpu in (0..smp::cpu_count()).filter(|&cpu| cpu != current && is_online(cpu)) {
+        pending.fetch_add(1, Ordering::Relaxed);
+        let call = Call::Sync {
+            func: call_shared::<F>,
+            data: &f as *const F as *const (),
+            pending: &pending,
+        };
+        if queue(cpu, call).is_err() {
+            pending.fetch_sub(1, Ordering::Relaxed);
+            result = Err(());
+        }
+    }
+
+    // as on the other CPUs, which run it upon the call interrupt
+    interrupts::without_interrupts(&f);
+    wait(&pending);
+    result
+}
+
+/// Queues `f` to run on `cpu`, returning immediately.
+/// 
+/// If `cpu` is the executing CPU, `f` is run once interrupts are enabled.
+/// Returns `Err(())` if `cpu` isn't online, its queue is full, `f` is larger
+/// than `ASYNC_CALL_SIZE`, or too many asynchronous calls are queued.
+pub fn run_on_async<F>(cpu: usize, f: F) -> Result<(), ()>
+where F: FnOnce() + Send + 'static {
+    queue(cpu, Call::Async(Box::try_new_in(f, &POOL).map_err(|_| ())?))
+}
+
+/// Queues `f` to run on every online CPU, including the executing CPU, returning immediately.
+/// 
+/// Returns `Err(())` if `f` can't be queued upon any CPU, see `run_on_async`, in
+/// which case `f` isn't run on that CPU, but is on the others.
+pub fn run_on_all_async<F>(f: F) -> Result<(), ()>
+where F: FnOnce() + Send + Clone + 'static {
+    let mut result = Ok(());
+
+    for cpu in (0..smp::cpu_count()).filter(|&cpu| is_online(cpu)) {
+        if run_on_async(cpu, f.clone()).is_err() {
+            result = Err(());
+        }
+    }
+    result
+}
+
+/// Halts every other online CPU, e.g. such that a panic's report isn't
+/// interleaved with their output.
+/// 
+/// CPUs that don't run their queued calls in time, e.g. due to having
+/// interrupts disabled, are sent an NMI. If another CPU is already freezing
+/// the others, the executing CPU is halted instead.
+pub fn freeze_others() {
+    if FREEZING.swap(true, Ordering::AcqRel) {
+        freeze();
+    }
+
+    let vector = match VECTOR.get() {
+        Some(&vector) => vector,
+        None => return,
+    };
+    let current = percpu::try_this_cpu().map_or(0, |this| this.cpu());
+    let targets = || (0..smp::cpu_count()).filter(move |&cpu| cpu != current && is_online(cpu));
+
+    for cpu in targets() {
+        // the lock may be held by a CPU that never releases it
+        if let Some(mut queue) = QUEUES[cpu].try_lock() {
+            let _ = queue.push(Call::Freeze);
+        }
+        // SAFETY: the vector is registered by `init`
+        let _ = unsafe { ipi::send(cpu, vector) };
+    }
+
+    for _ in 0..FREEZE_TIMEOUT_SPINS {
+        if targets().all(|cpu| FROZEN[cpu].load(Ordering::Acquire)) {
+            return;
+        }
+        core::hint::spin_loop();
+    }
+
+    for cpu in targets().filter(|&cpu| !FROZEN[cpu].load(Ordering::Acquire)) {
+        // SAFETY: NMIs are handled by freezing while `FREEZING` is set
+        let _ = unsafe { ipi::send_nmi(cpu) };
+    }
+}
+
+/// Returns whether a CPU has begun freezing the others, see `freeze_others`.
+pub fn is_freezing() -> bool {
+    FREEZING.load(Ordering::Acquire)
+}
+
+/// Halts the executing CPU indefinitely, see `freeze_others`.
+pub(crate) fn freeze() -> ! {
+    interrupts::cli();
+    // frozen CPUs can't acknowledge shootdowns
+    crate::memm::tlb::leave();
+    if let Some(this) = percpu::try_this_cpu() {
+        FROZEN[this.cpu()].store(true, Ordering::Release);
+    }
+    amd64::hlt_loop()
+}
+
+
+fn is_online(cpu: usize) -> bool {
+    matches!(smp::state(cpu), Some(CpuState::Online | CpuState::Parked))
+}
+
+/// Queues `call` upon `cpu` and interrupts it to run the call.
+fn queue(cpu: usize, call: Call) -> Result<(), ()> {
+    if !is_online(cpu) {
+        return Err(());
+    }
+    let vector = *VECTOR.get().ok_or(())?;
+
+    // the queue is also locked by the call interrupt
+    interrupts::without_interrupts(|| QUEUES[cpu].lock().push(call))?;
+    // SAFETY: the vector is registered by `init`
+    unsafe { ipi::send(cpu, vector) }
+}
+
+/// Waits for `pending` to reach zero, running the calls queued upon the
+/// executing CPU meanwhile, as their callers may be waiting upon it.
+fn wait(pending: &AtomicUsize) {
+    let current = smp::current();
+    while pending.load(Ordering::Acquire) != 0 {
+        run_queued(current);
+        core::hint::spin_loop();
+    }
+}
+
+/// Runs the calls queued upon `cpu`, being the executing CPU.
+fn run_queued(cpu: usize) {
+    while let Some(call) = interrupts::without_interrupts(|| QUEUES[cpu].lock().pop()) {
+        match call {
+            Call::Async(f) => f(),
+            // SAFETY: the caller waits for the call to complete, keeping the data alive
+            Call::Sync { func, data, pending } => unsafe {
+                func(data);
+                (*pending).fetch_sub(1, Ordering::Release);
+            },
+            Call::Freeze => freeze(),
+        }
+    }
+}
+
+/// Runs the `FnOnce` in the `(Option<F>, Option<R>)` slot at `data`, storing the result.
+unsafe fn call_once<F: FnOnce() -> R, R>(data: *const ()) {
+    let slot = &mut *(data as *mut (Option<F>, Option<R>));
+    slot.1 = slot.0.take().map(|f| f());
+}
+
+/// Runs the `Fn` at `data`.
+unsafe fn call_shared<F: Fn()>(data: *const ()) {
+    (*(data as *const F))();
+}
+
+fn call_interrupt(_: &mut TrapFrame, _: *mut ()) -> IrqStatus {
+    run_queued(percpu::this_cpu().cpu());
+    IrqStatus::Handled
+}